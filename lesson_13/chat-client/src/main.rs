@@ -1,24 +1,104 @@
-use std::io::{Read, Write};
-use std::iter::repeat_with;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::iter::{once, repeat_with};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::mpsc::channel;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Scope;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io, thread};
 
 use anyhow::{Context, Result};
-use image::ImageFormat;
 use serde::Deserialize;
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
-use chatlib::{ChatMessageError, Message};
+use chatlib::{
+    decode_image, tls_client_config_with_ca, tls_client_config_with_pinned_cert, Ack, Backoff,
+    Beat, ChatMessageError, Codec, CodecError, Command, Envelope, Event, FrameError, FrameReader,
+    FrameWriter, Heartbeat, HistoryContent, HistoryEntry, Identity, IdentityKey, ImageInfo,
+    ImageOptions, IncomingTransfers, KeyTrust, KnownKeys, Message, Presence, Sanction,
+    SealedMessage, Stream, Target, TlsClientConfig, TlsStream, DEFAULT_MAX_FRAME_SIZE,
+    DEFAULT_MAX_IMAGE_DIMENSION, LOBBY, MAX_DECODED_IMAGE_DIMENSION,
+};
+
+use outbox::Outbox;
+
+mod outbox;
+
+/// Number of history messages requested by .history without a count
+const HISTORY_PAGE_SIZE: usize = 20;
+
+/// Number of outgoing messages buffered before the command reader blocks,
+/// keeps chunked file transfers from being read into memory ahead of the network
+const OUTGOING_QUEUE_SIZE: usize = 16;
+
+/// How long a direct message waits for the identity key of the recipient
+const KEY_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of chunked file transfers a single sender may have in progress
+const MAX_INCOMING_TRANSFERS: usize = 4;
+
+/// How long an incoming file transfer waits for its next chunk before it is dropped
+const INCOMING_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands produced by a single command line
+type Commands<'a> = Box<dyn Iterator<Item = Result<Command>> + 'a>;
+
+/// Messages produced by a single command line
+type Messages<'a> = Box<dyn Iterator<Item = Result<Message>> + 'a>;
 
 struct Client {
     config: ClientConfig,
-    stream: Mutex<Option<TcpStream>>,
+    /// connections are plain TCP without TLS configuration
+    tls: Option<Arc<TlsClientConfig>>,
+    stream: Mutex<Option<Stream>>,
+    /// codec the server picked for the current connection
+    codec: Mutex<Codec>,
+    /// number of the current connection, a reader only closes its own
+    connection_id: AtomicU64,
+    /// signalled whenever the connection is closed
+    disconnected: Condvar,
+    /// messages waiting for a connection, its lock also keeps writes to the stream in order
+    outbox: Mutex<Outbox>,
+    /// id of the last message sent, starts at random so that ids differ across restarts
+    message_id: AtomicU64,
+    /// messages sent but not yet acknowledged by id, resent after reconnecting unless accepted
+    pending: Mutex<BTreeMap<u64, Pending>>,
+    /// transfer ids of sent file transfer messages until they are acknowledged
+    transfer_messages: Mutex<HashMap<u64, u64>>,
+    /// outgoing file transfers with a refused message, the rest of them is not sent
+    aborted_transfers: Mutex<HashSet<u64>>,
+    /// room messages are sent to
+    room: Mutex<String>,
+    /// oldest history message seen in every room, .history pages before it
+    history: Mutex<HashMap<String, u64>>,
+    /// username logged in (or to be logged in) with the server
+    username: Mutex<String>,
+    /// password of the account
+    password: Mutex<String>,
+    /// whether the account is created on the next connection instead of logging in
+    register: AtomicBool,
+    /// whether the server accepted the credentials on the current connection
+    authenticated: AtomicBool,
+    /// direct messages are sent in plain text without an identity
+    identity: Option<Identity>,
+    /// identity keys of users trusted on first use, a changed one has to be trusted again
+    known_keys: Mutex<KnownKeys>,
+    /// answers to identity key lookups not yet consumed, None when the user has no key
+    key_replies: Mutex<HashMap<String, Option<IdentityKey>>>,
+    /// signalled on every answer to an identity key lookup
+    key_replied: Condvar,
+}
+
+/// Message waiting for its acknowledgements
+struct Pending {
+    command: Command,
+    /// the server has the message, only the delivery count is missing
+    accepted: bool,
 }
 
 fn main() -> Result<()> {
@@ -35,9 +115,18 @@ pub enum AppError {
     #[error("Unable to connect to `{0}`")]
     ConnectionError(String),
 
+    #[error("Unable to load TLS certificate `{0}`")]
+    TlsError(String),
+
+    #[error("Unable to load identity `{0}`")]
+    IdentityError(String),
+
     #[error("Unable to write `{0}`")]
     DiskWriteError(String),
 
+    #[error("Unable to access outbox `{0}`")]
+    OutboxError(String),
+
     #[error("Error: `{0}`")]
     OtherError(String),
 }
@@ -51,6 +140,60 @@ pub struct ClientConfig {
     pub hostname: String,
     #[serde(default = "client_config_default_username")]
     pub username: String,
+    /// prompted for when not set
+    pub password: Option<String>,
+    /// create the account instead of logging in
+    #[serde(default)]
+    pub register: bool,
+    #[serde(default = "client_config_default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// PEM file with CA certificates the server certificate has to be issued by
+    pub tls_ca: Option<String>,
+    /// PEM file with the only accepted server certificate
+    pub tls_pinned_cert: Option<String>,
+    /// name the server certificate is verified against, hostname by default
+    pub tls_server_name: Option<String>,
+    /// file with the secret identity key, generated on first use
+    #[serde(default = "client_config_default_identity_file")]
+    pub identity_file: String,
+    /// encrypt direct messages end to end
+    #[serde(default = "client_config_default_e2e")]
+    pub e2e: bool,
+    /// seconds without a frame before the server is pinged
+    #[serde(default = "client_config_default_ping_interval")]
+    pub ping_interval: u64,
+    /// seconds without a frame before the server is considered gone
+    #[serde(default = "client_config_default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// seconds a started frame has to complete in
+    #[serde(default = "client_config_default_frame_timeout")]
+    pub frame_timeout: u64,
+    /// milliseconds before the first reconnect attempt, doubled after every failed one
+    #[serde(default = "client_config_default_reconnect_delay")]
+    pub reconnect_delay: u64,
+    /// longest wait between reconnect attempts in milliseconds
+    #[serde(default = "client_config_default_reconnect_max_delay")]
+    pub reconnect_max_delay: u64,
+    /// number of messages kept while disconnected
+    #[serde(default = "client_config_default_outbox_size")]
+    pub outbox_size: usize,
+    /// file the outbox is kept in across restarts, kept in memory only when not set
+    pub outbox_file: Option<String>,
+    /// wire encoding offered to the server, bincode when the server supports nothing else
+    #[serde(default)]
+    pub codec: Codec,
+    /// largest width of a sent image in pixels
+    #[serde(default = "client_config_default_image_max_dimension")]
+    pub image_max_width: u32,
+    /// largest height of a sent image in pixels
+    #[serde(default = "client_config_default_image_max_dimension")]
+    pub image_max_height: u32,
+    /// scale larger images down before sending instead of refusing them
+    #[serde(default = "client_config_default_image_downscale")]
+    pub image_downscale: bool,
+    /// re-encode images so EXIF and other metadata are not sent
+    #[serde(default = "client_config_default_image_strip_metadata")]
+    pub image_strip_metadata: bool,
 }
 
 fn server_config_default_port() -> u16 {
@@ -61,6 +204,54 @@ fn server_config_default_hostname() -> String {
     "localhost".to_owned()
 }
 
+fn client_config_default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn client_config_default_identity_file() -> String {
+    "identity.key".to_owned()
+}
+
+fn client_config_default_e2e() -> bool {
+    true
+}
+
+fn client_config_default_ping_interval() -> u64 {
+    15
+}
+
+fn client_config_default_idle_timeout() -> u64 {
+    45
+}
+
+fn client_config_default_frame_timeout() -> u64 {
+    10
+}
+
+fn client_config_default_reconnect_delay() -> u64 {
+    500
+}
+
+fn client_config_default_reconnect_max_delay() -> u64 {
+    30_000
+}
+
+fn client_config_default_outbox_size() -> usize {
+    100
+}
+
+fn client_config_default_image_max_dimension() -> u32 {
+    DEFAULT_MAX_IMAGE_DIMENSION
+}
+
+fn client_config_default_image_downscale() -> bool {
+    true
+}
+
+fn client_config_default_image_strip_metadata() -> bool {
+    true
+}
+
 /// Randomly generated username.
 fn client_config_default_username() -> String {
     format!(
@@ -75,14 +266,200 @@ impl Client {
     /// initialize new instance
     fn new() -> Result<Self> {
         let config = envy::from_env::<ClientConfig>().context(AppError::ConfigError())?;
+        let username = Mutex::new(config.username.clone());
+        let password = match &config.password {
+            Some(password) => password.clone(),
+            None => prompt_password(&config.username)?,
+        };
+        let (identity, known_keys) = if config.e2e {
+            let identity = Identity::load_or_generate(Path::new(&config.identity_file))
+                .context(AppError::IdentityError(config.identity_file.clone()))?;
+            let known_keys_file = known_keys_file(&config);
+            let known_keys = KnownKeys::load(Path::new(&known_keys_file))
+                .context(AppError::IdentityError(known_keys_file))?;
+            (Some(identity), known_keys)
+        } else {
+            (None, KnownKeys::in_memory())
+        };
+        let outbox = Outbox::open(
+            config.outbox_size,
+            config.max_frame_size,
+            config.outbox_file.as_ref().map(PathBuf::from),
+        )?;
         Ok(Client {
-            config,
+            tls: tls_config(&config)?,
             stream: Mutex::new(None), // no stream at init
+            codec: Mutex::new(Codec::Bincode),
+            connection_id: AtomicU64::new(0),
+            disconnected: Condvar::new(),
+            outbox: Mutex::new(outbox),
+            message_id: AtomicU64::new(fastrand::u64(..u64::MAX / 2)),
+            pending: Mutex::new(BTreeMap::new()),
+            transfer_messages: Mutex::new(HashMap::new()),
+            aborted_transfers: Mutex::new(HashSet::new()),
+            room: Mutex::new(LOBBY.to_owned()),
+            history: Mutex::new(HashMap::new()),
+            username,
+            password: Mutex::new(password),
+            register: AtomicBool::new(config.register),
+            authenticated: AtomicBool::new(false),
+            identity,
+            known_keys: Mutex::new(known_keys),
+            key_replies: Mutex::new(HashMap::new()),
+            key_replied: Condvar::new(),
+            config,
         })
     }
 
+    /// current username
+    fn username(&self) -> String {
+        self.username
+            .lock()
+            .map(|guard| guard.clone())
+            .unwrap_or_else(|_| self.config.username.clone())
+    }
+
+    /// remember username accepted by the server
+    fn set_username(&self, name: &str) {
+        if let Ok(mut guard) = self.username.lock() {
+            *guard = name.to_owned();
+        }
+    }
+
+    /// command authenticating the client with the current credentials
+    fn credentials(&self) -> Command {
+        let username = self.username();
+        let password = self
+            .password
+            .lock()
+            .map(|guard| guard.clone())
+            .unwrap_or_default();
+
+        if self.register.load(Ordering::Relaxed) {
+            Command::Register(username, password)
+        } else {
+            Command::Login(username, password)
+        }
+    }
+
+    /// switch to another account, the password is prompted for
+    fn set_credentials(&self, username: &str, register: bool) -> Result<Command> {
+        let password = prompt_password(username)?;
+        self.set_username(username);
+        if let Ok(mut guard) = self.password.lock() {
+            *guard = password;
+        }
+        self.register.store(register, Ordering::Relaxed);
+        Ok(self.credentials())
+    }
+
+    /// current room
+    fn room(&self) -> String {
+        self.room
+            .lock()
+            .map(|guard| guard.clone())
+            .unwrap_or_else(|_| LOBBY.to_owned())
+    }
+
+    /// switch current room
+    fn set_room(&self, room: &str) {
+        if let Ok(mut guard) = self.room.lock() {
+            *guard = room.to_owned();
+        }
+    }
+
+    /// command publishing the identity key, nothing to publish without E2E
+    fn publish_key(&self) -> Option<Command> {
+        let identity = self.identity.as_ref()?;
+        Some(Command::PublishKey(identity.public_key()))
+    }
+
+    /// check identity key of a user, the first one is trusted and a changed one refused
+    fn store_key(&self, user: String, key: Option<IdentityKey>) {
+        if let Some(key) = key {
+            match self.check_key(&user, &key) {
+                Ok(KeyTrust::New) => info!(user, "Identity key trusted on first use"),
+                Ok(KeyTrust::Trusted) => {}
+                Ok(KeyTrust::Changed) => warn!(
+                    user,
+                    "Identity key changed, direct messages are refused until it is verified and trusted with .trust {}",
+                    user
+                ),
+                Err(e) => error!(user, "Unable to check identity key: {:#}", e),
+            }
+        }
+
+        if let Ok(mut replies) = self.key_replies.lock() {
+            replies.insert(user, key);
+        }
+        self.key_replied.notify_all();
+    }
+
+    /// compare identity key of a user with the trusted one, the first one is trusted
+    fn check_key(&self, user: &str, key: &IdentityKey) -> Result<KeyTrust> {
+        self.known_keys
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock identity keys".to_owned()))?
+            .check(user, key)
+    }
+
+    /// refuse identity key of a user which is not the trusted one
+    fn verify_key(&self, user: &str, key: &IdentityKey) -> Result<()> {
+        self.known_keys
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock identity keys".to_owned()))?
+            .verify(user, key)
+    }
+
+    /// trust the changed identity key of a user after it was verified
+    fn trust_key(&self, user: &str) -> Result<()> {
+        self.known_keys
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock identity keys".to_owned()))?
+            .trust(user)?;
+        info!(user, "Identity key trusted");
+        Ok(())
+    }
+
+    /// forget previous answer before looking up the key of a user again
+    fn forget_key_reply(&self, user: &str) {
+        if let Ok(mut replies) = self.key_replies.lock() {
+            replies.remove(user);
+        }
+    }
+
+    /// wait for the server to answer the identity key lookup of a user
+    /// only the trusted key of the user is returned
+    fn wait_for_key(&self, user: &str) -> Result<IdentityKey> {
+        let replies = self
+            .key_replies
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock identity keys".to_owned()))?;
+        let (replies, _) = self
+            .key_replied
+            .wait_timeout_while(replies, KEY_LOOKUP_TIMEOUT, |replies| {
+                !replies.contains_key(user)
+            })
+            .map_err(|_| AppError::OtherError("Unable to lock identity keys".to_owned()))?;
+
+        match replies.get(user) {
+            Some(Some(key)) => {
+                self.verify_key(user, key)?;
+                Ok(*key)
+            }
+            Some(None) => Err(AppError::OtherError(format!(
+                "User `{}` has no identity key, set E2E=false to send in plain text",
+                user
+            ))
+            .into()),
+            None => {
+                Err(AppError::OtherError(format!("No identity key received for `{}`", user)).into())
+            }
+        }
+    }
+
     /// get existing stream connection
-    fn get_stream(&self) -> Option<TcpStream> {
+    fn get_stream(&self) -> Option<Stream> {
         if let Ok(mut guard) = self.stream.lock() {
             if let Some(stream) = &mut *guard {
                 if let Ok(stream) = stream.try_clone() {
@@ -93,15 +470,48 @@ impl Client {
         None
     }
 
-    /// create a new server stream
-    fn create_stream(&self) -> Result<TcpStream> {
+    /// create a new server stream and authenticate with the current credentials
+    fn create_stream(&self) -> Result<Stream> {
         let mut guard = self
             .stream
             .lock()
             .map_err(|_| AppError::OtherError("Unable to lock TcpStream".to_owned()))?;
         let server_host = format!("{}:{}", self.config.hostname, self.config.port);
-        let stream = TcpStream::connect(server_host.clone())
-            .context(AppError::ConnectionError(server_host))?;
+        let socket = TcpStream::connect(server_host.clone())
+            .context(AppError::ConnectionError(server_host.clone()))?;
+
+        let mut stream = match &self.tls {
+            Some(tls) => {
+                let server_name = self
+                    .config
+                    .tls_server_name
+                    .as_deref()
+                    .unwrap_or(&self.config.hostname);
+                let stream = TlsStream::connect(socket, tls.clone(), server_name)?;
+                // certificate problems are reported before credentials are sent
+                stream
+                    .handshake()
+                    .context(AppError::ConnectionError(server_host))?;
+                Stream::Tls(stream)
+            }
+            None => Stream::Plain(socket),
+        };
+
+        let codec = self.negotiate_codec(&mut stream)?;
+        if let Ok(mut guard) = self.codec.lock() {
+            *guard = codec;
+        }
+
+        self.authenticated.store(false, Ordering::Relaxed);
+        let credentials = self.credentials();
+        log_outgoing(&credentials);
+        self.send_command(&mut stream, &credentials)?;
+        // accepted once the credentials are
+        if let Some(publish_key) = self.publish_key() {
+            self.send_command(&mut stream, &publish_key)?;
+        }
+
+        self.connection_id.fetch_add(1, Ordering::Relaxed);
         *guard = Some(
             stream
                 .try_clone()
@@ -110,103 +520,659 @@ impl Client {
         Ok(stream)
     }
 
-    /// send message to the stream
-    fn send_message(&self, stream: &mut TcpStream, msg: Message) -> Result<()> {
-        // send message
-        let bytes = msg.encode()?;
-        let len = bytes.len() as u32;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(&bytes)?;
+    /// offer the configured codec before anything else is sent, bincode needs no negotiation
+    fn negotiate_codec(&self, stream: &mut Stream) -> Result<Codec> {
+        if self.config.codec == Codec::Bincode {
+            return Ok(Codec::Bincode);
+        }
+        let offer = Codec::offer(&[self.config.codec, Codec::Bincode]);
+        FrameWriter::new(&mut *stream, self.config.max_frame_size).write_frame(&offer)?;
+
+        stream.set_read_timeout(Some(Duration::from_secs(self.config.frame_timeout)))?;
+        let answer = FrameReader::new(&mut *stream, self.config.max_frame_size).read_frame()?;
+        let codec = Codec::parse_answer(&answer).ok_or(CodecError::InvalidFrame)??;
+        info!(codec = codec.name(), "Codec negotiated");
+        Ok(codec)
+    }
+
+    /// codec of the current connection
+    fn codec(&self) -> Codec {
+        self.codec.lock().map(|codec| *codec).unwrap_or_default()
+    }
+
+    /// send command to the stream
+    fn send_command(&self, stream: &mut Stream, cmd: &Command) -> Result<()> {
+        // send command
+        let bytes = self.codec().encode(cmd)?;
+        FrameWriter::new(stream, self.config.max_frame_size).write_frame(&bytes)?;
 
         Ok(())
     }
 
     /// will read replies from server
-    /// this fce will end on a read error
-    fn read_server_replies(&self, mut stream: TcpStream) -> Result<()> {
+    /// this fce will end on a read error or once the server shuts down
+    /// a quiet server is pinged through tx and dropped once the idle timeout passes
+    /// unfinished file transfers are dropped with the connection, partial files are removed
+    fn read_server_replies(&self, stream: Stream, tx: SyncSender<Command>) -> Result<()> {
+        let mut heartbeat = Heartbeat::new(
+            Duration::from_secs(self.config.ping_interval),
+            Duration::from_secs(self.config.idle_timeout),
+        );
+        let frame_timeout = Duration::from_secs(self.config.frame_timeout);
+        stream.set_read_timeout(Some(heartbeat.poll_interval().min(frame_timeout / 2)))?;
+        let mut reader =
+            FrameReader::new(stream, self.config.max_frame_size).with_frame_timeout(frame_timeout);
+        let mut transfers =
+            IncomingTransfers::new(MAX_INCOMING_TRANSFERS, INCOMING_TRANSFER_TIMEOUT);
+
         loop {
-            // read message
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes)?;
-            let len = u32::from_be_bytes(len_bytes) as usize;
-
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer)?;
-
-            let msg = Message::from_bytes(&buffer)?;
-
-            // process message
-            match msg {
-                Message::Text(text) => info!(text, "Incoming"),
-                Message::Image(image, ext, bytes) => {
-                    info!(image, ext, "Incoming");
-                    match self.process_incoming_image(image, ext, bytes) {
-                        Ok(name) => info!(name, "Image saved"),
-                        Err(e) => error!("Unable to save image: {:#}", e),
+            for (sender, transfer_id) in transfers.expire() {
+                warn!(
+                    sender,
+                    transfer_id, "File transfer stalled, partial file removed"
+                );
+            }
+
+            // read event
+            let buffer = match reader.read_frame() {
+                Ok(buffer) => buffer,
+                Err(FrameError::Idle) => {
+                    match heartbeat.poll() {
+                        Beat::Wait => {}
+                        // a full queue is busy sending, the server hears from us anyway
+                        Beat::Ping(id) => _ = tx.try_send(Command::Ping(id)),
+                        Beat::Expired => {
+                            return Err(AppError::OtherError(format!(
+                                "Nothing received for {} seconds",
+                                self.config.idle_timeout
+                            ))
+                            .into());
+                        }
                     }
+                    continue;
                 }
-                Message::File(file, bytes) => {
-                    info!(file, "incoming");
-                    match self.process_incoming_file(file, bytes) {
-                        Ok(name) => info!(name, "File saved"),
-                        Err(e) => error!("Unable to save file: {:#}", e),
+                Err(e) => return Err(e.into()),
+            };
+            heartbeat.received();
+
+            match self.codec().decode::<Event>(&buffer)? {
+                Event::Welcome(name) => {
+                    info!(name, "Logged in");
+                    self.set_username(&name);
+                    self.set_room(LOBBY);
+                    // account exists now, reconnects log in
+                    self.register.store(false, Ordering::Relaxed);
+                    self.authenticated.store(true, Ordering::Relaxed);
+                }
+                Event::Renamed(old, name) => {
+                    info!(old, name, "Renamed");
+                    if self.username() == old {
+                        self.set_username(&name);
                     }
                 }
+                Event::Message(envelope) => self.process_incoming_message(envelope, &mut transfers),
+                Event::Ack(id, ack) => self.process_ack(id, ack),
+                Event::Joined(room) => {
+                    info!(room, "Joined");
+                    self.set_room(&room);
+                }
+                Event::Left(room) => {
+                    info!(room, "Left");
+                    if self.room() == room {
+                        self.set_room(LOBBY);
+                    }
+                }
+                Event::Rooms(rooms) => {
+                    for room in rooms {
+                        let marker = if room.joined { "*" } else { " " };
+                        println!("\t{} {} ({})", marker, room.name, room.members);
+                    }
+                }
+                Event::Presence(name, Presence::Online) => info!(name, "Online"),
+                Event::Presence(name, Presence::Offline) => {
+                    info!(name, "Offline");
+                    transfers.remove_sender(&name);
+                }
+                Event::Users(users) => {
+                    for user in users {
+                        let since = user.connected.format("%Y-%m-%d %H:%M:%S UTC");
+                        println!("\t{} since {} idle {}s", user.name, since, user.idle);
+                    }
+                }
+                Event::History(room, entries) => self.process_history(room, entries),
+                Event::IdentityKey(user, key) => self.store_key(user, key),
+                Event::Sanctioned(admin, sanction) => self.process_sanction(admin, sanction),
+                Event::Shutdown(notice) => {
+                    warn!("{}", notice);
+                    return Ok(());
+                }
+                Event::Ping(id) => _ = tx.try_send(Command::Pong(id)),
+                Event::Pong(_) => {}
+                Event::Error(e) => error!("Server error: {}", e),
             };
         }
     }
 
+    /// log a sanction taken by an admin, a kicked or banned client does not reconnect
+    fn process_sanction(&self, admin: String, sanction: Sanction) {
+        let username = self.username();
+        match sanction {
+            Sanction::Kick(user, reason) if user == username => {
+                error!(admin, reason, "Kicked by the server");
+                exit(1);
+            }
+            Sanction::Ban(target, reason) if target == username => {
+                error!(admin, reason, "Banned by the server");
+                exit(1);
+            }
+            Sanction::Kick(user, reason) => info!(admin, user, reason, "Kicked"),
+            Sanction::Ban(target, reason) => info!(admin, target, reason, "Banned"),
+            Sanction::Unban(target) => info!(admin, target, "Unbanned"),
+            Sanction::Mute(user, 0) => info!(admin, user, "Unmuted"),
+            Sanction::Mute(user, secs) => info!(admin, user, secs, "Muted"),
+        }
+    }
+
+    /// close current connection, the connection thread connects again
+    fn reset_stream(&self) {
+        self.close_connection(self.connection_id.load(Ordering::Relaxed));
+    }
+
+    /// close connection with the id unless it was replaced already
+    fn close_connection(&self, id: u64) {
+        if let Ok(mut guard) = self.stream.lock() {
+            if self.connection_id.load(Ordering::Relaxed) == id {
+                if let Some(stream) = guard.take() {
+                    _ = stream.shutdown();
+                }
+            }
+        }
+        self.disconnected.notify_all();
+    }
+
+    /// block until the current connection is closed
+    fn wait_for_disconnect(&self) {
+        if let Ok(guard) = self.stream.lock() {
+            let _closed = self
+                .disconnected
+                .wait_while(guard, |stream| stream.is_some());
+        }
+    }
+
+    /// connect to the server, read its replies in a new thread and replay the outbox
+    fn connect<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        tx: SyncSender<Command>,
+    ) -> Result<()> {
+        let mut stream = self.create_stream()?;
+        let id = self.connection_id.load(Ordering::Relaxed);
+
+        let reply_stream = match stream.try_clone() {
+            Ok(reply_stream) => reply_stream,
+            Err(e) => {
+                self.close_connection(id);
+                return Err(e.into());
+            }
+        };
+        scope.spawn(move || {
+            if let Err(response) = self.read_server_replies(reply_stream, tx) {
+                error!("Server disconnected: {:#}", response);
+            }
+            self.close_connection(id);
+        });
+
+        let server = format!("{}:{}", self.config.hostname, self.config.port);
+        info!(server, "Connected");
+
+        if let Err(e) = self.replay_outbox(&mut stream) {
+            self.close_connection(id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// keep the client connected, a lost connection is established again after growing delays
+    fn keep_connected<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        tx: SyncSender<Command>,
+        mut connected: Result<()>,
+    ) {
+        let mut backoff = Backoff::new(
+            Duration::from_millis(self.config.reconnect_delay),
+            Duration::from_millis(self.config.reconnect_max_delay),
+        );
+        loop {
+            match connected {
+                Ok(()) => {
+                    self.wait_for_disconnect();
+                    // a server closing connections before logging in is not retried at once
+                    if !self.authenticated.load(Ordering::Relaxed) {
+                        connected = Err(AppError::OtherError(
+                            "Connection closed before logging in".to_owned(),
+                        )
+                        .into());
+                        continue;
+                    }
+                    backoff.reset();
+                    warn!("Connection lost, reconnecting");
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        attempt = backoff.attempts(),
+                        "Reconnecting in {:.1}s: {:#}",
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    thread::sleep(delay);
+                }
+            }
+            connected = self.connect(scope, tx.clone());
+        }
+    }
+
+    /// send messages queued while disconnected, before any newer message
+    fn replay_outbox(&self, stream: &mut Stream) -> Result<()> {
+        let mut outbox = self
+            .outbox
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock outbox".to_owned()))?;
+        let unacked = self.take_unacked();
+        if !unacked.is_empty() {
+            info!(count = unacked.len(), "Resending unacknowledged messages");
+            outbox.requeue(unacked)?;
+        }
+        let queued = outbox.len();
+        if queued == 0 {
+            return Ok(());
+        }
+
+        let mut replay = || -> Result<()> {
+            while let Some(cmd) = outbox.pop_front() {
+                // a message not sent is resent from the pending ones after reconnecting
+                self.track(&cmd);
+                log_outgoing(&cmd);
+                self.send_command(stream, &cmd)
+                    .context("Unable to send message to the server")?;
+            }
+            Ok(())
+        };
+        let replayed = replay();
+        // the file is rewritten once, whether all messages were sent or not
+        outbox.compact()?;
+        replayed?;
+        info!(queued, "Outbox replayed");
+        Ok(())
+    }
+
+    /// new message sending a command to the target
+    fn message(&self, target: Target, message: Message) -> Command {
+        let id = self.message_id.fetch_add(1, Ordering::Relaxed) + 1;
+        Command::Send(id, target, message)
+    }
+
+    /// remember message until the server acknowledges it
+    fn track(&self, cmd: &Command) {
+        if let (Command::Send(id, ..), Ok(mut pending)) = (cmd, self.pending.lock()) {
+            if replayable(cmd) {
+                let command = cmd.clone();
+                pending.insert(
+                    *id,
+                    Pending {
+                        command,
+                        accepted: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// remember which file transfer a message belongs to, sealed ones can not be told otherwise
+    fn track_transfer(&self, cmd: &Command, transfer_id: Option<u64>) {
+        if let (Command::Send(id, ..), Some(transfer_id)) = (cmd, transfer_id) {
+            if let Ok(mut transfer_messages) = self.transfer_messages.lock() {
+                transfer_messages.insert(*id, transfer_id);
+            }
+        }
+    }
+
+    /// stop the file transfer of a refused message, the rest of the file would be refused as well
+    fn abort_transfer(&self, id: u64) {
+        let Some(transfer_id) = self
+            .transfer_messages
+            .lock()
+            .ok()
+            .and_then(|mut transfer_messages| transfer_messages.remove(&id))
+        else {
+            return;
+        };
+        if let Ok(mut aborted) = self.aborted_transfers.lock() {
+            if aborted.insert(transfer_id) {
+                error!(transfer_id, "File transfer aborted");
+            }
+        }
+    }
+
+    /// whether the outgoing file transfer was aborted
+    fn transfer_aborted(&self, transfer_id: u64) -> bool {
+        self.aborted_transfers
+            .lock()
+            .map(|aborted| aborted.contains(&transfer_id))
+            .unwrap_or_default()
+    }
+
+    /// whether the command is a message of an aborted file transfer, it is forgotten if so
+    fn aborted_message(&self, cmd: &Command) -> bool {
+        let Command::Send(id, ..) = cmd else {
+            return false;
+        };
+        let Ok(mut transfer_messages) = self.transfer_messages.lock() else {
+            return false;
+        };
+        match transfer_messages.get(id) {
+            Some(transfer_id) if self.transfer_aborted(*transfer_id) => {
+                transfer_messages.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// messages never accepted by the server in the order they were sent
+    /// accepted ones are forgotten, their delivery count is lost with the connection
+    fn take_unacked(&self) -> Vec<Command> {
+        let Ok(mut pending) = self.pending.lock() else {
+            return vec![];
+        };
+        std::mem::take(&mut *pending)
+            .into_values()
+            .filter(|pending| !pending.accepted)
+            .map(|pending| pending.command)
+            .collect()
+    }
+
+    /// update status of a sent message, untracked file transfers are only logged
+    /// a file transfer is aborted on its first refused message
+    fn process_ack(&self, id: u64, ack: Ack) {
+        match &ack {
+            Ack::Accepted(_) => {}
+            Ack::Delivered(_) => {
+                if let Ok(mut transfer_messages) = self.transfer_messages.lock() {
+                    transfer_messages.remove(&id);
+                }
+            }
+            Ack::Rejected(_) | Ack::Refused(_) => self.abort_transfer(id),
+        }
+
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        match ack {
+            Ack::Accepted(message_id) => {
+                if let Some(pending) = pending.get_mut(&id) {
+                    pending.accepted = true;
+                }
+                debug!(id, message_id, "Accepted");
+            }
+            Ack::Delivered(recipients) => match pending.remove(&id) {
+                Some(_) => info!(id, recipients, "Delivered"),
+                None => debug!(id, recipients, "Delivered"),
+            },
+            Ack::Rejected(reason) => {
+                pending.remove(&id);
+                error!(id, "Message rejected: {}", reason);
+            }
+            Ack::Refused(e) => {
+                pending.remove(&id);
+                error!(id, "Attachment refused: {}", e);
+            }
+        }
+    }
+
+    /// list messages waiting for acknowledgements and for a connection
+    fn list_pending(&self) {
+        if let Ok(pending) = self.pending.lock() {
+            for (id, pending) in pending.iter() {
+                let Command::Send(_, target, _) = &pending.command else {
+                    continue;
+                };
+                let status = if pending.accepted {
+                    "accepted"
+                } else {
+                    "pending"
+                };
+                println!("\t{} {} {}", id, target, status);
+            }
+        }
+        if let Ok(outbox) = self.outbox.lock() {
+            println!("\t{} queued until connected", outbox.len());
+        }
+    }
+
+    /// send command to the server, messages are kept in the outbox while disconnected
+    fn deliver(&self, cmd: Command) -> Result<()> {
+        let mut outbox = self
+            .outbox
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock outbox".to_owned()))?;
+
+        // chunks read ahead before the transfer was aborted
+        if self.aborted_message(&cmd) {
+            return Ok(());
+        }
+
+        // older messages are still being replayed
+        if let Some(mut stream) = self.get_stream().filter(|_| outbox.is_empty()) {
+            // tracked before sending, the acknowledgement may arrive any time
+            self.track(&cmd);
+            log_outgoing(&cmd);
+            let mut sent = self.send_command(&mut stream, &cmd);
+            // the identity key is published again for the new account
+            if let (Ok(()), Command::Login(..) | Command::Register(..)) = (&sent, &cmd) {
+                if let Some(publish_key) = self.publish_key() {
+                    sent = self.send_command(&mut stream, &publish_key);
+                }
+            }
+
+            match sent.context("Unable to send message to the server") {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    error!("{:#}", e);
+                    self.reset_stream();
+                    // tracked messages are resent after reconnecting
+                    if replayable(&cmd) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        match cmd {
+            // connecting authenticates with the current credentials
+            Command::Login(..) | Command::Register(..) => {
+                info!("Not connected, logging in once connected")
+            }
+            // the next connection starts a new heartbeat
+            Command::Ping(_) | Command::Pong(_) => {}
+            // a file transfer is not replayed, its chunks are read from disk while sending
+            Command::Send(_, _, Message::FileBegin(_, file, _)) => {
+                error!(file, "Not connected, file not sent")
+            }
+            Command::Send(..) if replayable(&cmd) => {
+                outbox.push(cmd)?;
+                info!(queued = outbox.len(), "Not connected, message queued");
+            }
+            Command::Send(..) => {}
+            _ => return Err(AppError::OtherError("Not connected to the server".to_owned()).into()),
+        }
+        Ok(())
+    }
+
+    /// display page of room history and remember where the next page starts
+    fn process_history(&self, room: String, entries: Vec<HistoryEntry>) {
+        let Some(oldest) = entries.first() else {
+            info!(room, "No older messages");
+            return;
+        };
+        if let Ok(mut guard) = self.history.lock() {
+            guard.insert(room, oldest.id);
+        }
+
+        for entry in entries {
+            let HistoryEntry {
+                id,
+                sender,
+                room,
+                timestamp,
+                content,
+            } = entry;
+            let time = timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+            let target = Target::Room(room).to_string();
+
+            match content {
+                HistoryContent::Text(text) => info!(id, sender, target, time, text, "History"),
+                HistoryContent::Image(image, ext, size) => {
+                    info!(id, sender, target, time, image, ext, size, "History")
+                }
+                HistoryContent::File(file, size) => {
+                    info!(id, sender, target, time, file, size, "History")
+                }
+            }
+        }
+    }
+
+    /// open a sealed message after checking the sender key
+    /// own messages carry our key, keys of others are trusted on first use
+    fn open_sealed(&self, sender: &str, sealed: &SealedMessage) -> Result<Message> {
+        let Some(identity) = &self.identity else {
+            return Err(AppError::OtherError("E2E is disabled".to_owned()).into());
+        };
+
+        if sender == self.username() {
+            if sealed.sender_key != identity.public_key() {
+                return Err(ChatMessageError::KeyChanged(sender.to_owned()).into());
+            }
+        } else if self.check_key(sender, &sealed.sender_key)? == KeyTrust::Changed {
+            return Err(ChatMessageError::KeyChanged(sender.to_owned()).into());
+        }
+        identity.open(sealed)
+    }
+
+    /// process message distributed to a room or sent directly
+    fn process_incoming_message(&self, envelope: Envelope, transfers: &mut IncomingTransfers) {
+        if let Message::Sealed(sealed) = &envelope.message {
+            let opened = self.open_sealed(&envelope.sender, sealed);
+            match opened {
+                Ok(message) => self.process_incoming_message(
+                    Envelope {
+                        message,
+                        ..envelope
+                    },
+                    transfers,
+                ),
+                Err(e) => error!(sender = envelope.sender, "Unable to open message: {:#}", e),
+            }
+            return;
+        }
+
+        let Envelope {
+            id,
+            sender,
+            target,
+            timestamp,
+            message,
+        } = envelope;
+        let time = timestamp.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+        let target = target.to_string();
+
+        // process message
+        match message {
+            Message::Text(text) => info!(id, sender, target, time, text, "Incoming"),
+            Message::Image(image, ext, bytes, image_info) => {
+                let ImageInfo {
+                    width,
+                    height,
+                    format,
+                    ..
+                } = &image_info;
+                info!(
+                    id,
+                    sender, target, time, image, ext, width, height, format, "Incoming"
+                );
+                match self.process_incoming_image(image, bytes, &image_info) {
+                    Ok(name) => info!(name, "Image saved"),
+                    Err(e) => error!("Unable to save image: {:#}", e),
+                }
+            }
+            Message::File(file, bytes) => {
+                info!(id, sender, target, time, file, "Incoming");
+                match self.process_incoming_file(file, bytes) {
+                    Ok(name) => info!(name, "File saved"),
+                    Err(e) => error!("Unable to save file: {:#}", e),
+                }
+            }
+            Message::FileBegin(transfer_id, file, size) => {
+                info!(id, sender, target, time, file, size, "Incoming");
+                let begun = self
+                    .incoming_transfer_path(&file)
+                    .and_then(|path| transfers.begin(&sender, transfer_id, &path, size));
+                if let Err(e) = begun {
+                    error!("Unable to save file: {:#}", e);
+                }
+            }
+            Message::FileChunk(transfer_id, bytes) => {
+                if let Err(e) = transfers.write_chunk(&sender, transfer_id, &bytes) {
+                    error!("Unable to save file: {:#}", e);
+                }
+            }
+            Message::FileEnd(transfer_id) => {
+                if let Some(finished) = transfers.finish(&sender, transfer_id) {
+                    match finished {
+                        Ok(path) => info!(name = %path.display(), "File saved"),
+                        Err(e) => error!("Unable to save file: {:#}", e),
+                    }
+                }
+            }
+            // opened above
+            Message::Sealed(_) => {}
+        };
+    }
+
     fn run(&self) -> Result<()> {
         info!("Hello to the Chat Client!");
         info!(self.config.username, "USERNAME");
         info!(self.config.hostname, "HOSTNAME");
         info!(self.config.port, "PORT");
+        info!(self.config.max_frame_size, "MAX_FRAME_SIZE");
+        info!(self.config.outbox_size, "OUTBOX_SIZE");
+        if let Some(outbox_file) = &self.config.outbox_file {
+            info!(outbox_file, "OUTBOX_FILE");
+        }
+        match &self.identity {
+            Some(_) => info!(self.config.identity_file, "IDENTITY_FILE"),
+            None => warn!("E2E is disabled, direct messages are readable by the server"),
+        }
+        match (&self.config.tls_ca, &self.config.tls_pinned_cert) {
+            (Some(tls_ca), _) => info!(tls_ca, "TLS_CA"),
+            (_, Some(tls_pinned_cert)) => info!(tls_pinned_cert, "TLS_PINNED_CERT"),
+            _ => warn!("TLS is disabled, set TLS_CA or TLS_PINNED_CERT to enable it"),
+        }
 
         thread::scope(|scope| {
-            let (tx, rx) = channel::<Message>();
+            let (tx, rx) = sync_channel::<Command>(OUTGOING_QUEUE_SIZE);
+
+            // connect before the greeting is sent, a lost connection is established again
+            let connected = self.connect(scope, tx.clone());
+            let tx_connection = tx.clone();
+            scope.spawn(move || self.keep_connected(scope, tx_connection, connected));
 
             // command processor
             scope.spawn(move || {
                 for cmd in rx.iter() {
-                    match &cmd {
-                        Message::Text(text) => info!(text, "Outgoing"),
-                        Message::Image(image, ext, _) => info!(image, ext, "Outgoing"),
-                        Message::File(file, _) => info!(file, "Outgoing"),
-                    };
-
-                    let mut stream = if let Some(stream) = self.get_stream() {
-                        stream
-                    } else {
-                        let stream = self.create_stream();
-                        let Ok(stream) = stream else {
-                            error!("{:#}", stream.err().unwrap());
-                            continue;
-                        };
-
-                        let reply_stream = stream.try_clone();
-                        let Ok(reply_stream) = reply_stream else {
-                            error!("{:#}", reply_stream.err().unwrap());
-                            continue;
-                        };
-                        scope.spawn(move || {
-                            if let Err(response) = self.read_server_replies(reply_stream) {
-                                error!("Server disconnected: {:#}", response);
-                            }
-                        });
-
-                        stream
-                    };
-
-                    if let Err(e) = self
-                        .send_message(&mut stream, cmd)
-                        .context("Unable to send message to the server")
-                    {
+                    if let Err(e) = self.deliver(cmd) {
                         error!("{:#}", e);
-
-                        // reset stream
-                        if let Ok(mut guard) = self.stream.lock() {
-                            *guard = None;
-                        }
                     }
                 }
             });
@@ -233,19 +1199,30 @@ impl Client {
                             self.ls();
                             continue;
                         }
+                        ".pending" => {
+                            self.list_pending();
+                            continue;
+                        }
                         &_ => {}
                     }
 
-                    let cmd = self.message_from_command_line(cmd_line);
-                    let Ok(cmd) = cmd else {
-                        error!("{:#}", cmd.err().unwrap());
+                    let cmds = self.commands_from_command_line(cmd_line);
+                    let Ok(cmds) = cmds else {
+                        error!("{:#}", cmds.err().unwrap());
                         continue;
                     };
 
                     // file or image command can produce multiple messages
                     // command: .file a.dat b.dat
-                    for msg in cmd {
-                        if let Err(e) = tx_command.send(msg) {
+                    for cmd in cmds {
+                        let cmd = match cmd {
+                            Ok(cmd) => cmd,
+                            Err(e) => {
+                                error!("{:#}", e);
+                                break;
+                            }
+                        };
+                        if let Err(e) = tx_command.send(cmd) {
                             error!("{:#}", e);
                         }
                     }
@@ -256,48 +1233,224 @@ impl Client {
             });
 
             // send initial greeting
-            _ = tx.send(Message::Text(format!(
-                "Hello from {}",
-                self.config.username
-            )));
+            _ = tx.send(self.message(
+                Target::Room(LOBBY.to_owned()),
+                Message::Text(format!("Hello from {}", self.username())),
+            ));
         });
 
         Ok(())
     }
 
-    /// constructs message from command line
-    pub fn message_from_command_line(&self, cmd_line: &str) -> Result<Vec<Message>> {
+    /// constructs commands from command line
+    /// messages are sent to the current room unless addressed to a user with .dm
+    pub fn commands_from_command_line(&self, cmd_line: &str) -> Result<Commands<'_>> {
         let words = cmd_line.split_whitespace().collect::<Vec<_>>();
 
         let Some((cmd, params)) = words.split_first() else {
             return Err(AppError::OtherError("No command supplied".to_owned()).into());
         };
 
-        let message: Vec<Message> = match *cmd {
-            ".file" => params
-                .iter()
-                .map(|filename| Message::new_file_message(filename))
-                .collect::<Result<Vec<_>, _>>()?,
-            ".image" => params
-                .iter()
-                .map(|filename| Message::new_image_message(filename))
-                .collect::<Result<Vec<_>, _>>()?,
-            _ => vec![Message::new_text_message(cmd_line)],
+        let single_command = |command: fn(String) -> Command, usage: &str| -> Result<Commands> {
+            let [param] = params else {
+                return Err(AppError::OtherError(format!("Usage: {} <{}>", cmd, usage)).into());
+            };
+            Ok(Box::new(std::iter::once(Ok(command(param.to_string())))))
+        };
+
+        let (target, message_line) = match *cmd {
+            ".join" => return single_command(Command::Join, "room"),
+            ".leave" => return single_command(Command::Leave, "room"),
+            ".nick" if self.authenticated.load(Ordering::Relaxed) => {
+                return single_command(Command::Nick, "username")
+            }
+            ".nick" => {
+                return Err(AppError::OtherError(
+                    "Not logged in, use .login or .register first".to_owned(),
+                )
+                .into())
+            }
+            ".login" | ".register" if self.authenticated.load(Ordering::Relaxed) => {
+                return Err(AppError::OtherError(format!(
+                    "Already logged in as `{}`",
+                    self.username()
+                ))
+                .into())
+            }
+            ".login" | ".register" => {
+                let [username] = params else {
+                    return Err(AppError::OtherError(format!("Usage: {} <username>", cmd)).into());
+                };
+                let credentials = self.set_credentials(username, *cmd == ".register")?;
+                return Ok(Box::new(std::iter::once(Ok(credentials))));
+            }
+            ".trust" => {
+                let [user] = params else {
+                    return Err(AppError::OtherError("Usage: .trust <user>".to_owned()).into());
+                };
+                self.trust_key(user)?;
+                return Ok(Box::new(std::iter::empty()));
+            }
+            ".rooms" => return Ok(Box::new(std::iter::once(Ok(Command::Rooms)))),
+            ".who" => return Ok(Box::new(std::iter::once(Ok(Command::Who)))),
+            ".kick" | ".ban" => {
+                // command: .kick user reason or .ban user|ip reason
+                let Some((target, reason)) = params.split_first() else {
+                    return Err(
+                        AppError::OtherError(format!("Usage: {} <target> [reason]", cmd)).into(),
+                    );
+                };
+                let (target, reason) = (target.to_string(), reason.join(" "));
+                let sanction = match *cmd {
+                    ".kick" => Sanction::Kick(target, reason),
+                    _ => Sanction::Ban(target, reason),
+                };
+                return Ok(Box::new(std::iter::once(Ok(Command::Moderate(sanction)))));
+            }
+            ".unban" => {
+                return single_command(
+                    |target| Command::Moderate(Sanction::Unban(target)),
+                    "target",
+                )
+            }
+            ".mute" => {
+                let usage = || AppError::OtherError("Usage: .mute <user> <seconds>".to_owned());
+                let [user, secs] = params else {
+                    return Err(usage().into());
+                };
+                let secs = secs.parse().map_err(|_| usage())?;
+                let mute = Command::Moderate(Sanction::Mute(user.to_string(), secs));
+                return Ok(Box::new(std::iter::once(Ok(mute))));
+            }
+            ".history" => {
+                let usage = || AppError::OtherError("Usage: .history [count]".to_owned());
+                let limit = match params {
+                    [] => HISTORY_PAGE_SIZE,
+                    [count] => count.parse().map_err(|_| usage())?,
+                    _ => return Err(usage().into()),
+                };
+                let room = self.room();
+                let before = self
+                    .history
+                    .lock()
+                    .ok()
+                    .and_then(|guard| guard.get(&room).copied());
+                let history = Command::History(room, before, limit);
+                return Ok(Box::new(std::iter::once(Ok(history))));
+            }
+            ".dm" => {
+                // command: .dm user text or .dm user .file a.dat
+                let usage = || AppError::OtherError("Usage: .dm <user> <message>".to_owned());
+                let user = params.first().ok_or_else(usage)?;
+                let message_line = cmd_line[cmd.len()..].trim_start()[user.len()..].trim_start();
+                if message_line.is_empty() {
+                    return Err(usage().into());
+                }
+                (Target::User(user.to_string()), message_line)
+            }
+            _ => (Target::Room(self.room()), cmd_line),
         };
 
-        Ok(message)
+        let messages = self.messages_from_command_line(message_line)?;
+        if let (Target::User(user), Some(identity)) = (&target, &self.identity) {
+            return Ok(self.sealed_commands(identity, user.clone(), messages));
+        }
+        Ok(Box::new(messages.map(move |msg| {
+            msg.map(|msg| {
+                let transfer_id = transfer_id(&msg);
+                let cmd = self.message(target.clone(), msg);
+                self.track_transfer(&cmd, transfer_id);
+                cmd
+            })
+        })))
     }
 
-    /// convert and save incoming image
-    fn process_incoming_image(&self, name: String, ext: String, bytes: Vec<u8>) -> Result<String> {
-        let path = Path::new("incoming_images");
-        fs::create_dir_all(path).context(AppError::DiskWriteError(path.display().to_string()))?;
+    /// looks up the identity key of the user and seals the messages with it
+    /// the key arrives once the lookup is sent, so messages are sealed lazily
+    fn sealed_commands<'a>(
+        &'a self,
+        identity: &'a Identity,
+        user: String,
+        messages: Messages<'a>,
+    ) -> Commands<'a> {
+        self.forget_key_reply(&user);
+        let lookup = once(Ok(Command::GetKey(user.clone())));
+
+        let mut recipient_key = None;
+        let sealed = messages.map(move |msg| {
+            let msg = msg?;
+            let key = match recipient_key {
+                Some(key) => key,
+                None => *recipient_key.insert(self.wait_for_key(&user)?),
+            };
+            let sealed = identity.seal(&key, &msg)?;
+            let cmd = self.message(Target::User(user.clone()), sealed);
+            self.track_transfer(&cmd, transfer_id(&msg));
+            Ok(cmd)
+        });
+        Box::new(lookup.chain(sealed))
+    }
+
+    /// constructs messages from command line
+    /// files are streamed from disk in chunks while the messages are consumed
+    /// reading a file stops once its transfer is aborted
+    fn messages_from_command_line(&self, cmd_line: &str) -> Result<Messages<'_>> {
+        let words = cmd_line.split_whitespace().collect::<Vec<_>>();
 
-        let Some(format) = ImageFormat::from_extension(ext.clone()) else {
-            return Err(ChatMessageError::InvalidImageFormat(ext).into());
+        let Some((cmd, params)) = words.split_first() else {
+            return Err(AppError::OtherError("No message supplied".to_owned()).into());
+        };
+
+        let messages: Messages = match *cmd {
+            ".file" => {
+                // open all files first so a missing one fails the whole command
+                let transfers = params
+                    .iter()
+                    .map(|filename| {
+                        let transfer_id = fastrand::u64(..);
+                        Message::new_file_transfer(filename, transfer_id)
+                            .map(|transfer| (transfer_id, transfer))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Box::new(
+                    transfers
+                        .into_iter()
+                        .flat_map(move |(transfer_id, transfer)| {
+                            transfer.take_while(move |_| !self.transfer_aborted(transfer_id))
+                        }),
+                )
+            }
+            ".image" => {
+                let options = ImageOptions {
+                    max_width: self.config.image_max_width,
+                    max_height: self.config.image_max_height,
+                    downscale: self.config.image_downscale,
+                    strip_metadata: self.config.image_strip_metadata,
+                };
+                let images = params
+                    .iter()
+                    .map(|filename| Message::new_image_message_with_options(filename, &options))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Box::new(images.into_iter().map(Ok))
+            }
+            _ => Box::new(std::iter::once(Ok(Message::new_text_message(cmd_line)))),
         };
 
-        let img = image::load_from_memory_with_format(&bytes, format)?;
+        Ok(messages)
+    }
+
+    /// convert and save incoming image
+    /// the header has to match the image info, the pixels are decoded within limits
+    fn process_incoming_image(
+        &self,
+        name: String,
+        bytes: Vec<u8>,
+        info: &ImageInfo,
+    ) -> Result<String> {
+        let img = decode_image(&name, &bytes, info, MAX_DECODED_IMAGE_DIMENSION)?;
+
+        let path = Path::new("incoming_images");
+        fs::create_dir_all(path).context(AppError::DiskWriteError(path.display().to_string()))?;
 
         let t = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
         let output_file = path.join(format!("{}-{}.png", t, name));
@@ -315,13 +1468,28 @@ impl Client {
         Ok(output_file)
     }
 
-    /// convert and save incoming file
-    fn process_incoming_file(&self, name: String, bytes: Vec<u8>) -> Result<String> {
+    /// construct output path of an incoming file
+    fn incoming_file_path(&self, name: &str) -> Result<PathBuf> {
         let path = Path::new("incoming_files");
-        fs::create_dir_all(path)?;
+        fs::create_dir_all(path).context(AppError::DiskWriteError(path.display().to_string()))?;
 
         let t = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
-        let output_file = path.join(format!("{}-{}", t, name));
+        Ok(path.join(format!("{}-{}", t, name)))
+    }
+
+    /// construct output path of an incoming chunked file transfer
+    fn incoming_transfer_path(&self, name: &str) -> Result<PathBuf> {
+        // only the file name is used, never a path supplied by the sender
+        let Some(name) = Path::new(name).file_name().and_then(OsStr::to_str) else {
+            return Err(AppError::OtherError("Invalid file name".to_owned()).into());
+        };
+
+        self.incoming_file_path(name)
+    }
+
+    /// convert and save incoming file
+    fn process_incoming_file(&self, name: String, bytes: Vec<u8>) -> Result<String> {
+        let output_file = self.incoming_file_path(&name)?;
         let output_file = match output_file.to_str() {
             None => {
                 return Err(
@@ -350,3 +1518,105 @@ impl Client {
         }
     }
 }
+
+/// TLS configuration trusting either a CA or a single pinned certificate
+fn tls_config(config: &ClientConfig) -> Result<Option<Arc<TlsClientConfig>>> {
+    let tls = match (&config.tls_ca, &config.tls_pinned_cert) {
+        (Some(ca), None) => {
+            tls_client_config_with_ca(ca).context(AppError::TlsError(ca.clone()))?
+        }
+        (None, Some(cert)) => {
+            tls_client_config_with_pinned_cert(cert).context(AppError::TlsError(cert.clone()))?
+        }
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => {
+            return Err(AppError::OtherError(
+                "Set either TLS_CA or TLS_PINNED_CERT, not both".to_owned(),
+            )
+            .into())
+        }
+    };
+    Ok(Some(tls))
+}
+
+/// file keeping the identity keys of other users, next to the identity file
+fn known_keys_file(config: &ClientConfig) -> String {
+    format!("{}.known", config.identity_file)
+}
+
+/// read password from the terminal without echoing it
+fn prompt_password(username: &str) -> Result<String> {
+    let password = rpassword::prompt_password(format!("Password for {}: ", username))
+        .context(AppError::OtherError("Unable to read password".to_owned()))?;
+    Ok(password)
+}
+
+/// log outgoing command
+fn log_outgoing(cmd: &Command) {
+    match cmd {
+        Command::Login(name, _) => info!(name, "Login"),
+        Command::Register(name, _) => info!(name, "Register"),
+        Command::Nick(name) => info!(name, "Nick"),
+        Command::Send(id, target, msg) => log_outgoing_message(*id, target, msg),
+        Command::Join(room) => info!(room, "Join"),
+        Command::Leave(room) => info!(room, "Leave"),
+        Command::Rooms => info!("Rooms"),
+        Command::Who => info!("Who"),
+        Command::History(room, before, limit) => info!(room, before, limit, "History"),
+        Command::PublishKey(_) => debug!("PublishKey"),
+        Command::GetKey(user) => debug!(user, "GetKey"),
+        Command::Moderate(sanction) => info!(?sanction, "Moderate"),
+        Command::Ping(id) => debug!(id, "Ping"),
+        Command::Pong(id) => debug!(id, "Pong"),
+    };
+}
+
+/// log outgoing message, the id is acknowledged by the server
+fn log_outgoing_message(id: u64, target: &Target, msg: &Message) {
+    let target = target.to_string();
+    match msg {
+        Message::Text(text) => info!(id, target, text, "Outgoing"),
+        Message::Image(image, ext, _, image_info) => info!(
+            id,
+            target,
+            image,
+            ext,
+            width = image_info.width,
+            height = image_info.height,
+            "Outgoing"
+        ),
+        Message::File(file, _) => info!(id, target, file, "Outgoing"),
+        Message::FileBegin(transfer_id, file, size) => {
+            info!(id, target, transfer_id, file, size, "Outgoing")
+        }
+        Message::FileChunk(transfer_id, bytes) => {
+            debug!(id, target, transfer_id, len = bytes.len(), "Outgoing")
+        }
+        Message::FileEnd(transfer_id) => info!(id, target, transfer_id, "Outgoing"),
+        // the copy sent back by the server is shown once opened
+        Message::Sealed(sealed) => {
+            debug!(id, target, len = sealed.ciphertext.len(), "Outgoing")
+        }
+    };
+}
+
+/// transfer id of a file transfer message
+fn transfer_id(msg: &Message) -> Option<u64> {
+    match msg {
+        Message::FileBegin(transfer_id, ..)
+        | Message::FileChunk(transfer_id, _)
+        | Message::FileEnd(transfer_id) => Some(*transfer_id),
+        _ => None,
+    }
+}
+
+/// whether a command is a message kept for resending, file transfers are read from disk once
+fn replayable(cmd: &Command) -> bool {
+    match cmd {
+        Command::Send(_, _, msg) => !matches!(
+            msg,
+            Message::FileBegin(..) | Message::FileChunk(..) | Message::FileEnd(_)
+        ),
+        _ => false,
+    }
+}
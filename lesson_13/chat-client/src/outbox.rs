@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chatlib::{Command, FrameError, FrameReader, FrameWriter};
+use tracing::warn;
+
+use crate::AppError;
+
+/// Messages not sent while disconnected, replayed in order once connected again
+/// the file is a log new messages are appended to, it is rewritten only once compacted
+pub struct Outbox {
+    commands: VecDeque<Command>,
+    capacity: usize,
+    max_frame_size: usize,
+    /// queued messages survive a restart of the client when set
+    file: Option<PathBuf>,
+    /// number of messages in the file, the oldest ones may have been dropped already
+    logged: usize,
+}
+
+impl Outbox {
+    /// initialize new outbox holding at most capacity messages
+    /// messages left in the file by a previous run are loaded
+    pub fn open(capacity: usize, max_frame_size: usize, file: Option<PathBuf>) -> Result<Self> {
+        let mut outbox = Outbox {
+            commands: VecDeque::new(),
+            capacity: capacity.max(1),
+            max_frame_size,
+            file,
+            logged: 0,
+        };
+        if let Some(file) = &outbox.file {
+            let bytes = match fs::read(file) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e).context(AppError::OutboxError(file.display().to_string())),
+            };
+
+            // stored as frames of the wire format
+            let mut reader = FrameReader::new(&bytes[..], max_frame_size);
+            loop {
+                match reader.read_frame() {
+                    Ok(frame) => {
+                        outbox.commands.push_back(Command::from_bytes(&frame)?);
+                        outbox.logged += 1;
+                    }
+                    Err(FrameError::Closed) => break,
+                    Err(e) => {
+                        return Err(e).context(AppError::OutboxError(file.display().to_string()))
+                    }
+                }
+            }
+            // the log keeps messages dropped since it was compacted
+            let dropped = outbox.commands.len().saturating_sub(outbox.capacity);
+            outbox.commands.drain(..dropped);
+        }
+        Ok(outbox)
+    }
+
+    /// queue message, the oldest one is dropped once the outbox is full
+    pub fn push(&mut self, command: Command) -> Result<()> {
+        if self.commands.len() >= self.capacity {
+            self.commands.pop_front();
+            warn!(
+                capacity = self.capacity,
+                "Outbox full, oldest message dropped"
+            );
+        }
+        self.append(&command)?;
+        self.commands.push_back(command);
+        Ok(())
+    }
+
+    /// queue messages ahead of all others, sent before but never acknowledged
+    pub fn requeue(&mut self, commands: Vec<Command>) -> Result<()> {
+        for command in commands.into_iter().rev() {
+            self.commands.push_front(command);
+        }
+        if self.commands.len() > self.capacity {
+            let dropped = self.commands.len() - self.capacity;
+            self.commands.drain(..dropped);
+            warn!(dropped, "Outbox full, oldest messages dropped");
+        }
+        self.compact()
+    }
+
+    /// take oldest queued message, the file keeps it until compacted
+    pub fn pop_front(&mut self) -> Option<Command> {
+        self.commands.pop_front()
+    }
+
+    /// number of queued messages
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// whether no message is queued
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// add message to the end of the file, compacted once it holds twice the capacity
+    fn append(&mut self, command: &Command) -> Result<()> {
+        if self.logged >= 2 * self.capacity {
+            self.compact()?;
+        }
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let handle = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .context(AppError::OutboxError(file.display().to_string()))?;
+        FrameWriter::new(handle, self.max_frame_size).write_frame(&command.encode()?)?;
+        self.logged += 1;
+        Ok(())
+    }
+
+    /// rewrite the file with the queued messages only, taken and dropped ones are removed
+    pub fn compact(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let mut bytes = Vec::new();
+        let mut writer = FrameWriter::new(&mut bytes, self.max_frame_size);
+        for command in &self.commands {
+            writer.write_frame(&command.encode()?)?;
+        }
+        fs::write(file, bytes).context(AppError::OutboxError(file.display().to_string()))?;
+        self.logged = self.commands.len();
+        Ok(())
+    }
+}
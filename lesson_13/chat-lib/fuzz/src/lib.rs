@@ -0,0 +1,60 @@
+//! Checks run by the fuzz targets on untrusted input
+//! the chat-lib tests replay the corpus through them without cargo fuzz
+
+use std::fmt::Debug;
+
+use chat_lib::{Codec, Command, Event, FrameReader, Message};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Largest frame accepted while fuzzing, keeps runs from allocating huge buffers
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// read frames until the input ends, every frame is decoded as a peer would
+pub fn frame_reader(data: &[u8]) {
+    let mut reader = FrameReader::new(data, MAX_FRAME_SIZE);
+    while let Ok(frame) = reader.read_frame() {
+        assert!(frame.len() <= MAX_FRAME_SIZE);
+        protocol_decode(&frame);
+    }
+}
+
+/// a message decodes in any codec or fails cleanly
+pub fn message_decode(data: &[u8]) {
+    for codec in supported() {
+        round_trip::<Message>(codec, data);
+    }
+}
+
+/// commands, events and codec offers decode or fail cleanly
+pub fn protocol_decode(data: &[u8]) {
+    if let Some(Ok(offered)) = Codec::parse_offer(data) {
+        assert_eq!(
+            Codec::parse_offer(&Codec::offer(&offered))
+                .unwrap()
+                .unwrap(),
+            offered
+        );
+    }
+    for codec in supported() {
+        round_trip::<Command>(codec, data);
+        round_trip::<Event>(codec, data);
+    }
+}
+
+fn supported() -> impl Iterator<Item = Codec> {
+    Codec::ALL.into_iter().filter(Codec::is_supported)
+}
+
+/// whatever decodes encodes again and decodes to the same value
+fn round_trip<T>(codec: Codec, data: &[u8])
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let Ok(value) = codec.decode::<T>(data) else {
+        return;
+    };
+    let bytes = codec.encode(&value).expect("decoded value encodes");
+    let decoded = codec.decode::<T>(&bytes).expect("encoded value decodes");
+    assert_eq!(decoded, value, "{}", codec);
+}
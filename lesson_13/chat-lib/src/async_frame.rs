@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::frame::{check_frame_len, LEN_PREFIX_SIZE};
+use crate::FrameError;
+
+/// Async counterpart of FrameReader, reads length prefixed frames
+pub struct AsyncFrameReader<R: AsyncRead + Unpin> {
+    inner: R,
+    max_frame_size: usize,
+    /// time waited for a frame to start before reporting idle
+    idle_timeout: Option<Duration>,
+    /// time a started frame has to complete in
+    frame_timeout: Option<Duration>,
+}
+
+/// Async counterpart of FrameWriter, writes length prefixed frames
+pub struct AsyncFrameWriter<W: AsyncWrite + Unpin> {
+    inner: W,
+    max_frame_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncFrameReader<R> {
+    /// construct a new reader accepting frames up to max_frame_size bytes
+    pub fn new(inner: R, max_frame_size: usize) -> Self {
+        AsyncFrameReader {
+            inner,
+            max_frame_size,
+            idle_timeout: None,
+            frame_timeout: None,
+        }
+    }
+
+    /// report idle when no frame starts within the timeout, reading can continue after
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// frames have to complete within the timeout once their first byte arrived
+    pub fn with_frame_timeout(mut self, frame_timeout: Duration) -> Self {
+        self.frame_timeout = Some(frame_timeout);
+        self
+    }
+
+    /// read next frame payload
+    /// the announced length is validated before any payload memory is allocated
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut len_bytes = [0u8; LEN_PREFIX_SIZE];
+        // a read cancelled by the timeout consumes nothing
+        let first = match self.idle_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, self.inner.read(&mut len_bytes))
+                .await
+                .map_err(|_| FrameError::Idle)??,
+            None => self.inner.read(&mut len_bytes).await?,
+        };
+        if first == 0 {
+            return Err(FrameError::Closed);
+        }
+
+        match self.frame_timeout {
+            Some(frame_timeout) => timeout(frame_timeout, self.read_rest(len_bytes, first))
+                .await
+                .map_err(|_| FrameError::FrameTimeout(frame_timeout))?,
+            None => self.read_rest(len_bytes, first).await,
+        }
+    }
+
+    /// read rest of a frame whose first bytes arrived already
+    async fn read_rest(
+        &mut self,
+        mut len_bytes: [u8; LEN_PREFIX_SIZE],
+        first: usize,
+    ) -> Result<Vec<u8>, FrameError> {
+        let received = first + self.read_fully(&mut len_bytes[first..]).await?;
+        if received < LEN_PREFIX_SIZE {
+            return Err(FrameError::Truncated {
+                expected: LEN_PREFIX_SIZE,
+                received,
+            });
+        }
+
+        let len = check_frame_len(u32::from_be_bytes(len_bytes) as usize, self.max_frame_size)?;
+
+        let mut buffer = vec![0u8; len];
+        let received = self.read_fully(&mut buffer).await?;
+        if received < len {
+            return Err(FrameError::Truncated {
+                expected: len,
+                received,
+            });
+        }
+
+        Ok(buffer)
+    }
+
+    /// read until the buffer is full or the stream ends, returns number of bytes read
+    async fn read_fully(&mut self, buffer: &mut [u8]) -> Result<usize, FrameError> {
+        let mut received = 0;
+        while received < buffer.len() {
+            match self.inner.read(&mut buffer[received..]).await? {
+                0 => break,
+                n => received += n,
+            }
+        }
+        Ok(received)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncFrameWriter<W> {
+    /// construct a new writer refusing frames over max_frame_size bytes
+    pub fn new(inner: W, max_frame_size: usize) -> Self {
+        AsyncFrameWriter {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    /// write a single frame payload prefixed by its length
+    pub async fn write_frame(&mut self, bytes: &[u8]) -> Result<(), FrameError> {
+        let len = check_frame_len(bytes.len(), self.max_frame_size)?;
+
+        self.inner.write_all(&(len as u32).to_be_bytes()).await?;
+        self.inner.write_all(bytes).await?;
+        self.inner.flush().await?;
+
+        Ok(())
+    }
+}
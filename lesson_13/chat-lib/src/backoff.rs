@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+/// Delays between reconnect attempts, doubling from initial up to max
+/// half of every delay is random so that clients dropped together do not return together
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// initialize new instance, the first delay is about initial
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            attempts: 0,
+        }
+    }
+
+    /// number of delays handed out since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// delay before the next attempt, between half and all of the current step
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .initial
+            .saturating_mul(1 << self.attempts.min(31))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        let half = step / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+
+    /// start from the initial delay again, called once connected
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Prefix of the frames negotiating a codec, no bincode command or event starts with it
+pub const CODEC_PREFIX: &[u8] = b"CODEC ";
+
+/// Wire encoding of commands and events
+/// connections speak bincode until a client offers another codec as its first frame
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Compact binary encoding shared with Rust peers
+    #[default]
+    Bincode,
+    /// JSON text, attachments as base64
+    Json,
+    /// MessagePack with named fields
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Unknown codec `{0}`")]
+    Unknown(String),
+    #[error("Codec `{0}` is not supported by this build")]
+    Unsupported(Codec),
+    #[error("None of the offered codecs is supported")]
+    NoneSupported,
+    #[error("Invalid codec negotiation frame")]
+    InvalidFrame,
+}
+
+impl Codec {
+    /// All codecs in order of preference
+    pub const ALL: [Codec; 3] = [Codec::Json, Codec::MessagePack, Codec::Bincode];
+
+    /// name used in negotiation frames and configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Bincode => "bincode",
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    /// whether the codec was compiled in, bincode always is
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Codec::Bincode => true,
+            Codec::Json => cfg!(feature = "json"),
+            Codec::MessagePack => cfg!(feature = "msgpack"),
+        }
+    }
+
+    /// encode a command, an event or a message
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+            #[cfg(feature = "json")]
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+            #[allow(unreachable_patterns)]
+            codec => Err(CodecError::Unsupported(*codec).into()),
+        }
+    }
+
+    /// decode a command, an event or a message
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Codec::Bincode => Ok(bincode::deserialize(bytes)?),
+            #[cfg(feature = "json")]
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            #[allow(unreachable_patterns)]
+            codec => Err(CodecError::Unsupported(*codec).into()),
+        }
+    }
+
+    /// frame offering codecs in order of preference, e.g. `CODEC json,bincode`
+    pub fn offer(codecs: &[Codec]) -> Vec<u8> {
+        let names: Vec<&str> = codecs.iter().map(Codec::name).collect();
+        [CODEC_PREFIX, names.join(",").as_bytes()].concat()
+    }
+
+    /// frame answering an offer with the codec picked
+    pub fn answer(&self) -> Vec<u8> {
+        Codec::offer(&[*self])
+    }
+
+    /// codecs offered by a frame, none when the frame is no negotiation frame
+    pub fn parse_offer(frame: &[u8]) -> Option<Result<Vec<Codec>, CodecError>> {
+        let names = frame.strip_prefix(CODEC_PREFIX)?;
+        let Ok(names) = std::str::from_utf8(names) else {
+            return Some(Err(CodecError::InvalidFrame));
+        };
+        Some(names.split(',').map(|name| name.trim().parse()).collect())
+    }
+
+    /// codec picked by the peer, none when the frame is no negotiation frame
+    pub fn parse_answer(frame: &[u8]) -> Option<Result<Codec, CodecError>> {
+        Some(
+            Codec::parse_offer(frame)?.and_then(|codecs| match codecs[..] {
+                [codec] => Ok(codec),
+                _ => Err(CodecError::InvalidFrame),
+            }),
+        )
+    }
+
+    /// first offered codec supported by this build
+    pub fn negotiate(offered: &[Codec]) -> Result<Codec, CodecError> {
+        offered
+            .iter()
+            .find(|codec| codec.is_supported())
+            .copied()
+            .ok_or(CodecError::NoneSupported)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = CodecError;
+
+    fn from_str(name: &str) -> Result<Codec, CodecError> {
+        Codec::ALL
+            .into_iter()
+            .find(|codec| codec.name() == name)
+            .ok_or_else(|| CodecError::Unknown(name.to_owned()))
+    }
+}
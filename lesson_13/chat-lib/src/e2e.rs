@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{ChatMessageError, Codec, IdentityKey, Message, SealedMessage};
+
+/// Domain separation of the derived message keys
+const KEY_INFO: &[u8] = b"chat e2e direct message key v1";
+
+/// Long term x25519 key pair of a client
+/// messages are sealed with a key derived from the static shared secret of both parties
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+/// Identity keys of other users trusted on first use
+/// a key differing from the trusted one is not used until the user trusts it explicitly
+pub struct KnownKeys {
+    trusted: BTreeMap<String, IdentityKey>,
+    /// keys presented for users which differ from their trusted ones
+    changed: HashMap<String, IdentityKey>,
+    /// trusted keys survive a restart when set
+    file: Option<PathBuf>,
+}
+
+/// How a key presented for a user relates to the trusted one
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyTrust {
+    /// first key of the user, trusted from now on
+    New,
+    /// the trusted key
+    Trusted,
+    /// differs from the trusted key, refused until trusted explicitly
+    Changed,
+}
+
+impl Identity {
+    /// generate a new random identity
+    pub fn generate() -> Identity {
+        Identity::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// load identity from file, a new one is generated and saved when the file does not exist
+    pub fn load_or_generate(path: &Path) -> Result<Identity> {
+        if !path.exists() {
+            let identity = Identity::generate();
+            identity.save(path)?;
+            return Ok(identity);
+        }
+
+        let bytes =
+            fs::read(path).context(ChatMessageError::FileReadError(path.display().to_string()))?;
+        let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+            ChatMessageError::EncryptionError(format!("invalid identity file {}", path.display()))
+        })?;
+        Ok(Identity::from_secret(StaticSecret::from(secret)))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Identity {
+        let public = PublicKey::from(&secret);
+        Identity { secret, public }
+    }
+
+    /// write the secret key readable by the owner only
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options
+            .open(path)
+            .context(ChatMessageError::OtherError(format!(
+                "Unable to create identity file {}",
+                path.display()
+            )))?;
+        file.write_all(self.secret.as_bytes())?;
+        Ok(())
+    }
+
+    /// public key published to other users
+    pub fn public_key(&self) -> IdentityKey {
+        self.public.to_bytes()
+    }
+
+    /// encrypt message for the owner of the recipient key
+    pub fn seal(&self, recipient_key: &IdentityKey, message: &Message) -> Result<Message> {
+        if let Message::Sealed(_) = message {
+            return Err(encryption_error("message is already sealed"));
+        }
+
+        let sender_key = self.public_key();
+        let cipher = self.cipher(recipient_key)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(&sender_key, recipient_key);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &message.encode()?,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| encryption_error("unable to encrypt message"))?;
+
+        Ok(Message::Sealed(SealedMessage {
+            sender_key,
+            recipient_key: *recipient_key,
+            nonce: nonce.into(),
+            ciphertext,
+        }))
+    }
+
+    /// decrypt message sent to or by this identity
+    pub fn open(&self, sealed: &SealedMessage) -> Result<Message> {
+        let own_key = self.public_key();
+        let peer_key = if sealed.recipient_key == own_key {
+            &sealed.sender_key
+        } else if sealed.sender_key == own_key {
+            // copy of a message we sent
+            &sealed.recipient_key
+        } else {
+            return Err(encryption_error("message is sealed for another identity"));
+        };
+
+        let aad = associated_data(&sealed.sender_key, &sealed.recipient_key);
+        let bytes = self
+            .cipher(peer_key)?
+            .decrypt(
+                &sealed.nonce.into(),
+                Payload {
+                    msg: &sealed.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| encryption_error("message was tampered with"))?;
+
+        match Message::from_bytes(&bytes)? {
+            Message::Sealed(_) => Err(encryption_error("nested sealed message")),
+            message => Ok(message),
+        }
+    }
+
+    /// cipher keyed by the shared secret with the peer
+    fn cipher(&self, peer_key: &IdentityKey) -> Result<XChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_key));
+        // low order keys would make the secret predictable
+        if !shared.was_contributory() {
+            return Err(encryption_error("invalid identity key"));
+        }
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(KEY_INFO, &mut key)
+            .map_err(|_| encryption_error("unable to derive key"))?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+impl KnownKeys {
+    /// keys which are lost when dropped
+    pub fn in_memory() -> KnownKeys {
+        KnownKeys {
+            trusted: BTreeMap::new(),
+            changed: HashMap::new(),
+            file: None,
+        }
+    }
+
+    /// load keys trusted by a previous run, the file is created once a key is trusted
+    pub fn load(path: &Path) -> Result<KnownKeys> {
+        let trusted = match fs::read(path) {
+            Ok(bytes) => {
+                Codec::Bincode
+                    .decode(&bytes)
+                    .context(ChatMessageError::EncryptionError(format!(
+                        "invalid known keys file {}",
+                        path.display()
+                    )))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).context(ChatMessageError::FileReadError(path.display().to_string()))
+            }
+        };
+
+        Ok(KnownKeys {
+            trusted,
+            changed: HashMap::new(),
+            file: Some(path.to_path_buf()),
+        })
+    }
+
+    /// check a key presented for the user, the first one is trusted and saved
+    pub fn check(&mut self, user: &str, key: &IdentityKey) -> Result<KeyTrust> {
+        match self.trusted.get(user) {
+            Some(trusted) if trusted == key => {
+                self.changed.remove(user);
+                Ok(KeyTrust::Trusted)
+            }
+            Some(_) => {
+                self.changed.insert(user.to_owned(), *key);
+                Ok(KeyTrust::Changed)
+            }
+            None => {
+                self.trusted.insert(user.to_owned(), *key);
+                self.save()?;
+                Ok(KeyTrust::New)
+            }
+        }
+    }
+
+    /// refuse a key which is not the trusted key of the user
+    pub fn verify(&self, user: &str, key: &IdentityKey) -> Result<()> {
+        match self.trusted.get(user) {
+            Some(trusted) if trusted == key => Ok(()),
+            Some(_) => Err(ChatMessageError::KeyChanged(user.to_owned()).into()),
+            None => Err(encryption_error("identity key is not trusted")),
+        }
+    }
+
+    /// trust the last changed key presented for the user instead of the old one
+    pub fn trust(&mut self, user: &str) -> Result<IdentityKey> {
+        let Some(key) = self.changed.remove(user) else {
+            return Err(ChatMessageError::EncryptionError(format!(
+                "identity key of `{}` did not change",
+                user
+            ))
+            .into());
+        };
+        self.trusted.insert(user.to_owned(), key);
+        self.save()?;
+        Ok(key)
+    }
+
+    /// rewrite the file with the trusted keys
+    fn save(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        fs::write(file, Codec::Bincode.encode(&self.trusted)?).context(
+            ChatMessageError::OtherError(format!(
+                "Unable to write known keys file {}",
+                file.display()
+            )),
+        )
+    }
+}
+
+/// both identity keys are authenticated together with the message
+fn associated_data(sender_key: &IdentityKey, recipient_key: &IdentityKey) -> Vec<u8> {
+    [sender_key.as_slice(), recipient_key.as_slice()].concat()
+}
+
+fn encryption_error(reason: &str) -> anyhow::Error {
+    ChatMessageError::EncryptionError(reason.to_owned()).into()
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+/// Default upper bound of a single frame payload (64 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the big-endian length prefix preceding every frame
+pub(crate) const LEN_PREFIX_SIZE: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Connection closed")]
+    Closed,
+    #[error("Frame of {len} bytes exceeds the limit of {max} bytes")]
+    Oversized { len: usize, max: usize },
+    #[error("Frame truncated after {received} of {expected} bytes")]
+    Truncated { expected: usize, received: usize },
+    /// nothing consumed, reading can continue
+    #[error("No frame received")]
+    Idle,
+    #[error("Frame not completed within {0:?}")]
+    FrameTimeout(Duration),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Reads length prefixed frames from the underlying reader
+/// read timeouts of the underlying reader before a frame starts are reported as idle
+pub struct FrameReader<R: Read> {
+    inner: R,
+    max_frame_size: usize,
+    /// time a started frame has to complete in, read timeouts within it are retried
+    frame_timeout: Option<Duration>,
+}
+
+/// Writes length prefixed frames into the underlying writer
+pub struct FrameWriter<W: Write> {
+    inner: W,
+    max_frame_size: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// construct a new reader accepting frames up to max_frame_size bytes
+    pub fn new(inner: R, max_frame_size: usize) -> Self {
+        FrameReader {
+            inner,
+            max_frame_size,
+            frame_timeout: None,
+        }
+    }
+
+    /// frames have to complete within the timeout once their first byte arrived
+    /// the underlying reader needs a read timeout shorter than that
+    pub fn with_frame_timeout(mut self, frame_timeout: Duration) -> Self {
+        self.frame_timeout = Some(frame_timeout);
+        self
+    }
+
+    /// read next frame payload
+    /// the announced length is validated before any payload memory is allocated
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut started = None;
+        let mut len_bytes = [0u8; LEN_PREFIX_SIZE];
+        let received = self.read_fully(&mut len_bytes, &mut started)?;
+        if received == 0 {
+            return Err(FrameError::Closed);
+        }
+        if received < LEN_PREFIX_SIZE {
+            return Err(FrameError::Truncated {
+                expected: LEN_PREFIX_SIZE,
+                received,
+            });
+        }
+
+        let len = check_frame_len(u32::from_be_bytes(len_bytes) as usize, self.max_frame_size)?;
+
+        let mut buffer = vec![0u8; len];
+        let received = self.read_fully(&mut buffer, &mut started)?;
+        if received < len {
+            return Err(FrameError::Truncated {
+                expected: len,
+                received,
+            });
+        }
+
+        Ok(buffer)
+    }
+
+    /// read until the buffer is full or the stream ends, returns number of bytes read
+    /// started is set once the first byte of the frame arrives
+    fn read_fully(
+        &mut self,
+        buffer: &mut [u8],
+        started: &mut Option<Instant>,
+    ) -> Result<usize, FrameError> {
+        let mut received = 0;
+        while received < buffer.len() {
+            match self.inner.read(&mut buffer[received..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    started.get_or_insert_with(Instant::now);
+                    received += n;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    match (*started, self.frame_timeout) {
+                        (None, _) => return Err(FrameError::Idle),
+                        (Some(started), Some(timeout)) if started.elapsed() < timeout => continue,
+                        (Some(_), Some(timeout)) => return Err(FrameError::FrameTimeout(timeout)),
+                        (Some(_), None) => return Err(e.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(received)
+    }
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// construct a new writer refusing frames over max_frame_size bytes
+    pub fn new(inner: W, max_frame_size: usize) -> Self {
+        FrameWriter {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    /// write a single frame payload prefixed by its length
+    pub fn write_frame(&mut self, bytes: &[u8]) -> Result<(), FrameError> {
+        let len = check_frame_len(bytes.len(), self.max_frame_size)?;
+
+        self.inner.write_all(&(len as u32).to_be_bytes())?;
+        self.inner.write_all(bytes)?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+}
+
+/// validate frame length against the configured maximum and the u32 length prefix
+pub(crate) fn check_frame_len(len: usize, max_frame_size: usize) -> Result<usize, FrameError> {
+    if len > max_frame_size || len > u32::MAX as usize {
+        return Err(FrameError::Oversized {
+            len,
+            max: max_frame_size,
+        });
+    }
+    Ok(len)
+}
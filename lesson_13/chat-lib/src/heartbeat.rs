@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+/// What to do when no frame arrived for a while
+#[derive(Debug, Eq, PartialEq)]
+pub enum Beat {
+    /// keep waiting
+    Wait,
+    /// send a ping with the id, any answer keeps the connection alive
+    Ping(u64),
+    /// nothing received within the idle timeout, the peer is gone
+    Expired,
+}
+
+/// Liveness of a connection, pings an idle peer and gives up after the idle timeout
+#[derive(Debug)]
+pub struct Heartbeat {
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    last_received: Instant,
+    last_ping: Option<Instant>,
+    ping_id: u64,
+}
+
+impl Heartbeat {
+    /// initialize new instance, the peer counts as just heard from
+    pub fn new(ping_interval: Duration, idle_timeout: Duration) -> Self {
+        Heartbeat {
+            ping_interval,
+            idle_timeout,
+            last_received: Instant::now(),
+            last_ping: None,
+            ping_id: 0,
+        }
+    }
+
+    /// how long to wait for a frame before polling again
+    pub fn poll_interval(&self) -> Duration {
+        self.ping_interval.min(self.idle_timeout) / 2
+    }
+
+    /// a frame arrived from the peer
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    /// decide what to do while the peer is quiet
+    pub fn poll(&mut self) -> Beat {
+        let now = Instant::now();
+        let idle = now.duration_since(self.last_received);
+        if idle >= self.idle_timeout {
+            return Beat::Expired;
+        }
+
+        // at most one ping per interval
+        let ping_due = self
+            .last_ping
+            .is_none_or(|at| now.duration_since(at) >= self.ping_interval);
+        if idle >= self.ping_interval && ping_due {
+            self.last_ping = Some(now);
+            self.ping_id += 1;
+            return Beat::Ping(self.ping_id);
+        }
+        Beat::Wait
+    }
+}
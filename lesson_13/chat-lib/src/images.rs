@@ -0,0 +1,237 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::ChatMessageError;
+
+/// Longest side of an image thumbnail in pixels
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+/// Largest image width and height sent by default
+pub const DEFAULT_MAX_IMAGE_DIMENSION: u32 = 4096;
+
+/// Largest image width and height decoded, larger ones are refused without decoding their pixels
+pub const MAX_DECODED_IMAGE_DIMENSION: u32 = 16384;
+
+/// Most memory a decoder may allocate for a single image
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Quality of re-encoded JPEG images
+const JPEG_QUALITY: u8 = 90;
+
+/// Dimensions, format and preview of an image message
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct ImageInfo {
+    /// Width of the content in pixels
+    pub width: u32,
+    /// Height of the content in pixels
+    pub height: u32,
+    /// Format of the image content, e.g. `png` or `jpeg`
+    pub format: String,
+    /// PNG thumbnail fitting into THUMBNAIL_SIZE pixels
+    #[cfg_attr(feature = "json", serde(with = "crate::json::base64_bytes"))]
+    pub thumbnail: Vec<u8>,
+}
+
+/// How an image is checked and prepared before it is sent
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ImageOptions {
+    /// Largest width in pixels
+    pub max_width: u32,
+    /// Largest height in pixels
+    pub max_height: u32,
+    /// Scale larger images down to fit instead of refusing them
+    pub downscale: bool,
+    /// Re-encode images so EXIF and other metadata like the GPS position are not sent
+    pub strip_metadata: bool,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            max_width: DEFAULT_MAX_IMAGE_DIMENSION,
+            max_height: DEFAULT_MAX_IMAGE_DIMENSION,
+            downscale: true,
+            strip_metadata: true,
+        }
+    }
+}
+
+/// Image content ready to be sent
+pub(crate) struct PreparedImage {
+    /// Format of the bytes, PNG when the original format can not be re-encoded
+    pub format: ImageFormat,
+    pub bytes: Vec<u8>,
+    pub info: ImageInfo,
+}
+
+/// decode the whole image in the given format and prepare it according to the options
+/// the pixels are rotated upright, re-encoded images lose the EXIF orientation
+pub(crate) fn prepare_image(
+    name: &str,
+    format: ImageFormat,
+    bytes: Vec<u8>,
+    options: &ImageOptions,
+) -> Result<PreparedImage> {
+    let image = decode(name, &bytes, format, MAX_DECODED_IMAGE_DIMENSION)?;
+    let dimensions = (image.width(), image.height());
+    let mut image = orient(image, orientation(&bytes));
+
+    let too_large = image.width() > options.max_width || image.height() > options.max_height;
+    if too_large && !options.downscale {
+        return Err(ChatMessageError::ImageTooLarge(
+            name.to_owned(),
+            image.width(),
+            image.height(),
+            options.max_width,
+            options.max_height,
+        )
+        .into());
+    }
+
+    // content sent unchanged keeps its orientation, the dimensions are those of the content
+    let (format, bytes, (width, height)) = if too_large {
+        image = image.resize(options.max_width, options.max_height, FilterType::Lanczos3);
+        let (format, bytes) = encode(&image, format)?;
+        (format, bytes, (image.width(), image.height()))
+    } else if options.strip_metadata && carries_metadata(format) {
+        let (format, bytes) = encode(&image, format)?;
+        (format, bytes, (image.width(), image.height()))
+    } else {
+        (format, bytes, dimensions)
+    };
+
+    let (_, thumbnail) = encode(
+        &image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+        ImageFormat::Png,
+    )?;
+    let info = ImageInfo {
+        width,
+        height,
+        format: format_name(format).to_owned(),
+        thumbnail,
+    };
+    Ok(PreparedImage {
+        format,
+        bytes,
+        info,
+    })
+}
+
+/// format, width and height read from the header of an image, the pixels are not decoded
+pub fn image_dimensions(bytes: &[u8]) -> Option<(ImageFormat, u32, u32)> {
+    let format = image::guess_format(bytes).ok()?;
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .ok()?;
+    Some((format, width, height))
+}
+
+/// decode an image received from a peer, it has to be what its info announces
+/// images larger than max_dimension are refused before their pixels are decoded
+pub fn decode_image(
+    name: &str,
+    bytes: &[u8],
+    info: &ImageInfo,
+    max_dimension: u32,
+) -> Result<DynamicImage> {
+    let invalid = || ChatMessageError::InvalidImage(name.to_owned());
+    let (format, width, height) = image_dimensions(bytes).ok_or_else(invalid)?;
+    if ImageFormat::from_extension(&info.format) != Some(format)
+        || (width, height) != (info.width, info.height)
+    {
+        return Err(invalid().into());
+    }
+    decode(name, bytes, format, max_dimension)
+}
+
+/// decode the whole image in the given format within the decoder limits
+fn decode(
+    name: &str,
+    bytes: &[u8],
+    format: ImageFormat,
+    max_dimension: u32,
+) -> Result<DynamicImage> {
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .context(ChatMessageError::InvalidImage(name.to_owned()))?;
+    if width > max_dimension || height > max_dimension {
+        return Err(ChatMessageError::ImageTooLarge(
+            name.to_owned(),
+            width,
+            height,
+            max_dimension,
+            max_dimension,
+        )
+        .into());
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension);
+    limits.max_image_height = Some(max_dimension);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = Reader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = reader
+        .decode()
+        .context(ChatMessageError::InvalidImage(name.to_owned()))?;
+    Ok(image)
+}
+
+/// name of the format, also used as the extension of re-encoded images
+pub(crate) fn format_name(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("image")
+}
+
+/// formats able to carry EXIF, re-encoding an animated GIF would lose its frames
+fn carries_metadata(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Tiff | ImageFormat::WebP
+    )
+}
+
+/// encode in the given format, formats without an encoder become PNG
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<(ImageFormat, Vec<u8>)> {
+    let output = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(JPEG_QUALITY),
+        format => format.into(),
+    };
+    let mut bytes = Vec::new();
+    match image.write_to(&mut Cursor::new(&mut bytes), output) {
+        Ok(()) => Ok((format, bytes)),
+        Err(_) if format != ImageFormat::Png => encode(image, ImageFormat::Png),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// EXIF orientation of the image, 1 when upright or unknown
+fn orientation(bytes: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+/// rotate and mirror the pixels as the EXIF orientation tells viewers to
+fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+
+use crate::{Command, Event};
+
+impl Command {
+    /// deserialize a command from JSON
+    pub fn from_json(json: &str) -> Result<Command> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// encode command into JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+impl Event {
+    /// deserialize an event from JSON
+    pub fn from_json(json: &str) -> Result<Event> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// encode event into JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Attachment content as base64 in human readable formats like JSON
+/// binary formats like bincode keep encoding the bytes unchanged
+pub(crate) mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&STANDARD.encode(bytes)),
+            false => bytes.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match deserializer.is_human_readable() {
+            true => STANDARD
+                .decode(String::deserialize(deserializer)?)
+                .map_err(D::Error::custom),
+            false => Vec::deserialize(deserializer),
+        }
+    }
+}
@@ -3,19 +3,112 @@ use std::fs;
 use std::path::Path;
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use image::ImageFormat;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+#[cfg(feature = "tokio")]
+pub use async_frame::{AsyncFrameReader, AsyncFrameWriter};
+pub use backoff::Backoff;
+pub use codec::{Codec, CodecError, CODEC_PREFIX};
+#[cfg(feature = "e2e")]
+pub use e2e::{Identity, KeyTrust, KnownKeys};
+pub use frame::{FrameError, FrameReader, FrameWriter, DEFAULT_MAX_FRAME_SIZE};
+pub use heartbeat::{Beat, Heartbeat};
+pub use images::{
+    decode_image, image_dimensions, ImageInfo, ImageOptions, DEFAULT_MAX_IMAGE_DIMENSION,
+    MAX_DECODED_IMAGE_DIMENSION, THUMBNAIL_SIZE,
+};
+pub use protocol::{
+    Ack, AttachmentError, Command, Event, HistoryContent, HistoryEntry, Presence, RoomInfo,
+    Sanction, Target, UserInfo, LOBBY,
+};
+#[cfg(feature = "tls")]
+pub use rustls::{ClientConfig as TlsClientConfig, ServerConfig as TlsServerConfig};
+#[cfg(feature = "tls")]
+pub use tls::{
+    generate_self_signed_cert, tls_client_config_with_ca, tls_client_config_with_pinned_cert,
+    tls_server_config, Stream, TlsError, TlsStream,
+};
+pub use transfer::{FileTransfer, IncomingTransfer, IncomingTransfers, DEFAULT_CHUNK_SIZE};
+
+#[cfg(feature = "tokio")]
+mod async_frame;
+mod backoff;
+mod codec;
+#[cfg(feature = "e2e")]
+mod e2e;
+mod frame;
+mod heartbeat;
+mod images;
+#[cfg(feature = "json")]
+mod json;
+mod protocol;
+#[cfg(feature = "tls")]
+mod tls;
+mod transfer;
+
 /// Message object
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub enum Message {
     /// Text massage
     Text(String),
-    /// Image name, extension and content
-    Image(String, String, Vec<u8>),
+    /// Image name, extension, content and its dimensions, format and thumbnail
+    Image(
+        String,
+        String,
+        #[cfg_attr(feature = "json", serde(with = "json::base64_bytes"))] Vec<u8>,
+        ImageInfo,
+    ),
     /// File name and content
-    File(String, Vec<u8>),
+    File(
+        String,
+        #[cfg_attr(feature = "json", serde(with = "json::base64_bytes"))] Vec<u8>,
+    ),
+    /// Start of a chunked file transfer: transfer id, file name and total size
+    FileBegin(u64, String, u64),
+    /// Transfer id and next chunk of the file content
+    FileChunk(
+        u64,
+        #[cfg_attr(feature = "json", serde(with = "json::base64_bytes"))] Vec<u8>,
+    ),
+    /// End of a chunked file transfer with transfer id
+    FileEnd(u64),
+    /// Any other message encrypted end to end for a single user
+    Sealed(SealedMessage),
+}
+
+/// Public x25519 identity key of a user
+pub type IdentityKey = [u8; 32];
+
+/// Message encrypted for a single recipient, the server only routes the ciphertext
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct SealedMessage {
+    /// Identity key of the sender
+    pub sender_key: IdentityKey,
+    /// Identity key of the recipient
+    pub recipient_key: IdentityKey,
+    /// Random nonce used for this message only
+    pub nonce: [u8; 24],
+    /// Encrypted and authenticated message
+    #[cfg_attr(feature = "json", serde(with = "json::base64_bytes"))]
+    pub ciphertext: Vec<u8>,
+}
+
+/// Message stamped by the server before it is distributed to clients
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Envelope {
+    /// Server assigned message id
+    pub id: u64,
+    /// Sender as identified by the server
+    pub sender: String,
+    /// Room or user the message was sent to
+    pub target: Target,
+    /// UTC time the server received the message
+    pub timestamp: DateTime<Utc>,
+    /// Message payload
+    pub message: Message,
 }
 
 #[derive(Error, Debug)]
@@ -26,6 +119,14 @@ pub enum ChatMessageError {
     InvalidImageFormat(String),
     #[error("Invalid image `{0}`")]
     InvalidImage(String),
+    #[error("Image `{0}` of {1}x{2} pixels exceeds {3}x{4} pixels")]
+    ImageTooLarge(String, u32, u32, u32, u32),
+    #[error("Invalid file transfer: {0}")]
+    TransferError(String),
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+    #[error("Identity key of `{0}` changed and is not trusted")]
+    KeyChanged(String),
     #[error("Error: `{0}`")]
     OtherError(String),
 }
@@ -37,13 +138,24 @@ impl Message {
         Ok(Message::File(file.to_string(), bytes))
     }
 
+    /// open a file for a chunked transfer producing begin, chunk and end messages
+    pub fn new_file_transfer(file: &str, transfer_id: u64) -> Result<FileTransfer> {
+        FileTransfer::open(file, transfer_id, DEFAULT_CHUNK_SIZE)
+    }
+
     /// construct a new text message
     pub fn new_text_message(text: &str) -> Message {
         Message::Text(text.to_owned())
     }
 
-    /// construct a new message from image file
+    /// construct a new message from image file with the default image options
     pub fn new_image_message(file: &str) -> Result<Message> {
+        Message::new_image_message_with_options(file, &ImageOptions::default())
+    }
+
+    /// construct a new message from image file
+    /// the content has to decode as the format named by the extension
+    pub fn new_image_message_with_options(file: &str, options: &ImageOptions) -> Result<Message> {
         // read image as bytes
         let bytes = fs::read(file).context(ChatMessageError::FileReadError(file.to_owned()))?;
         let Some(name) = Path::new(file).file_stem().and_then(OsStr::to_str) else {
@@ -61,23 +173,52 @@ impl Message {
             return Err(ChatMessageError::InvalidImageFormat(ext.to_string()).into());
         };
 
-        // validate image
-        _ = image::load_from_memory_with_format(&bytes, format)
-            .context(ChatMessageError::InvalidImage(file.to_owned()));
+        // validate, downscale and strip the image
+        let image = images::prepare_image(file, format, bytes, options)?;
+        let ext = if image.format == format {
+            ext
+        } else {
+            images::format_name(image.format)
+        };
 
-        Ok(Message::Image(name.to_owned(), ext.to_owned(), bytes))
+        Ok(Message::Image(
+            name.to_owned(),
+            ext.to_owned(),
+            image.bytes,
+            image.info,
+        ))
     }
 
     /// deserialize a new message from bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Message> {
-        let message = bincode::deserialize(bytes)?;
-        Ok(message)
+        Codec::Bincode.decode(bytes)
     }
 
     /// encode message into bytes
     pub fn encode(&self) -> Result<Vec<u8>> {
-        // casting to dyn Error so we do not bind to specific implementation
-        let bytes = bincode::serialize(self)?;
-        Ok(bytes)
+        Codec::Bincode.encode(self)
+    }
+}
+
+impl Envelope {
+    /// wrap a message into a new envelope stamped with current time
+    pub fn new(id: u64, sender: &str, target: Target, message: Message) -> Envelope {
+        Envelope {
+            id,
+            sender: sender.to_owned(),
+            target,
+            timestamp: Utc::now(),
+            message,
+        }
+    }
+
+    /// deserialize a new envelope from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Envelope> {
+        Codec::Bincode.decode(bytes)
+    }
+
+    /// encode envelope into bytes
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Codec::Bincode.encode(self)
     }
 }
@@ -0,0 +1,255 @@
+use std::fmt;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Codec, Envelope, IdentityKey, Message};
+
+/// Room every client joins on connect
+pub const LOBBY: &str = "lobby";
+
+/// Recipient of a message
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum Target {
+    /// All members of the room
+    Room(String),
+    /// Single user addressed by name
+    User(String),
+}
+
+/// Command sent by a client to the server
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum Command {
+    /// Log into an existing account with username and password
+    Login(String, String),
+    /// Create a new account with username and password and log into it
+    Register(String, String),
+    /// Rename the account of a logged in client
+    Nick(String),
+    /// Message with an id chosen by the client and its recipient, acknowledged under the id
+    /// a message resent with the same id is not distributed again
+    Send(u64, Target, Message),
+    /// Join room
+    Join(String),
+    /// Leave room
+    Leave(String),
+    /// List rooms
+    Rooms,
+    /// List users online
+    Who,
+    /// Page of room history: room, messages older than the id (newest when none), page size
+    History(String, Option<u64>, usize),
+    /// Publish identity key of the logged in client for end to end encrypted messages
+    PublishKey(IdentityKey),
+    /// Request identity key of a connected user
+    GetKey(String),
+    /// Sanction a user, admins only
+    Moderate(Sanction),
+    /// Check the server is alive, answered by a pong with the same id
+    Ping(u64),
+    /// Answer to a ping of the server
+    Pong(u64),
+}
+
+/// Event sent by the server to a client
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Client logged in under the username
+    Welcome(String),
+    /// User changed name from old to new
+    Renamed(String, String),
+    /// Message distributed to a room or sent directly to a user
+    Message(Envelope),
+    /// Outcome of a message sent by the client under the id
+    Ack(u64, Ack),
+    /// Room joined by the client
+    Joined(String),
+    /// Room left by the client
+    Left(String),
+    /// Rooms existing on the server
+    Rooms(Vec<RoomInfo>),
+    /// User logged in or disconnected
+    Presence(String, Presence),
+    /// Users online ordered by name
+    Users(Vec<UserInfo>),
+    /// Page of room history ordered from the oldest message
+    History(String, Vec<HistoryEntry>),
+    /// Identity key of a user, none when not connected or not published
+    IdentityKey(String, Option<IdentityKey>),
+    /// Sanction taken by the admin
+    Sanctioned(String, Sanction),
+    /// Server is shutting down, the connection closes after this notice
+    Shutdown(String),
+    /// Check the client is alive, answered by a pong with the same id
+    Ping(u64),
+    /// Answer to a ping of the client
+    Pong(u64),
+    /// Command rejected by the server
+    Error(String),
+}
+
+/// Room description listed by the server
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct RoomInfo {
+    /// Room name
+    pub name: String,
+    /// Number of connected members
+    pub members: usize,
+    /// Whether the requesting client is a member
+    pub joined: bool,
+}
+
+/// Acknowledgement of a sent message
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum Ack {
+    /// Accepted and stamped with the message id, resending is not needed anymore
+    Accepted(u64),
+    /// Queued for the number of recipients, the sender is not counted
+    Delivered(usize),
+    /// Refused with the reason
+    Rejected(String),
+    /// Attachment refused by the server policy
+    Refused(AttachmentError),
+}
+
+/// Why the server refused a file or image
+#[derive(Error, Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum AttachmentError {
+    /// Size and the largest allowed size in bytes
+    #[error("Attachment of {0} bytes exceeds the limit of {1} bytes")]
+    TooLarge(u64, u64),
+    /// Extension or sniffed MIME type not on the allow list
+    #[error("Attachment type `{0}` is not allowed")]
+    NotAllowed(String),
+    /// Extension or sniffed MIME type on the deny list
+    #[error("Attachment type `{0}` is denied")]
+    Denied(String),
+    /// Image content not matching any image format or its announced dimensions
+    #[error("Image `{0}` is not a valid image")]
+    InvalidImage(String),
+    /// Width and height of an image and the largest allowed width and height in pixels
+    #[error("Image of {0}x{1} pixels exceeds the limit of {2}x{2} pixels")]
+    ImageTooLarge(u32, u32, u32),
+    /// Chunked transfers a client may have in progress at most
+    #[error("More than {0} file transfers in progress")]
+    TooManyTransfers(usize),
+}
+
+/// Moderation of a user by an admin
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum Sanction {
+    /// Disconnect the user with the reason
+    Kick(String, String),
+    /// Disconnect and refuse a username or IP address for good with the reason
+    Ban(String, String),
+    /// Lift the ban of a username or IP address
+    Unban(String),
+    /// Refuse messages of the user for the number of seconds, zero lifts the mute
+    Mute(String, u64),
+}
+
+/// Whether a user came online or went offline
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Presence {
+    Online,
+    Offline,
+}
+
+/// Online user listed by the server
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct UserInfo {
+    /// Username
+    pub name: String,
+    /// UTC time the user logged in
+    pub connected: DateTime<Utc>,
+    /// Seconds since the user last sent a command, heartbeats do not count
+    pub idle: u64,
+}
+
+/// Room message kept in the server history
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct HistoryEntry {
+    /// Server assigned message id
+    pub id: u64,
+    /// Sender as identified by the server
+    pub sender: String,
+    /// Room the message was sent to
+    pub room: String,
+    /// UTC time the server received the message
+    pub timestamp: DateTime<Utc>,
+    /// Message text or attachment metadata
+    pub content: HistoryContent,
+}
+
+/// Stored message content, attachments are kept as metadata only
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub enum HistoryContent {
+    /// Text message
+    Text(String),
+    /// Image name, extension and size in bytes
+    Image(String, String, u64),
+    /// File name and size in bytes
+    File(String, u64),
+}
+
+impl HistoryEntry {
+    /// history entry of a room message, none for direct messages, file chunks and ciphertext
+    pub fn from_envelope(envelope: &Envelope) -> Option<HistoryEntry> {
+        let Target::Room(room) = &envelope.target else {
+            return None;
+        };
+
+        let content = match &envelope.message {
+            Message::Text(text) => HistoryContent::Text(text.clone()),
+            Message::Image(name, ext, bytes, _) => {
+                HistoryContent::Image(name.clone(), ext.clone(), bytes.len() as u64)
+            }
+            Message::File(name, bytes) => HistoryContent::File(name.clone(), bytes.len() as u64),
+            Message::FileBegin(_, name, size) => HistoryContent::File(name.clone(), *size),
+            Message::FileChunk(..) | Message::FileEnd(_) | Message::Sealed(_) => return None,
+        };
+
+        Some(HistoryEntry {
+            id: envelope.id,
+            sender: envelope.sender.clone(),
+            room: room.clone(),
+            timestamp: envelope.timestamp,
+            content,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Room(room) => write!(f, "#{}", room),
+            Target::User(user) => write!(f, "@{}", user),
+        }
+    }
+}
+
+impl Command {
+    /// deserialize a new command from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Command> {
+        Codec::Bincode.decode(bytes)
+    }
+
+    /// encode command into bytes
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Codec::Bincode.encode(self)
+    }
+}
+
+impl Event {
+    /// deserialize a new event from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Event> {
+        Codec::Bincode.decode(bytes)
+    }
+
+    /// encode event into bytes
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Codec::Bincode.encode(self)
+    }
+}
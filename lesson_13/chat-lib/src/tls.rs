@@ -0,0 +1,353 @@
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, Connection, DigitallySignedStruct,
+    RootCertStore, ServerConfig, ServerConnection, SignatureScheme,
+};
+use thiserror::Error;
+
+/// Size of the buffer for TLS records read from the socket
+const TLS_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Unable to read `{0}`")]
+    ReadError(String),
+    #[error("No certificate found in `{0}`")]
+    NoCertificate(String),
+    #[error("No private key found in `{0}`")]
+    NoPrivateKey(String),
+    #[error("Invalid server name `{0}`")]
+    InvalidServerName(String),
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Unable to generate certificate: {0}")]
+    Certificate(#[from] rcgen::Error),
+}
+
+/// TLS connection over a TCP socket
+/// clones share the TLS session, one clone can block reading while another one writes
+pub struct TlsStream {
+    connection: Arc<Mutex<Connection>>,
+    socket: TcpStream,
+    /// TLS records read from the socket, every clone reads into its own
+    records: Box<[u8]>,
+}
+
+/// Plain or TLS protected connection
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+/// Accepts exactly one server certificate regardless of its issuer, name and validity
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+/// TLS server configuration with certificate chain and private key loaded from PEM files
+pub fn tls_server_config(cert_file: &str, key_file: &str) -> Result<Arc<ServerConfig>, TlsError> {
+    let certificates = load_certificates(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|_| TlsError::NoPrivateKey(key_file.to_owned()))?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    Ok(Arc::new(config))
+}
+
+/// TLS client configuration trusting certificates issued by the CA certificates in the PEM file
+pub fn tls_client_config_with_ca(ca_file: &str) -> Result<Arc<ClientConfig>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_file)? {
+        roots.add(certificate)?;
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// TLS client configuration trusting only the server certificate in the PEM file
+pub fn tls_client_config_with_pinned_cert(cert_file: &str) -> Result<Arc<ClientConfig>, TlsError> {
+    let Some(certificate) = load_certificates(cert_file)?.into_iter().next() else {
+        return Err(TlsError::NoCertificate(cert_file.to_owned()));
+    };
+    let verifier = PinnedCertVerifier {
+        certificate,
+        provider: provider(),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// generate a self signed certificate for development, returns certificate and key in PEM
+pub fn generate_self_signed_cert(hostnames: Vec<String>) -> Result<(String, String), TlsError> {
+    let certified = rcgen::generate_simple_self_signed(hostnames)?;
+    Ok((certified.cert.pem(), certified.key_pair.serialize_pem()))
+}
+
+/// crypto used by all configurations
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// read all certificates from a PEM file
+fn load_certificates(file: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = fs::read(file).map_err(|_| TlsError::ReadError(file.to_owned()))?;
+    let certificates = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| TlsError::NoCertificate(file.to_owned()))?;
+
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(file.to_owned()));
+    }
+    Ok(certificates)
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.certificate.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl TlsStream {
+    /// start client side of a TLS session, the handshake completes on first use
+    pub fn connect(
+        socket: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> Result<TlsStream, TlsError> {
+        let name = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_owned()))?;
+        let connection = ClientConnection::new(config, name)?;
+        Ok(TlsStream::new(connection.into(), socket))
+    }
+
+    /// start server side of a TLS session, the handshake completes on first use
+    pub fn accept(socket: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, TlsError> {
+        let connection = ServerConnection::new(config)?;
+        Ok(TlsStream::new(connection.into(), socket))
+    }
+
+    fn new(connection: Connection, socket: TcpStream) -> TlsStream {
+        TlsStream {
+            connection: Arc::new(Mutex::new(connection)),
+            socket,
+            records: vec![0u8; TLS_READ_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// finish the handshake now, reports certificate errors before any data is sent
+    pub fn handshake(&self) -> io::Result<()> {
+        let mut connection = self.connection()?;
+        let mut socket = &self.socket;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        Ok(())
+    }
+
+    /// another handle of the same TLS session
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            connection: self.connection.clone(),
+            socket: self.socket.try_clone()?,
+            records: vec![0u8; TLS_READ_BUFFER_SIZE].into_boxed_slice(),
+        })
+    }
+
+    /// shut the socket down, blocked reads and writes of all clones fail
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    /// lock the shared TLS session
+    fn connection(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| io::Error::other("Unable to lock TLS connection"))
+    }
+
+    /// send handshake messages, alerts and encrypted data waiting in the session
+    fn write_pending(connection: &mut Connection, mut socket: &TcpStream) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection()?.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // wait for more records without holding the session, writers keep going meanwhile
+            let received = match (&self.socket).read(&mut self.records) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let mut connection = self.connection()?;
+            let mut records = &self.records[..received];
+            loop {
+                // zero bytes tell the session the peer closed the socket
+                connection.read_tls(&mut records)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                if records.is_empty() {
+                    break;
+                }
+            }
+            TlsStream::write_pending(&mut connection, &self.socket)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection()?;
+        let written = connection.writer().write(buf)?;
+        TlsStream::write_pending(&mut connection, &self.socket)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.connection()?;
+        connection.writer().flush()?;
+        TlsStream::write_pending(&mut connection, &self.socket)?;
+        (&self.socket).flush()
+    }
+}
+
+impl Stream {
+    /// another handle of the same connection
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(socket) => Ok(Stream::Plain(socket.try_clone()?)),
+            Stream::Tls(stream) => Ok(Stream::Tls(stream.try_clone()?)),
+        }
+    }
+
+    /// shut the connection down, blocked reads and writes of all clones fail
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.shutdown(Shutdown::Both),
+            Stream::Tls(stream) => stream.shutdown(),
+        }
+    }
+
+    /// reads of all clones fail with WouldBlock or TimedOut after the timeout
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.socket.set_read_timeout(timeout),
+        }
+    }
+
+    /// address of the remote peer
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Plain(socket) => socket.peer_addr(),
+            Stream::Tls(stream) => stream.socket.peer_addr(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::{ChatMessageError, Message};
+
+/// Default size of a single file chunk (1 MiB)
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Outgoing chunked file transfer
+/// iterates over begin, chunk and end messages while reading the file from disk
+pub struct FileTransfer {
+    transfer_id: u64,
+    name: String,
+    size: u64,
+    file: File,
+    chunk_size: usize,
+    state: TransferState,
+}
+
+enum TransferState {
+    Begin,
+    Chunks,
+    Done,
+}
+
+/// Incoming chunked file transfer written to disk as chunks arrive
+pub struct IncomingTransfer {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    received: u64,
+    finished: bool,
+}
+
+/// Incoming chunked file transfers of all senders keyed by sender and transfer id
+/// every sender has a limited number of them, a transfer without chunks for the timeout is dropped
+pub struct IncomingTransfers {
+    transfers: HashMap<(String, u64), (IncomingTransfer, Instant)>,
+    max_per_sender: usize,
+    timeout: Duration,
+}
+
+impl FileTransfer {
+    /// open a file for a chunked transfer
+    pub fn open(file: &str, transfer_id: u64, chunk_size: usize) -> Result<FileTransfer> {
+        let Some(name) = Path::new(file).file_name().and_then(OsStr::to_str) else {
+            return Err(ChatMessageError::OtherError("Unable to get file name".to_owned()).into());
+        };
+
+        let handle = File::open(file).context(ChatMessageError::FileReadError(file.to_owned()))?;
+        let size = handle
+            .metadata()
+            .context(ChatMessageError::FileReadError(file.to_owned()))?
+            .len();
+
+        Ok(FileTransfer {
+            transfer_id,
+            name: name.to_owned(),
+            size,
+            file: handle,
+            chunk_size: chunk_size.max(1),
+            state: TransferState::Begin,
+        })
+    }
+
+    /// read next chunk, empty chunk marks the end of file
+    fn read_chunk(&mut self) -> Result<Vec<u8>> {
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        (&mut self.file)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut chunk)
+            .context(ChatMessageError::FileReadError(self.name.clone()))?;
+        Ok(chunk)
+    }
+}
+
+impl Iterator for FileTransfer {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            TransferState::Begin => {
+                self.state = TransferState::Chunks;
+                Some(Ok(Message::FileBegin(
+                    self.transfer_id,
+                    self.name.clone(),
+                    self.size,
+                )))
+            }
+            TransferState::Chunks => match self.read_chunk() {
+                Ok(chunk) if chunk.is_empty() => {
+                    self.state = TransferState::Done;
+                    Some(Ok(Message::FileEnd(self.transfer_id)))
+                }
+                Ok(chunk) => Some(Ok(Message::FileChunk(self.transfer_id, chunk))),
+                Err(e) => {
+                    self.state = TransferState::Done;
+                    Some(Err(e))
+                }
+            },
+            TransferState::Done => None,
+        }
+    }
+}
+
+impl IncomingTransfer {
+    /// create output file for a transfer announcing size bytes
+    pub fn create(path: &Path, size: u64) -> Result<IncomingTransfer> {
+        let file = File::create(path).context(ChatMessageError::TransferError(format!(
+            "unable to create {}",
+            path.display()
+        )))?;
+
+        Ok(IncomingTransfer {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            size,
+            received: 0,
+            finished: false,
+        })
+    }
+
+    /// append a chunk to the output file
+    pub fn write_chunk(&mut self, bytes: &[u8]) -> Result<()> {
+        let received = self.received + bytes.len() as u64;
+        if received > self.size {
+            return Err(ChatMessageError::TransferError(format!(
+                "received {} bytes of {} announced",
+                received, self.size
+            ))
+            .into());
+        }
+
+        self.writer.write_all(bytes)?;
+        self.received = received;
+        Ok(())
+    }
+
+    /// complete the transfer, fails if not all announced bytes arrived
+    pub fn finish(mut self) -> Result<PathBuf> {
+        if self.received != self.size {
+            return Err(ChatMessageError::TransferError(format!(
+                "received {} bytes of {} announced",
+                self.received, self.size
+            ))
+            .into());
+        }
+
+        self.writer.flush()?;
+        self.finished = true;
+        Ok(self.path.clone())
+    }
+}
+
+impl IncomingTransfers {
+    /// initialize new instance
+    pub fn new(max_per_sender: usize, timeout: Duration) -> IncomingTransfers {
+        IncomingTransfers {
+            transfers: HashMap::new(),
+            max_per_sender,
+            timeout,
+        }
+    }
+
+    /// start saving a transfer of the sender to path, fails once the sender has too many
+    pub fn begin(&mut self, sender: &str, transfer_id: u64, path: &Path, size: u64) -> Result<()> {
+        let key = (sender.to_owned(), transfer_id);
+        let active = self.transfers.keys().filter(|(s, _)| s == sender).count();
+        if !self.transfers.contains_key(&key) && active >= self.max_per_sender {
+            return Err(ChatMessageError::TransferError(format!(
+                "{} transfers from `{}` in progress already",
+                active, sender
+            ))
+            .into());
+        }
+
+        let transfer = IncomingTransfer::create(path, size)?;
+        self.transfers.insert(key, (transfer, Instant::now()));
+        Ok(())
+    }
+
+    /// append a chunk to a transfer, chunks of unknown transfers are ignored
+    /// a failed transfer is dropped together with its partial file
+    pub fn write_chunk(&mut self, sender: &str, transfer_id: u64, bytes: &[u8]) -> Result<()> {
+        let key = (sender.to_owned(), transfer_id);
+        let Some((transfer, last_chunk)) = self.transfers.get_mut(&key) else {
+            return Ok(());
+        };
+        if let Err(e) = transfer.write_chunk(bytes) {
+            self.transfers.remove(&key);
+            return Err(e);
+        }
+        *last_chunk = Instant::now();
+        Ok(())
+    }
+
+    /// complete a transfer, None for unknown transfers
+    pub fn finish(&mut self, sender: &str, transfer_id: u64) -> Option<Result<PathBuf>> {
+        let (transfer, _) = self.transfers.remove(&(sender.to_owned(), transfer_id))?;
+        Some(transfer.finish())
+    }
+
+    /// drop transfers without a chunk within the timeout, returns their senders and ids
+    pub fn expire(&mut self) -> Vec<(String, u64)> {
+        let now = Instant::now();
+        let expired = self
+            .transfers
+            .iter()
+            .filter(|(_, (_, last_chunk))| now.duration_since(*last_chunk) >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &expired {
+            self.transfers.remove(key);
+        }
+        expired
+    }
+
+    /// drop all transfers of a sender
+    pub fn remove_sender(&mut self, sender: &str) {
+        self.transfers.retain(|(s, _), _| s != sender);
+    }
+
+    /// number of transfers in progress
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// whether no transfer is in progress
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+}
+
+impl Drop for IncomingTransfer {
+    /// remove partially written file of an incomplete transfer
+    fn drop(&mut self) {
+        if !self.finished {
+            _ = fs::remove_file(&self.path);
+        }
+    }
+}
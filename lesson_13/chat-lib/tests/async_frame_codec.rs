@@ -0,0 +1,88 @@
+#![cfg(feature = "tokio")]
+
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+
+use chat_lib::{AsyncFrameReader, AsyncFrameWriter, FrameError, FrameReader, FrameWriter, Message};
+
+#[tokio::test]
+async fn async_frame_round_trip() {
+    let msg = Message::new_text_message("text message");
+
+    let mut bytes = Vec::new();
+    AsyncFrameWriter::new(&mut bytes, 1024)
+        .write_frame(&msg.encode().unwrap())
+        .await
+        .unwrap();
+
+    // async and blocking codecs share the wire format
+    let mut reader = FrameReader::new(&bytes[..], 1024);
+    assert_eq!(
+        Message::from_bytes(&reader.read_frame().unwrap()).unwrap(),
+        msg
+    );
+
+    let mut reader = AsyncFrameReader::new(&bytes[..], 1024);
+    assert_eq!(
+        Message::from_bytes(&reader.read_frame().await.unwrap()).unwrap(),
+        msg
+    );
+    assert!(matches!(reader.read_frame().await, Err(FrameError::Closed)));
+}
+
+#[tokio::test]
+async fn async_partial_and_garbage_frames() {
+    let mut bytes = Vec::new();
+    FrameWriter::new(&mut bytes, 1024)
+        .write_frame(b"payload")
+        .unwrap();
+
+    let mut reader = AsyncFrameReader::new(slow_reader(&bytes), 1024);
+    assert_eq!(reader.read_frame().await.unwrap(), b"payload");
+
+    let mut reader = AsyncFrameReader::new(&[0xFF, 0xFF, 0xFF, 0xFF][..], 1024);
+    assert!(matches!(
+        reader.read_frame().await,
+        Err(FrameError::Oversized { max: 1024, .. })
+    ));
+
+    let mut reader = AsyncFrameReader::new(&bytes[..bytes.len() - 1], 1024);
+    assert!(matches!(
+        reader.read_frame().await,
+        Err(FrameError::Truncated {
+            expected: 7,
+            received: 6
+        })
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn async_idle_and_frame_timeout() {
+    let (client, mut server) = tokio::io::duplex(64);
+    let mut reader = AsyncFrameReader::new(client, 1024)
+        .with_idle_timeout(Duration::from_secs(1))
+        .with_frame_timeout(Duration::from_secs(5));
+
+    // an idle timeout consumes nothing, the next frame is read whole
+    assert!(matches!(reader.read_frame().await, Err(FrameError::Idle)));
+    server.write_all(&[0, 0, 0, 2, b'o', b'k']).await.unwrap();
+    assert_eq!(reader.read_frame().await.unwrap(), b"ok");
+
+    // a stalled frame is not reported idle but fails after the frame timeout
+    server.write_all(&[0, 0]).await.unwrap();
+    assert!(matches!(
+        reader.read_frame().await,
+        Err(FrameError::FrameTimeout(_))
+    ));
+}
+
+/// build a reader receiving the bytes through a single byte pipe
+fn slow_reader(bytes: &[u8]) -> impl tokio::io::AsyncRead + Unpin {
+    let (client, mut server) = tokio::io::duplex(1);
+    let bytes = bytes.to_vec();
+    tokio::spawn(async move {
+        _ = server.write_all(&bytes).await;
+    });
+    client
+}
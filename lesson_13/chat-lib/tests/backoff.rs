@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use chat_lib::Backoff;
+
+#[test]
+fn delays_double_up_to_max() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+
+    // every delay is jittered between half and all of its step
+    for step in [100, 200, 400, 800, 1000, 1000] {
+        let delay = backoff.next_delay();
+        assert!(delay >= Duration::from_millis(step / 2), "{:?}", delay);
+        assert!(delay <= Duration::from_millis(step), "{:?}", delay);
+    }
+    assert_eq!(backoff.attempts(), 6);
+
+    backoff.reset();
+    assert_eq!(backoff.attempts(), 0);
+    assert!(backoff.next_delay() <= Duration::from_millis(100));
+}
+
+#[test]
+fn many_attempts_do_not_overflow() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+    for _ in 0..100 {
+        assert!(backoff.next_delay() <= Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "e2e")]
+
+use std::fs;
+
+use chat_lib::{Identity, KeyTrust, KnownKeys, Message};
+
+fn sealed(message: &Message) -> chat_lib::SealedMessage {
+    let Message::Sealed(sealed) = message else {
+        panic!("sealed message expected");
+    };
+    sealed.clone()
+}
+
+#[test]
+fn seal_and_open() {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let message = Message::new_text_message("psst");
+
+    let envelope = sealed(&alice.seal(&bob.public_key(), &message).unwrap());
+    assert_eq!(envelope.sender_key, alice.public_key());
+    assert_eq!(envelope.recipient_key, bob.public_key());
+
+    // recipient and sender can read it, nobody else
+    assert_eq!(bob.open(&envelope).unwrap(), message);
+    assert_eq!(alice.open(&envelope).unwrap(), message);
+    assert!(Identity::generate().open(&envelope).is_err());
+}
+
+#[test]
+fn tampered_message_is_rejected() {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let message = Message::File("a.dat".to_owned(), vec![1, 2, 3]);
+    let envelope = sealed(&alice.seal(&bob.public_key(), &message).unwrap());
+
+    let mut tampered = envelope.clone();
+    tampered.ciphertext[0] ^= 1;
+    assert!(bob.open(&tampered).is_err());
+
+    // sender key is authenticated too
+    let mut forged = envelope.clone();
+    forged.sender_key = Identity::generate().public_key();
+    assert!(bob.open(&forged).is_err());
+}
+
+#[test]
+fn sealed_message_is_not_sealed_again() {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let message = alice
+        .seal(&bob.public_key(), &Message::new_text_message("psst"))
+        .unwrap();
+
+    assert!(alice.seal(&bob.public_key(), &message).is_err());
+}
+
+#[test]
+fn identity_is_persisted() {
+    let path = std::env::temp_dir().join(format!("chat-identity-{}.key", std::process::id()));
+    _ = fs::remove_file(&path);
+
+    let generated = Identity::load_or_generate(&path).unwrap();
+    let loaded = Identity::load_or_generate(&path).unwrap();
+    assert_eq!(generated.public_key(), loaded.public_key());
+
+    fs::write(&path, b"too short").unwrap();
+    assert!(Identity::load_or_generate(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn keys_are_trusted_on_first_use() {
+    let path = std::env::temp_dir().join(format!("chat-known-keys-{}", std::process::id()));
+    _ = fs::remove_file(&path);
+    let alice = Identity::generate().public_key();
+    let mallory = Identity::generate().public_key();
+
+    let mut keys = KnownKeys::load(&path).unwrap();
+    assert_eq!(keys.check("alice", &alice).unwrap(), KeyTrust::New);
+    assert_eq!(keys.check("alice", &alice).unwrap(), KeyTrust::Trusted);
+    keys.verify("alice", &alice).unwrap();
+    assert!(keys.verify("bob", &alice).is_err());
+    // nothing to trust again while the key is unchanged
+    assert!(keys.trust("alice").is_err());
+
+    // a changed key is refused until trusted explicitly, also after a restart
+    assert_eq!(keys.check("alice", &mallory).unwrap(), KeyTrust::Changed);
+    assert!(keys.verify("alice", &mallory).is_err());
+    keys.verify("alice", &alice).unwrap();
+
+    let mut keys = KnownKeys::load(&path).unwrap();
+    assert_eq!(keys.check("alice", &mallory).unwrap(), KeyTrust::Changed);
+    assert_eq!(keys.trust("alice").unwrap(), mallory);
+    keys.verify("alice", &mallory).unwrap();
+    assert!(keys.verify("alice", &alice).is_err());
+
+    let keys = KnownKeys::load(&path).unwrap();
+    keys.verify("alice", &mallory).unwrap();
+
+    fs::write(&path, b"garbage").unwrap();
+    assert!(KnownKeys::load(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
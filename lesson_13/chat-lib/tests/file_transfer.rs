@@ -0,0 +1,124 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chat_lib::{FileTransfer, IncomingTransfer, IncomingTransfers, Message};
+
+fn test_file() -> PathBuf {
+    let mut test_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    test_file_path.push("resources/test/calendar.ics");
+    test_file_path
+}
+
+fn output_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("chat-lib-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn chunked_transfer() -> Result<(), Box<dyn Error>> {
+    let source = test_file();
+    let content = fs::read(&source)?;
+
+    let messages = FileTransfer::open(source.to_str().unwrap(), 7, 100)?
+        .map(|msg| Message::from_bytes(&msg?.encode()?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert_eq!(
+        messages.first(),
+        Some(&Message::FileBegin(
+            7,
+            "calendar.ics".to_owned(),
+            content.len() as u64
+        ))
+    );
+    assert_eq!(messages.last(), Some(&Message::FileEnd(7)));
+    assert_eq!(messages.len(), content.len().div_ceil(100) + 2);
+
+    let output = output_file("chunked.ics");
+    let mut incoming = IncomingTransfer::create(&output, content.len() as u64)?;
+    for msg in &messages {
+        if let Message::FileChunk(7, bytes) = msg {
+            incoming.write_chunk(bytes)?;
+        }
+    }
+    let saved = incoming.finish()?;
+
+    assert_eq!(fs::read(&saved)?, content);
+    fs::remove_file(saved)?;
+
+    Ok(())
+}
+
+#[test]
+fn incomplete_transfer() -> Result<(), Box<dyn Error>> {
+    let output = output_file("incomplete.dat");
+
+    let mut incoming = IncomingTransfer::create(&output, 4)?;
+    assert!(incoming.write_chunk(b"abcde").is_err());
+    incoming.write_chunk(b"abc")?;
+    assert!(incoming.finish().is_err());
+
+    // partial output is removed
+    assert!(!output.exists());
+
+    Ok(())
+}
+
+#[test]
+fn transfers_limited_per_sender() -> Result<(), Box<dyn Error>> {
+    let mut transfers = IncomingTransfers::new(2, Duration::from_secs(60));
+    let outputs = (0..4)
+        .map(|i| output_file(&format!("limited-{}.dat", i)))
+        .collect::<Vec<_>>();
+
+    transfers.begin("alice", 1, &outputs[0], 3)?;
+    transfers.begin("alice", 2, &outputs[1], 3)?;
+    assert!(transfers.begin("alice", 3, &outputs[2], 3).is_err());
+    assert!(!outputs[2].exists());
+
+    // other senders have their own limit
+    transfers.begin("bob", 1, &outputs[3], 3)?;
+    assert_eq!(transfers.len(), 3);
+
+    transfers.write_chunk("alice", 1, b"abc")?;
+    let saved = transfers.finish("alice", 1).unwrap()?;
+    assert_eq!(fs::read(&saved)?, b"abc");
+    fs::remove_file(saved)?;
+
+    // a finished transfer frees its slot
+    transfers.begin("alice", 3, &outputs[2], 3)?;
+
+    // leaving removes partial files of the sender
+    transfers.remove_sender("alice");
+    assert!(!outputs[1].exists());
+    assert!(!outputs[2].exists());
+    assert!(outputs[3].exists());
+    assert!(transfers.finish("alice", 2).is_none());
+
+    drop(transfers);
+    assert!(!outputs[3].exists());
+
+    Ok(())
+}
+
+#[test]
+fn stalled_transfers_expire() -> Result<(), Box<dyn Error>> {
+    let mut transfers = IncomingTransfers::new(4, Duration::from_millis(50));
+    let output = output_file("stalled.dat");
+
+    transfers.begin("alice", 1, &output, 6)?;
+    transfers.write_chunk("alice", 1, b"abc")?;
+    assert!(transfers.expire().is_empty());
+
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(transfers.expire(), vec![("alice".to_owned(), 1)]);
+    assert!(transfers.is_empty());
+    assert!(!output.exists());
+
+    // chunks arriving late are ignored
+    transfers.write_chunk("alice", 1, b"def")?;
+    assert!(transfers.finish("alice", 1).is_none());
+
+    Ok(())
+}
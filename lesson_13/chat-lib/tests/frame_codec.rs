@@ -0,0 +1,131 @@
+use std::io::{Cursor, ErrorKind, Read};
+use std::time::Duration;
+
+use chat_lib::{FrameError, FrameReader, FrameWriter, Message, DEFAULT_MAX_FRAME_SIZE};
+
+/// reader returning at most one byte per read call
+struct SlowReader<R: Read>(R);
+
+impl<R: Read> Read for SlowReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+/// reader timing out once its bytes are consumed, like a socket with a read timeout
+struct StalledReader<R: Read>(R);
+
+impl<R: Read> Read for StalledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read(buf)? {
+            0 => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+fn encode_frames(payloads: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut writer = FrameWriter::new(&mut bytes, DEFAULT_MAX_FRAME_SIZE);
+    for payload in payloads {
+        writer.write_frame(payload).unwrap();
+    }
+    bytes
+}
+
+#[test]
+fn frame_round_trip() {
+    let msg = Message::new_text_message("text message");
+    let bytes = encode_frames(&[&msg.encode().unwrap(), b""]);
+
+    let mut reader = FrameReader::new(Cursor::new(bytes), DEFAULT_MAX_FRAME_SIZE);
+    let decoded = Message::from_bytes(&reader.read_frame().unwrap()).unwrap();
+
+    assert_eq!(msg, decoded);
+    assert!(reader.read_frame().unwrap().is_empty());
+    assert!(matches!(reader.read_frame(), Err(FrameError::Closed)));
+}
+
+#[test]
+fn partial_reads() {
+    let bytes = encode_frames(&[b"first", b"second"]);
+
+    let mut reader = FrameReader::new(SlowReader(Cursor::new(bytes)), DEFAULT_MAX_FRAME_SIZE);
+
+    assert_eq!(reader.read_frame().unwrap(), b"first");
+    assert_eq!(reader.read_frame().unwrap(), b"second");
+    assert!(matches!(reader.read_frame(), Err(FrameError::Closed)));
+}
+
+#[test]
+fn garbage_prefix() {
+    // 0xFFFFFFFF announces a 4 GiB frame which must be refused before allocation
+    let bytes = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x02];
+
+    let mut reader = FrameReader::new(Cursor::new(bytes), 1024);
+
+    match reader.read_frame() {
+        Err(FrameError::Oversized { len, max }) => {
+            assert_eq!(len, u32::MAX as usize);
+            assert_eq!(max, 1024);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn truncated_frames() {
+    let mut reader = FrameReader::new(Cursor::new(vec![0x00, 0x00]), DEFAULT_MAX_FRAME_SIZE);
+    assert!(matches!(
+        reader.read_frame(),
+        Err(FrameError::Truncated {
+            expected: 4,
+            received: 2
+        })
+    ));
+
+    let mut bytes = encode_frames(&[b"payload"]);
+    bytes.truncate(bytes.len() - 3);
+    let mut reader = FrameReader::new(SlowReader(Cursor::new(bytes)), DEFAULT_MAX_FRAME_SIZE);
+    assert!(matches!(
+        reader.read_frame(),
+        Err(FrameError::Truncated {
+            expected: 7,
+            received: 4
+        })
+    ));
+}
+
+#[test]
+fn oversized_write() {
+    let mut bytes = Vec::new();
+    let mut writer = FrameWriter::new(&mut bytes, 4);
+
+    assert!(matches!(
+        writer.write_frame(b"too long"),
+        Err(FrameError::Oversized { len: 8, max: 4 })
+    ));
+    assert!(bytes.is_empty());
+}
+
+#[test]
+fn idle_and_frame_timeout() {
+    // a timeout between frames consumes nothing, reading continues with the next frame
+    let bytes = encode_frames(&[b"payload"]);
+    let mut reader = FrameReader::new(StalledReader(Cursor::new(bytes)), DEFAULT_MAX_FRAME_SIZE)
+        .with_frame_timeout(Duration::from_millis(50));
+    assert_eq!(reader.read_frame().unwrap(), b"payload");
+    assert!(matches!(reader.read_frame(), Err(FrameError::Idle)));
+    assert!(matches!(reader.read_frame(), Err(FrameError::Idle)));
+
+    // a frame stalled after its first bytes fails once the frame timeout passes
+    let mut bytes = encode_frames(&[b"payload"]);
+    bytes.truncate(2);
+    let mut reader = FrameReader::new(StalledReader(Cursor::new(bytes)), DEFAULT_MAX_FRAME_SIZE)
+        .with_frame_timeout(Duration::from_millis(50));
+    assert!(matches!(
+        reader.read_frame(),
+        Err(FrameError::FrameTimeout(_))
+    ));
+}
@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::Path;
+
+#[path = "../fuzz/src/lib.rs"]
+mod checks;
+
+/// run every input of a fuzz target corpus through its check
+fn replay(target: &str, check: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut inputs = 0;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        check(&fs::read(&path).unwrap());
+        inputs += 1;
+    }
+    assert!(inputs > 0, "empty corpus {}", dir.display());
+}
+
+#[test]
+fn frame_reader_corpus() {
+    replay("frame_reader", checks::frame_reader);
+}
+
+#[test]
+fn message_decode_corpus() {
+    replay("message_decode", checks::message_decode);
+}
+
+#[test]
+fn protocol_decode_corpus() {
+    replay("protocol_decode", checks::protocol_decode);
+}
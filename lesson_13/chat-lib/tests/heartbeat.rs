@@ -0,0 +1,31 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use chat_lib::{Beat, Heartbeat};
+
+#[test]
+fn quiet_peer_is_pinged_then_expires() {
+    let mut heartbeat = Heartbeat::new(Duration::from_millis(50), Duration::from_millis(200));
+    assert_eq!(heartbeat.poll_interval(), Duration::from_millis(25));
+    assert_eq!(heartbeat.poll(), Beat::Wait);
+
+    // a single ping per interval
+    sleep(Duration::from_millis(60));
+    assert_eq!(heartbeat.poll(), Beat::Ping(1));
+    assert_eq!(heartbeat.poll(), Beat::Wait);
+    sleep(Duration::from_millis(60));
+    assert_eq!(heartbeat.poll(), Beat::Ping(2));
+
+    sleep(Duration::from_millis(100));
+    assert_eq!(heartbeat.poll(), Beat::Expired);
+}
+
+#[test]
+fn received_frame_keeps_peer_alive() {
+    let mut heartbeat = Heartbeat::new(Duration::from_millis(200), Duration::from_millis(400));
+    for _ in 0..4 {
+        sleep(Duration::from_millis(20));
+        heartbeat.received();
+        assert_eq!(heartbeat.poll(), Beat::Wait);
+    }
+}
@@ -0,0 +1,126 @@
+use chat_lib::{
+    Codec, Command, Envelope, Event, FrameReader, FrameWriter, ImageInfo, Message, SealedMessage,
+    Target,
+};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+const MAX_FRAME_SIZE: usize = 4096;
+
+fn target() -> impl Strategy<Value = Target> {
+    prop_oneof![
+        any::<String>().prop_map(Target::Room),
+        any::<String>().prop_map(Target::User),
+    ]
+}
+
+fn image_info() -> impl Strategy<Value = ImageInfo> {
+    (
+        any::<u32>(),
+        any::<u32>(),
+        any::<String>(),
+        vec(any::<u8>(), 0..64),
+    )
+        .prop_map(|(width, height, format, thumbnail)| ImageInfo {
+            width,
+            height,
+            format,
+            thumbnail,
+        })
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    let bytes = || vec(any::<u8>(), 0..256);
+    prop_oneof![
+        any::<String>().prop_map(Message::Text),
+        (any::<String>(), any::<String>(), bytes(), image_info())
+            .prop_map(|(name, ext, bytes, info)| Message::Image(name, ext, bytes, info)),
+        (any::<String>(), bytes()).prop_map(|(name, bytes)| Message::File(name, bytes)),
+        (any::<u64>(), any::<String>(), any::<u64>())
+            .prop_map(|(id, name, size)| Message::FileBegin(id, name, size)),
+        (any::<u64>(), bytes()).prop_map(|(id, bytes)| Message::FileChunk(id, bytes)),
+        any::<u64>().prop_map(Message::FileEnd),
+        (
+            any::<[u8; 32]>(),
+            any::<[u8; 32]>(),
+            any::<[u8; 24]>(),
+            bytes()
+        )
+            .prop_map(
+                |(sender_key, recipient_key, nonce, ciphertext)| Message::Sealed(SealedMessage {
+                    sender_key,
+                    recipient_key,
+                    nonce,
+                    ciphertext,
+                })
+            ),
+    ]
+}
+
+fn codec() -> impl Strategy<Value = Codec> {
+    prop::sample::select(
+        Codec::ALL
+            .into_iter()
+            .filter(Codec::is_supported)
+            .collect::<Vec<_>>(),
+    )
+}
+
+proptest! {
+    #[test]
+    fn messages_round_trip(codec in codec(), message in message()) {
+        let bytes = codec.encode(&message).unwrap();
+        prop_assert_eq!(codec.decode::<Message>(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn sent_messages_round_trip(
+        codec in codec(),
+        id in any::<u64>(),
+        target in target(),
+        message in message(),
+    ) {
+        let command = Command::Send(id, target.clone(), message.clone());
+        let bytes = codec.encode(&command).unwrap();
+        prop_assert_eq!(codec.decode::<Command>(&bytes).unwrap(), command);
+
+        let event = Event::Message(Envelope::new(id, "alice", target, message));
+        let bytes = codec.encode(&event).unwrap();
+        prop_assert_eq!(codec.decode::<Event>(&bytes).unwrap(), event);
+    }
+
+    #[test]
+    fn frames_round_trip(payloads in vec(vec(any::<u8>(), 0..MAX_FRAME_SIZE), 0..8)) {
+        let mut stream = Vec::new();
+        let mut writer = FrameWriter::new(&mut stream, MAX_FRAME_SIZE);
+        for payload in &payloads {
+            writer.write_frame(payload).unwrap();
+        }
+
+        let mut reader = FrameReader::new(&stream[..], MAX_FRAME_SIZE);
+        for payload in &payloads {
+            prop_assert_eq!(&reader.read_frame().unwrap(), payload);
+        }
+        prop_assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn cut_frames_are_errors(message in message(), cut in any::<prop::sample::Index>()) {
+        let mut stream = Vec::new();
+        let bytes = message.encode().unwrap();
+        FrameWriter::new(&mut stream, usize::MAX).write_frame(&bytes).unwrap();
+
+        // a frame cut anywhere is an error, never a shorter frame
+        let cut = cut.index(stream.len());
+        let mut reader = FrameReader::new(&stream[..cut], usize::MAX);
+        prop_assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(codec in codec(), bytes in vec(any::<u8>(), 0..512)) {
+        _ = codec.decode::<Message>(&bytes);
+        _ = codec.decode::<Command>(&bytes);
+        _ = codec.decode::<Event>(&bytes);
+        _ = FrameReader::new(&bytes[..], MAX_FRAME_SIZE).read_frame();
+    }
+}
@@ -0,0 +1,104 @@
+use chat_lib::{
+    Codec, CodecError, Command, Envelope, Event, ImageInfo, Message, SealedMessage, Target, LOBBY,
+};
+
+fn messages() -> Vec<Message> {
+    vec![
+        Message::new_text_message("text message"),
+        Message::Image(
+            "rust".to_owned(),
+            "png".to_owned(),
+            vec![0, 1, 2, 255],
+            ImageInfo {
+                width: 2,
+                height: 1,
+                format: "png".to_owned(),
+                thumbnail: vec![137, 80],
+            },
+        ),
+        Message::File("notes.txt".to_owned(), b"notes".to_vec()),
+        Message::FileBegin(3, "movie.mp4".to_owned(), 1 << 40),
+        Message::FileChunk(3, vec![255; 64]),
+        Message::FileEnd(3),
+        Message::Sealed(SealedMessage {
+            sender_key: [1; 32],
+            recipient_key: [2; 32],
+            nonce: [3; 24],
+            ciphertext: vec![4, 5, 6],
+        }),
+    ]
+}
+
+fn supported() -> impl Iterator<Item = Codec> {
+    Codec::ALL.into_iter().filter(Codec::is_supported)
+}
+
+#[test]
+fn messages_round_trip_in_every_codec() {
+    for codec in supported() {
+        for message in messages() {
+            let bytes = codec.encode(&message).unwrap();
+            assert_eq!(
+                codec.decode::<Message>(&bytes).unwrap(),
+                message,
+                "{}",
+                codec
+            );
+
+            let command = Command::Send(1, Target::Room(LOBBY.to_owned()), message.clone());
+            let bytes = codec.encode(&command).unwrap();
+            assert_eq!(
+                codec.decode::<Command>(&bytes).unwrap(),
+                command,
+                "{}",
+                codec
+            );
+
+            let event = Event::Message(Envelope::new(
+                2,
+                "alice",
+                Target::User("bob".to_owned()),
+                message,
+            ));
+            let bytes = codec.encode(&event).unwrap();
+            assert_eq!(codec.decode::<Event>(&bytes).unwrap(), event, "{}", codec);
+        }
+    }
+}
+
+#[test]
+fn bincode_is_the_default() {
+    let command = Command::Join("rust".to_owned());
+    assert_eq!(Codec::default(), Codec::Bincode);
+    assert_eq!(
+        Codec::default().encode(&command).unwrap(),
+        command.encode().unwrap()
+    );
+    // a bincode frame is never mistaken for a codec offer
+    assert!(Codec::parse_offer(&command.encode().unwrap()).is_none());
+}
+
+#[test]
+fn codecs_are_negotiated() {
+    let offer = Codec::offer(&[Codec::Json, Codec::Bincode]);
+    assert_eq!(offer, b"CODEC json,bincode");
+    let offered = Codec::parse_offer(&offer).unwrap().unwrap();
+    assert_eq!(offered, vec![Codec::Json, Codec::Bincode]);
+
+    let picked = Codec::negotiate(&offered).unwrap();
+    assert!(picked.is_supported());
+    assert_eq!(
+        Codec::parse_answer(&picked.answer()).unwrap().unwrap(),
+        picked
+    );
+
+    assert!(matches!(
+        Codec::parse_offer(b"CODEC yaml"),
+        Some(Err(CodecError::Unknown(name))) if name == "yaml"
+    ));
+    assert!(matches!(
+        Codec::parse_answer(&offer),
+        Some(Err(CodecError::InvalidFrame))
+    ));
+    assert_eq!("msgpack".parse::<Codec>().unwrap(), Codec::MessagePack);
+}
@@ -0,0 +1,18 @@
+use chat_lib::{Envelope, Message, Target, LOBBY};
+
+#[test]
+fn envelope_serialization() {
+    let envelope = Envelope::new(
+        42,
+        "alice",
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message("text message"),
+    );
+    let encoded = envelope.encode().unwrap();
+    let decoded = Envelope::from_bytes(&encoded[..]).unwrap();
+
+    assert_eq!(decoded.id, 42);
+    assert_eq!(decoded.sender, "alice");
+    assert_eq!(decoded.target, Target::Room(LOBBY.to_owned()));
+    assert_eq!(envelope, decoded);
+}
@@ -1,7 +1,52 @@
 use std::error::Error;
 use std::path::PathBuf;
 
-use chat_lib::Message;
+use chat_lib::{
+    decode_image, image_dimensions, ChatMessageError, ImageOptions, Message, THUMBNAIL_SIZE,
+};
+use image::{ImageFormat, RgbImage};
+
+fn test_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("chat-lib-{}-{}", std::process::id(), name))
+}
+
+/// image of the given size written to a temporary file
+fn write_image(name: &str, width: u32, height: u32) -> PathBuf {
+    let path = test_file(name);
+    RgbImage::from_pixel(width, height, image::Rgb([200, 30, 30]))
+        .save(&path)
+        .unwrap();
+    path
+}
+
+/// PNG whose header announces the size, the pixels are those of a 1x1 image
+fn png_announcing(width: u32, height: u32) -> Vec<u8> {
+    let path = write_image("announcing.png", 1, 1);
+    let mut bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // IHDR follows the signature and the chunk length, its CRC covers type and data
+    bytes[16..20].copy_from_slice(&width.to_be_bytes());
+    bytes[20..24].copy_from_slice(&height.to_be_bytes());
+    let crc = crc32(&bytes[12..29]);
+    bytes[29..33].copy_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
 
 #[test]
 fn image_serialization() -> Result<(), Box<dyn Error>> {
@@ -12,7 +57,7 @@ fn image_serialization() -> Result<(), Box<dyn Error>> {
     let encoded = msg.encode().unwrap();
     let decoded = Message::from_bytes(&encoded[..]).unwrap();
 
-    assert!(matches!(msg, Message::Image(_, _, _)));
+    assert!(matches!(msg, Message::Image(_, _, _, _)));
     assert_eq!(decoded, msg);
 
     Ok(())
@@ -28,3 +73,127 @@ fn invalid_image_format() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn image_info_and_thumbnail() -> Result<(), Box<dyn Error>> {
+    let path = write_image("wide.png", 600, 300);
+    let msg = Message::new_image_message(path.to_str().unwrap())?;
+    std::fs::remove_file(&path)?;
+
+    let Message::Image(name, ext, bytes, info) = msg else {
+        panic!("not an image")
+    };
+    assert!(name.ends_with("wide"));
+    assert_eq!(ext, "png");
+    assert_eq!((info.width, info.height), (600, 300));
+    assert_eq!(info.format, "png");
+    assert_eq!(image::guess_format(&bytes)?, ImageFormat::Png);
+
+    let thumbnail = image::load_from_memory_with_format(&info.thumbnail, ImageFormat::Png)?;
+    assert_eq!(
+        (thumbnail.width(), thumbnail.height()),
+        (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2)
+    );
+    Ok(())
+}
+
+#[test]
+fn large_images_are_downscaled_or_refused() -> Result<(), Box<dyn Error>> {
+    let path = write_image("large.jpg", 400, 200);
+    let file = path.to_str().unwrap();
+    let mut options = ImageOptions {
+        max_width: 100,
+        max_height: 100,
+        ..ImageOptions::default()
+    };
+
+    let Message::Image(_, ext, bytes, info) =
+        Message::new_image_message_with_options(file, &options)?
+    else {
+        panic!("not an image")
+    };
+    assert_eq!(ext, "jpg");
+    assert_eq!((info.width, info.height), (100, 50));
+    let downscaled = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?;
+    assert_eq!((downscaled.width(), downscaled.height()), (100, 50));
+
+    options.downscale = false;
+    let error = Message::new_image_message_with_options(file, &options).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert!(matches!(
+        error.downcast_ref::<ChatMessageError>(),
+        Some(ChatMessageError::ImageTooLarge(_, 400, 200, 100, 100))
+    ));
+    Ok(())
+}
+
+#[test]
+fn corrupt_image_is_refused() -> Result<(), Box<dyn Error>> {
+    let mut test_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    test_file_path.push("resources/test/rust.jpg");
+    let mut bytes = std::fs::read(test_file_path)?;
+    bytes.truncate(bytes.len() / 2);
+    let path = test_file("corrupt.jpg");
+    std::fs::write(&path, bytes)?;
+
+    let error = Message::new_image_message(path.to_str().unwrap()).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert!(matches!(
+        error.downcast_ref::<ChatMessageError>(),
+        Some(ChatMessageError::InvalidImage(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn huge_image_is_refused_before_decoding() -> Result<(), Box<dyn Error>> {
+    let bytes = png_announcing(60_000, 60_000);
+    assert_eq!(
+        image_dimensions(&bytes),
+        Some((ImageFormat::Png, 60_000, 60_000))
+    );
+
+    let path = test_file("huge.png");
+    std::fs::write(&path, bytes)?;
+    let error = Message::new_image_message(path.to_str().unwrap()).unwrap_err();
+    std::fs::remove_file(&path)?;
+    assert!(matches!(
+        error.downcast_ref::<ChatMessageError>(),
+        Some(ChatMessageError::ImageTooLarge(_, 60_000, 60_000, _, _))
+    ));
+    Ok(())
+}
+
+#[test]
+fn received_images_are_checked_against_their_info() -> Result<(), Box<dyn Error>> {
+    let path = write_image("received.png", 60, 30);
+    let msg = Message::new_image_message(path.to_str().unwrap())?;
+    std::fs::remove_file(&path)?;
+    let Message::Image(name, _, bytes, info) = msg else {
+        panic!("not an image")
+    };
+
+    let image = decode_image(&name, &bytes, &info, 100)?;
+    assert_eq!((image.width(), image.height()), (60, 30));
+
+    // larger than the receiver accepts
+    let error = decode_image(&name, &bytes, &info, 50).unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<ChatMessageError>(),
+        Some(ChatMessageError::ImageTooLarge(_, 60, 30, 50, 50))
+    ));
+
+    // header not matching the announced dimensions or format
+    let mut lying = info.clone();
+    lying.width = 30;
+    assert!(decode_image(&name, &bytes, &lying, 100).is_err());
+    let mut lying = info.clone();
+    lying.format = "jpg".to_owned();
+    assert!(decode_image(&name, &bytes, &lying, 100).is_err());
+
+    let mut info = info;
+    let huge = png_announcing(60_000, 60_000);
+    (info.width, info.height) = (60_000, 60_000);
+    assert!(decode_image(&name, &huge, &info, 100).is_err());
+    Ok(())
+}
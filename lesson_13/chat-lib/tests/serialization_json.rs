@@ -0,0 +1,72 @@
+#![cfg(feature = "json")]
+
+use chat_lib::{Ack, Command, Envelope, Event, ImageInfo, Message, Target, LOBBY};
+
+#[test]
+fn command_json() {
+    let command = Command::Send(
+        1,
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message("hi"),
+    );
+    let json = command.to_json().unwrap();
+    assert_eq!(json, r#"{"Send":[1,{"Room":"lobby"},{"Text":"hi"}]}"#);
+    assert_eq!(Command::from_json(&json).unwrap(), command);
+
+    assert_eq!(Command::from_json(r#""Who""#).unwrap(), Command::Who);
+    assert!(Command::from_json(r#"{"Unknown":1}"#).is_err());
+}
+
+#[test]
+fn attachments_are_base64_in_json_only() {
+    let info = ImageInfo {
+        width: 2,
+        height: 1,
+        format: "png".to_owned(),
+        thumbnail: vec![137, 80],
+    };
+    let image = Message::Image(
+        "rust".to_owned(),
+        "png".to_owned(),
+        vec![0, 1, 2, 255],
+        info,
+    );
+    let event = Event::Message(Envelope::new(
+        7,
+        "alice",
+        Target::User("bob".to_owned()),
+        image.clone(),
+    ));
+    let json = event.to_json().unwrap();
+    assert!(
+        json.contains(
+            r#"{"Image":["rust","png","AAEC/w==",{"width":2,"height":1,"format":"png","thumbnail":"iVA="}]}"#
+        ),
+        "{}",
+        json
+    );
+    assert_eq!(Event::from_json(&json).unwrap(), event);
+
+    // bincode encoding of the bytes is unchanged
+    let bincode = Command::Send(1, Target::User("bob".to_owned()), image)
+        .encode()
+        .unwrap();
+    assert!(bincode.ends_with(&[2, 0, 0, 0, 0, 0, 0, 0, 137, 80]));
+    assert!(bincode
+        .windows(12)
+        .any(|bytes| bytes == [4, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 255]));
+
+    let chunk = Command::from_json(r#"{"Send":[2,{"User":"bob"},{"FileChunk":[3,"aGk="]}]}"#);
+    assert_eq!(
+        chunk.unwrap(),
+        Command::Send(
+            2,
+            Target::User("bob".to_owned()),
+            Message::FileChunk(3, b"hi".to_vec())
+        )
+    );
+    assert!(Command::from_json(r#"{"Send":[2,{"User":"bob"},{"File":["a","%%"]}]}"#).is_err());
+
+    let ack = Event::Ack(1, Ack::Delivered(2)).to_json().unwrap();
+    assert_eq!(ack, r#"{"Ack":[1,{"Delivered":2}]}"#);
+}
@@ -0,0 +1,159 @@
+use chat_lib::{
+    Ack, AttachmentError, Command, Envelope, Event, HistoryContent, HistoryEntry, ImageInfo,
+    Message, Presence, RoomInfo, Sanction, SealedMessage, Target, UserInfo, LOBBY,
+};
+
+#[test]
+fn command_serialization() {
+    let commands = vec![
+        Command::Login("alice".to_owned(), "secret password".to_owned()),
+        Command::Register("alice".to_owned(), "secret password".to_owned()),
+        Command::Nick("alice2".to_owned()),
+        Command::Send(
+            1,
+            Target::Room(LOBBY.to_owned()),
+            Message::new_text_message("text message"),
+        ),
+        Command::Send(
+            2,
+            Target::User("bob".to_owned()),
+            Message::new_text_message("text message"),
+        ),
+        Command::Join("rust".to_owned()),
+        Command::Leave("rust".to_owned()),
+        Command::Rooms,
+        Command::Who,
+        Command::History(LOBBY.to_owned(), None, 20),
+        Command::History(LOBBY.to_owned(), Some(42), 20),
+        Command::PublishKey([7; 32]),
+        Command::GetKey("bob".to_owned()),
+        Command::Moderate(Sanction::Kick("bob".to_owned(), "spam".to_owned())),
+        Command::Moderate(Sanction::Ban("10.0.0.1".to_owned(), "spam".to_owned())),
+        Command::Moderate(Sanction::Unban("bob".to_owned())),
+        Command::Moderate(Sanction::Mute("bob".to_owned(), 60)),
+        Command::Ping(1),
+        Command::Pong(2),
+        Command::Send(
+            3,
+            Target::User("bob".to_owned()),
+            Message::Sealed(SealedMessage {
+                sender_key: [1; 32],
+                recipient_key: [2; 32],
+                nonce: [3; 24],
+                ciphertext: vec![4; 64],
+            }),
+        ),
+    ];
+
+    for command in commands {
+        let encoded = command.encode().unwrap();
+        assert_eq!(Command::from_bytes(&encoded[..]).unwrap(), command);
+    }
+}
+
+#[test]
+fn event_serialization() {
+    let events = vec![
+        Event::Welcome("alice".to_owned()),
+        Event::Renamed("alice".to_owned(), "alice2".to_owned()),
+        Event::Message(Envelope::new(
+            1,
+            "alice",
+            Target::User("bob".to_owned()),
+            Message::new_text_message("text message"),
+        )),
+        Event::Joined("rust".to_owned()),
+        Event::Left("rust".to_owned()),
+        Event::Rooms(vec![RoomInfo {
+            name: LOBBY.to_owned(),
+            members: 2,
+            joined: true,
+        }]),
+        Event::Ack(1, Ack::Accepted(42)),
+        Event::Ack(1, Ack::Delivered(2)),
+        Event::Ack(2, Ack::Rejected("User `bob` is not connected".to_owned())),
+        Event::Ack(3, Ack::Refused(AttachmentError::TooLarge(2048, 1024))),
+        Event::Ack(3, Ack::Refused(AttachmentError::Denied("exe".to_owned()))),
+        Event::Presence("bob".to_owned(), Presence::Online),
+        Event::Presence("bob".to_owned(), Presence::Offline),
+        Event::Users(vec![UserInfo {
+            name: "alice".to_owned(),
+            connected: chrono::Utc::now(),
+            idle: 42,
+        }]),
+        Event::History(
+            LOBBY.to_owned(),
+            vec![HistoryEntry {
+                id: 1,
+                sender: "alice".to_owned(),
+                room: LOBBY.to_owned(),
+                timestamp: chrono::Utc::now(),
+                content: HistoryContent::File("a.dat".to_owned(), 1024),
+            }],
+        ),
+        Event::IdentityKey("bob".to_owned(), Some([7; 32])),
+        Event::IdentityKey("carol".to_owned(), None),
+        Event::Sanctioned(
+            "alice".to_owned(),
+            Sanction::Kick("bob".to_owned(), "spam".to_owned()),
+        ),
+        Event::Shutdown("Server is shutting down".to_owned()),
+        Event::Ping(1),
+        Event::Pong(2),
+        Event::Error("error".to_owned()),
+    ];
+
+    for event in events {
+        let encoded = event.encode().unwrap();
+        assert_eq!(Event::from_bytes(&encoded[..]).unwrap(), event);
+    }
+}
+
+#[test]
+fn history_entry_from_envelope() {
+    let room = |message| Envelope::new(7, "alice", Target::Room(LOBBY.to_owned()), message);
+
+    let entry = HistoryEntry::from_envelope(&room(Message::new_text_message("hi"))).unwrap();
+    assert_eq!(entry.id, 7);
+    assert_eq!(entry.sender, "alice");
+    assert_eq!(entry.room, LOBBY);
+    assert_eq!(entry.content, HistoryContent::Text("hi".to_owned()));
+
+    // attachments are kept as metadata
+    let info = ImageInfo {
+        width: 1,
+        height: 1,
+        format: "png".to_owned(),
+        thumbnail: vec![0; 5],
+    };
+    let entry = HistoryEntry::from_envelope(&room(Message::Image(
+        "cat".to_owned(),
+        "png".to_owned(),
+        vec![0; 10],
+        info,
+    )))
+    .unwrap();
+    assert_eq!(
+        entry.content,
+        HistoryContent::Image("cat".to_owned(), "png".to_owned(), 10)
+    );
+    let entry = HistoryEntry::from_envelope(&room(Message::FileBegin(1, "a.dat".to_owned(), 2048)))
+        .unwrap();
+    assert_eq!(
+        entry.content,
+        HistoryContent::File("a.dat".to_owned(), 2048)
+    );
+
+    // chunks and direct messages are not kept
+    assert_eq!(
+        HistoryEntry::from_envelope(&room(Message::FileChunk(1, vec![0; 10]))),
+        None
+    );
+    let dm = Envelope::new(
+        8,
+        "alice",
+        Target::User("bob".to_owned()),
+        Message::new_text_message("psst"),
+    );
+    assert_eq!(HistoryEntry::from_envelope(&dm), None);
+}
@@ -0,0 +1,122 @@
+#![cfg(feature = "tls")]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+
+use chat_lib::{
+    generate_self_signed_cert, tls_client_config_with_ca, tls_client_config_with_pinned_cert,
+    tls_server_config, FrameReader, FrameWriter, Stream, TlsClientConfig, TlsStream,
+    DEFAULT_MAX_FRAME_SIZE,
+};
+
+/// certificate and key files of a freshly generated localhost certificate
+struct DevCert {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl DevCert {
+    fn generate(name: &str) -> DevCert {
+        let (cert_pem, key_pem) = generate_self_signed_cert(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("{}-{}-cert.pem", name, std::process::id()));
+        let key = dir.join(format!("{}-{}-key.pem", name, std::process::id()));
+        fs::write(&cert, cert_pem).unwrap();
+        fs::write(&key, key_pem).unwrap();
+        DevCert { cert, key }
+    }
+
+    fn cert(&self) -> &str {
+        self.cert.to_str().unwrap()
+    }
+
+    fn key(&self) -> &str {
+        self.key.to_str().unwrap()
+    }
+}
+
+impl Drop for DevCert {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.cert);
+        _ = fs::remove_file(&self.key);
+    }
+}
+
+/// echo server answering every frame from a cloned writer, returns its port
+fn echo_server(cert: &DevCert) -> u16 {
+    let config = tls_server_config(cert.cert(), cert.key()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let stream = Stream::Tls(TlsStream::accept(socket.unwrap(), config.clone()).unwrap());
+            let mut writer = FrameWriter::new(stream.try_clone().unwrap(), DEFAULT_MAX_FRAME_SIZE);
+            let mut reader = FrameReader::new(stream, DEFAULT_MAX_FRAME_SIZE);
+            thread::spawn(move || {
+                while let Ok(frame) = reader.read_frame() {
+                    writer.write_frame(&frame).unwrap();
+                }
+            });
+        }
+    });
+    port
+}
+
+fn connect(port: u16, config: std::sync::Arc<TlsClientConfig>) -> std::io::Result<Stream> {
+    let socket = TcpStream::connect(("127.0.0.1", port))?;
+    let stream = TlsStream::connect(socket, config, "localhost").unwrap();
+    stream.handshake()?;
+    Ok(Stream::Tls(stream))
+}
+
+fn round_trip(stream: Stream) {
+    let mut writer = FrameWriter::new(stream.try_clone().unwrap(), DEFAULT_MAX_FRAME_SIZE);
+    let mut reader = FrameReader::new(stream, DEFAULT_MAX_FRAME_SIZE);
+
+    // frames larger than a single TLS record
+    for len in [0, 5, 100_000] {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        writer.write_frame(&payload).unwrap();
+        assert_eq!(reader.read_frame().unwrap(), payload);
+    }
+}
+
+#[test]
+fn tls_with_ca() {
+    let cert = DevCert::generate("tls-ca");
+    let port = echo_server(&cert);
+
+    let config = tls_client_config_with_ca(cert.cert()).unwrap();
+    round_trip(connect(port, config).unwrap());
+}
+
+#[test]
+fn tls_with_pinned_cert() {
+    let cert = DevCert::generate("tls-pinned");
+    let port = echo_server(&cert);
+
+    let config = tls_client_config_with_pinned_cert(cert.cert()).unwrap();
+    round_trip(connect(port, config).unwrap());
+}
+
+#[test]
+fn tls_rejects_unknown_cert() {
+    let cert = DevCert::generate("tls-server");
+    let other = DevCert::generate("tls-other");
+    let port = echo_server(&cert);
+
+    let config = tls_client_config_with_ca(other.cert()).unwrap();
+    assert!(connect(port, config).is_err());
+
+    let config = tls_client_config_with_pinned_cert(other.cert()).unwrap();
+    assert!(connect(port, config).is_err());
+}
+
+#[test]
+fn tls_missing_files() {
+    assert!(tls_server_config("no-such-cert.pem", "no-such-key.pem").is_err());
+    assert!(tls_client_config_with_ca("no-such-ca.pem").is_err());
+}
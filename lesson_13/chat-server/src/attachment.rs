@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chatlib::{image_dimensions, Ack, AttachmentError, Command, Event, ImageInfo, Message};
+use image::ImageFormat;
+
+use crate::Delivery;
+
+/// Magic bytes of file types worth recognizing besides images
+const SIGNATURES: [(&[u8], &str); 8] = [
+    (b"%PDF", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/x-msdownload"),
+    (b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+    (b"#!", "text/x-shellscript"),
+];
+
+/// Files and images accepted from clients
+/// types are lowercase extensions, MIME types or MIME type families like `image/*`
+#[derive(Debug, Clone)]
+pub struct AttachmentPolicy {
+    pub max_image_size: u64,
+    /// largest image width and height in pixels
+    pub max_image_dimension: u32,
+    pub max_file_size: u64,
+    /// chunked transfers a connection may have in progress, refused ones included
+    pub max_transfers: usize,
+    /// how long a transfer without a chunk is remembered
+    pub transfer_timeout: Duration,
+    /// anything not denied is allowed when empty
+    pub allowed_types: Vec<String>,
+    pub denied_types: Vec<String>,
+}
+
+/// What happens to a command carrying an attachment
+#[derive(Debug, Eq, PartialEq)]
+pub enum Screened {
+    Pass,
+    /// refused with a reply to the sender
+    Refused(AttachmentError),
+    /// part of a transfer refused before, dropped silently
+    Dropped,
+}
+
+/// Chunked transfer announced by a client
+#[derive(Debug)]
+struct Transfer {
+    name: String,
+    size: u64,
+    received: u64,
+    state: TransferState,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum TransferState {
+    Open,
+    /// refused on begin, recipients never heard of it
+    RefusedBegin,
+    /// refused on a chunk, recipients discard the incomplete file on end
+    RefusedChunk,
+}
+
+/// Attachment policy applied to the messages of a single connection
+/// chunked transfers are followed to check their content and real size
+/// their number is limited and stalled ones are forgotten, parts of unknown ones are dropped
+#[derive(Debug)]
+pub struct AttachmentFilter {
+    policy: AttachmentPolicy,
+    transfers: HashMap<u64, Transfer>,
+}
+
+/// MIME type recognized from the content
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if let Ok(format) = image::guess_format(bytes) {
+        return Some(format.to_mime_type());
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|(_, mime)| *mime)
+}
+
+/// lowercase extension of a file name
+fn extension(name: &str) -> Option<String> {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+}
+
+/// whether a policy entry names the extension or the MIME type
+fn matches(entry: &str, ext: Option<&str>, mime: Option<&str>) -> bool {
+    let entry = entry.trim().to_lowercase();
+    if entry.is_empty() {
+        return false;
+    }
+    if ext == Some(entry.as_str()) || mime == Some(entry.as_str()) {
+        return true;
+    }
+    match (entry.strip_suffix("/*"), mime) {
+        (Some(family), Some(mime)) => mime.split('/').next() == Some(family),
+        _ => false,
+    }
+}
+
+impl AttachmentPolicy {
+    /// check type of an attachment by its extension and sniffed MIME type
+    fn check_type(&self, ext: Option<&str>, mime: Option<&str>) -> Result<(), AttachmentError> {
+        let name = || mime.or(ext).unwrap_or("unknown").to_owned();
+        if self
+            .denied_types
+            .iter()
+            .any(|entry| matches(entry, ext, mime))
+        {
+            return Err(AttachmentError::Denied(name()));
+        }
+        if !self.allowed_types.is_empty()
+            && !self
+                .allowed_types
+                .iter()
+                .any(|entry| matches(entry, ext, mime))
+        {
+            return Err(AttachmentError::NotAllowed(name()));
+        }
+        Ok(())
+    }
+
+    fn check_size(size: u64, max: u64) -> Result<(), AttachmentError> {
+        match size > max {
+            true => Err(AttachmentError::TooLarge(size, max)),
+            false => Ok(()),
+        }
+    }
+
+    /// check a whole image, its thumbnail is shown by clients too and has to be a PNG
+    /// the dimensions in its header have to be those announced, the pixels are not decoded
+    fn check_image(
+        &self,
+        name: &str,
+        ext: &str,
+        bytes: &[u8],
+        info: &ImageInfo,
+    ) -> Result<(), AttachmentError> {
+        let size = bytes.len() + info.thumbnail.len();
+        Self::check_size(size as u64, self.max_image_size)?;
+        let invalid = || AttachmentError::InvalidImage(format!("{}.{}", name, ext));
+        if !matches!(image::guess_format(&info.thumbnail), Ok(ImageFormat::Png)) {
+            return Err(invalid());
+        }
+        let Some((format, width, height)) = image_dimensions(bytes) else {
+            return Err(invalid());
+        };
+        if (width, height) != (info.width, info.height) {
+            return Err(invalid());
+        }
+        let max = self.max_image_dimension;
+        if width > max || height > max {
+            return Err(AttachmentError::ImageTooLarge(width, height, max));
+        }
+        self.check_type(Some(&ext.to_lowercase()), Some(format.to_mime_type()))
+    }
+
+    /// check a whole file
+    fn check_file(&self, name: &str, bytes: &[u8]) -> Result<(), AttachmentError> {
+        Self::check_size(bytes.len() as u64, self.max_file_size)?;
+        self.check_type(extension(name).as_deref(), sniff(bytes))
+    }
+}
+
+impl AttachmentFilter {
+    /// initialize new instance without transfers
+    pub fn new(policy: AttachmentPolicy) -> Self {
+        AttachmentFilter {
+            policy,
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// check a command, only files and images are screened
+    /// end to end encrypted messages are opaque to the server and pass
+    pub fn screen(&mut self, command: &Command) -> Screened {
+        let Command::Send(_, _, message) = command else {
+            return Screened::Pass;
+        };
+        let checked = match message {
+            Message::Image(name, ext, bytes, info) => {
+                self.policy.check_image(name, ext, bytes, info)
+            }
+            Message::File(name, bytes) => self.policy.check_file(name, bytes),
+            Message::FileBegin(transfer_id, name, size) => {
+                return self.begin(*transfer_id, name, *size)
+            }
+            Message::FileChunk(transfer_id, bytes) => return self.chunk(*transfer_id, bytes),
+            Message::FileEnd(transfer_id) => return self.end(*transfer_id),
+            Message::Text(_) | Message::Sealed(_) => Ok(()),
+        };
+        match checked {
+            Ok(()) => Screened::Pass,
+            Err(e) => Screened::Refused(e),
+        }
+    }
+
+    /// the announced size and the extension are known before any content
+    fn begin(&mut self, transfer_id: u64, name: &str, size: u64) -> Screened {
+        let now = Instant::now();
+        let timeout = self.policy.transfer_timeout;
+        self.transfers
+            .retain(|_, transfer| now.saturating_duration_since(transfer.last_seen) < timeout);
+
+        let max = self.policy.max_transfers;
+        if !self.transfers.contains_key(&transfer_id) && self.transfers.len() >= max {
+            return Screened::Refused(AttachmentError::TooManyTransfers(max));
+        }
+
+        let checked = AttachmentPolicy::check_size(size, self.policy.max_file_size)
+            .and_then(|_| self.policy.check_type(extension(name).as_deref(), None));
+        let state = match checked {
+            Ok(()) => TransferState::Open,
+            Err(_) => TransferState::RefusedBegin,
+        };
+        self.transfers.insert(
+            transfer_id,
+            Transfer {
+                name: name.to_owned(),
+                size,
+                received: 0,
+                state,
+                last_seen: now,
+            },
+        );
+        match checked {
+            Ok(()) => Screened::Pass,
+            Err(e) => Screened::Refused(e),
+        }
+    }
+
+    /// content is sniffed from the first chunk, chunks beyond the announced size are refused
+    fn chunk(&mut self, transfer_id: u64, bytes: &[u8]) -> Screened {
+        let Some(transfer) = self.transfers.get_mut(&transfer_id) else {
+            return Screened::Dropped;
+        };
+        transfer.last_seen = Instant::now();
+        if transfer.state != TransferState::Open {
+            return Screened::Dropped;
+        }
+
+        let first = transfer.received == 0;
+        transfer.received += bytes.len() as u64;
+        let checked = if transfer.received > transfer.size {
+            Err(AttachmentError::TooLarge(transfer.received, transfer.size))
+        } else if first {
+            self.policy
+                .check_type(extension(&transfer.name).as_deref(), sniff(bytes))
+        } else {
+            Ok(())
+        };
+
+        match checked {
+            Ok(()) => Screened::Pass,
+            Err(e) => {
+                transfer.state = TransferState::RefusedChunk;
+                Screened::Refused(e)
+            }
+        }
+    }
+
+    fn end(&mut self, transfer_id: u64) -> Screened {
+        match self.transfers.remove(&transfer_id) {
+            Some(transfer) if transfer.state == TransferState::RefusedBegin => Screened::Dropped,
+            Some(_) => Screened::Pass,
+            None => Screened::Dropped,
+        }
+    }
+}
+
+impl Screened {
+    /// refusal of the message under its id
+    pub fn reply(&self, client: SocketAddr, command: &Command) -> Option<Delivery> {
+        let (Screened::Refused(e), Command::Send(id, ..)) = (self, command) else {
+            return None;
+        };
+        Some(Delivery::reply(
+            client,
+            Event::Ack(*id, Ack::Refused(e.clone())),
+        ))
+    }
+}
@@ -0,0 +1,59 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use chat_server::AppError;
+use chatlib::generate_self_signed_cert;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// Writes a self signed certificate for local TLS testing,
+/// the server uses it as TLS_CERT and TLS_KEY, clients trust it with TLS_CA or TLS_PINNED_CERT
+#[derive(Deserialize, Debug)]
+struct DevCertConfig {
+    #[serde(default = "dev_cert_config_default_hostname")]
+    hostname: String,
+    #[serde(default = "dev_cert_config_default_tls_cert")]
+    tls_cert: String,
+    #[serde(default = "dev_cert_config_default_tls_key")]
+    tls_key: String,
+}
+
+fn dev_cert_config_default_hostname() -> String {
+    "localhost".to_owned()
+}
+
+fn dev_cert_config_default_tls_cert() -> String {
+    "cert.pem".to_owned()
+}
+
+fn dev_cert_config_default_tls_key() -> String {
+    "key.pem".to_owned()
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let config = envy::from_env::<DevCertConfig>().context(AppError::ConfigError())?;
+
+    let mut hostnames = vec![config.hostname.clone()];
+    if config.hostname != "localhost" {
+        hostnames.push("localhost".to_owned());
+    }
+
+    let (cert, key) = generate_self_signed_cert(hostnames.clone())?;
+    fs::write(&config.tls_cert, cert).context(AppError::OtherError(format!(
+        "Unable to write {}",
+        config.tls_cert
+    )))?;
+    fs::write(&config.tls_key, key).context(AppError::OtherError(format!(
+        "Unable to write {}",
+        config.tls_key
+    )))?;
+
+    info!(
+        ?hostnames,
+        config.tls_cert, config.tls_key, "Certificate written"
+    );
+    warn!("Self signed certificates are meant for development only");
+    Ok(())
+}
@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chat_server::{
+    accept_websocket, log_command, log_disconnect, log_limit, log_screened, negotiate_codec,
+    queue_deliveries, AppError, AttachmentFilter, Delivery, EventFrames, History, Limit, Metrics,
+    OutboundQueue, RateLimiter, Screened, ServerConfig, State, SHUTDOWN_NOTICE,
+};
+use chatlib::{
+    AsyncFrameReader, AsyncFrameWriter, Beat, Codec, Command, Event, FrameError, Heartbeat,
+};
+use futures_util::stream::SplitStream;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info, warn};
+
+/// How often shutdown checks whether all clients were flushed
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tokio based server speaking the same protocol as the threaded chat-server
+struct Server {
+    config: ServerConfig,
+    /// rooms, message routing and user accounts
+    state: State,
+    /// outgoing frame queue of every connected client
+    clients: Mutex<HashMap<SocketAddr, Arc<OutboundQueue>>>,
+    /// connections are plain TCP without TLS configuration
+    tls: Option<TlsAcceptor>,
+    /// new clients are refused once set
+    shutting_down: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    Arc::new(Server::new()?).run().await
+}
+
+impl Server {
+    fn new() -> Result<Self> {
+        let config = envy::from_env::<ServerConfig>().context(AppError::ConfigError())?;
+        let users = config.user_store()?;
+        let history = History::open(&config.database)
+            .context(AppError::DatabaseError(config.database.clone()))?;
+        Ok(Server {
+            state: State::new(users, history, config.history_size)?
+                .with_admins(config.admins.clone()),
+            tls: config.tls()?.map(TlsAcceptor::from),
+            config,
+            clients: Mutex::new(HashMap::new()),
+            shutting_down: AtomicBool::new(false),
+            metrics: Arc::new(Metrics::new()?),
+        })
+    }
+
+    /// handle client commands and distribute resulting events
+    /// every message is stamped with an id, the sender and the time of arrival
+    async fn handle_client<S>(&self, client_socket: SocketAddr, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+
+        // writer task ends once the client is deregistered and its queue closed
+        let queue = Arc::new(self.config.outbound_queue(client_socket));
+        let mut writer = AsyncFrameWriter::new(write_half, self.config.max_frame_size);
+        let frames = queue.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = frames.pop().await {
+                // a write stuck on a slow client is abandoned once its queue overflows
+                tokio::select! {
+                    written = writer.write_frame(&frame) => if let Err(e) = written {
+                        error!("{}", e);
+                        break;
+                    },
+                    _ = frames.overflowed() => break,
+                }
+            }
+        });
+
+        let commands = self.read_commands(client_socket, read_half, queue.clone());
+        self.serve(client_socket, queue, writer_task, commands)
+            .await
+    }
+
+    /// handle a browser client speaking JSON over a WebSocket
+    /// events are queued encoded in JSON, the writer sends them as text messages
+    async fn handle_json_client<S>(
+        &self,
+        client_socket: SocketAddr,
+        websocket: WebSocketStream<S>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut sink, frames) = websocket.split();
+
+        let queue = Arc::new(
+            self.config
+                .outbound_queue(client_socket)
+                .with_codec(Codec::Json),
+        );
+        let events = queue.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = events.pop().await {
+                let json = match String::from_utf8(frame.to_vec()) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+                tokio::select! {
+                    written = sink.send(WsMessage::Text(json)) => if let Err(e) = written {
+                        error!("{}", e);
+                        break;
+                    },
+                    _ = events.overflowed() => break,
+                }
+            }
+            _ = sink.close().await;
+        });
+
+        self.serve(
+            client_socket,
+            queue,
+            writer_task,
+            self.read_json_commands(client_socket, frames),
+        )
+        .await
+    }
+
+    /// register the client and read its commands until the reader or the writer ends
+    async fn serve(
+        &self,
+        client_socket: SocketAddr,
+        queue: Arc<OutboundQueue>,
+        mut writer_task: JoinHandle<()>,
+        read: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        if let Err(e) = self.register(client_socket, queue.clone()) {
+            queue.close();
+            return Err(e);
+        }
+
+        tokio::select! {
+            read = read => read,
+            _ = &mut writer_task => match queue.is_closed() {
+                // kicked, or shutting down
+                true => Ok(()),
+                false => Err(AppError::OtherError(format!(
+                    "Unable to write to {}",
+                    client_socket
+                ))
+                .into()),
+            },
+        }
+    }
+
+    /// read commands until the client disconnects or is evicted
+    async fn read_commands<S>(
+        &self,
+        client_socket: SocketAddr,
+        read_half: ReadHalf<S>,
+        queue: Arc<OutboundQueue>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        // commands are logged with the peer address, the hub stamps messages with the username
+        let sender = client_socket.to_string();
+
+        // reads wake up regularly to ping a quiet client and to evict a gone one
+        let mut heartbeat = self.config.heartbeat();
+        let mut reader = AsyncFrameReader::new(read_half, self.config.max_frame_size)
+            .with_idle_timeout(self.config.read_timeout(&heartbeat))
+            .with_frame_timeout(self.config.frame_timeout());
+        let mut limiter = self.config.rate_limiter();
+        let mut attachments = self.config.attachment_filter();
+        let mut codec = Codec::Bincode;
+        let mut negotiable = true;
+
+        loop {
+            let frame = match reader.read_frame().await {
+                Ok(frame) => frame,
+                Err(FrameError::Idle) => {
+                    self.beat(client_socket, &mut heartbeat)?;
+                    continue;
+                }
+                Err(e) => {
+                    self.metrics.frame_error(&e);
+                    return Err(e.into());
+                }
+            };
+            heartbeat.received();
+
+            // a client may pick another codec with its first frame
+            if std::mem::take(&mut negotiable) {
+                if let Some(picked) = negotiate_codec(&frame, &queue)? {
+                    info!(client = sender, codec = picked.name(), "Codec negotiated");
+                    codec = picked;
+                    continue;
+                }
+            }
+            let command = codec
+                .decode::<Command>(&frame)
+                .inspect_err(|_| self.metrics.decode_error())?;
+            self.process(
+                client_socket,
+                &sender,
+                &mut limiter,
+                &mut attachments,
+                command,
+                frame.len(),
+            )
+            .await?;
+        }
+    }
+
+    /// read JSON commands of a browser client until it disconnects or is evicted
+    /// pings and pongs of the WebSocket protocol are answered by tungstenite
+    async fn read_json_commands<S>(
+        &self,
+        client_socket: SocketAddr,
+        mut frames: SplitStream<WebSocketStream<S>>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let sender = client_socket.to_string();
+
+        let mut heartbeat = self.config.heartbeat();
+        let read_timeout = self.config.read_timeout(&heartbeat);
+        let mut limiter = self.config.rate_limiter();
+        let mut attachments = self.config.attachment_filter();
+
+        loop {
+            let frame = match timeout(read_timeout, frames.next()).await {
+                Ok(Some(frame)) => frame?,
+                Ok(None) => return Err(FrameError::Closed.into()),
+                Err(_) => {
+                    self.beat(client_socket, &mut heartbeat)?;
+                    continue;
+                }
+            };
+            heartbeat.received();
+            let json = match frame {
+                WsMessage::Text(json) => json,
+                WsMessage::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                WsMessage::Close(_) => return Err(FrameError::Closed.into()),
+                WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_) => continue,
+            };
+
+            // a browser client is told what it got wrong instead of being disconnected
+            let command = match Command::from_json(&json) {
+                Ok(command) => command,
+                Err(e) => {
+                    self.metrics.decode_error();
+                    let error = format!("Invalid command: {}", e);
+                    self.distribute(vec![Delivery::error(client_socket, error)])?;
+                    continue;
+                }
+            };
+            self.process(
+                client_socket,
+                &sender,
+                &mut limiter,
+                &mut attachments,
+                command,
+                json.len(),
+            )
+            .await?;
+        }
+    }
+
+    /// ping a quiet client, a client quiet for too long is evicted
+    fn beat(&self, client_socket: SocketAddr, heartbeat: &mut Heartbeat) -> Result<()> {
+        match heartbeat.poll() {
+            Beat::Wait => Ok(()),
+            Beat::Ping(id) => {
+                self.distribute(vec![Delivery::reply(client_socket, Event::Ping(id))])
+            }
+            Beat::Expired => Err(AppError::IdleTimeout(self.config.idle_timeout).into()),
+        }
+    }
+
+    /// apply the connection policies to a command received in len bytes and distribute the outcome
+    async fn process(
+        &self,
+        client_socket: SocketAddr,
+        sender: &str,
+        limiter: &mut RateLimiter,
+        attachments: &mut AttachmentFilter,
+        command: Command,
+        len: usize,
+    ) -> Result<()> {
+        self.metrics.received(&command, len);
+        log_command(sender, &command);
+
+        // refused commands are answered right away, sustained flooding ends the connection
+        let limit = limiter.check(&command, len);
+        if let Limit::Throttled(delay) = limit {
+            log_limit(sender, &limit);
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(refusal) = limit.reply(client_socket, &command) {
+            log_limit(sender, &limit);
+            self.distribute(vec![refusal])?;
+            if limit == Limit::Disconnect {
+                return Err(AppError::Flooding().into());
+            }
+            return Ok(());
+        }
+
+        // offending files and images are not forwarded
+        let screened = attachments.screen(&command);
+        if screened != Screened::Pass {
+            log_screened(sender, &screened);
+            if let Some(refusal) = screened.reply(client_socket, &command) {
+                self.distribute(vec![refusal])?;
+            }
+            return Ok(());
+        }
+
+        // password hashing blocks, keep other tasks of this worker running
+        let deliveries = tokio::task::block_in_place(|| self.state.handle(client_socket, command))?;
+        self.distribute(deliveries)
+    }
+
+    /// remember new client
+    fn register(&self, client_socket: SocketAddr, queue: Arc<OutboundQueue>) -> Result<()> {
+        let mut guard = self
+            .clients
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock client list".to_owned()))?;
+        // checked under the lock, shutdown notifies every client registered before
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(AppError::OtherError(SHUTDOWN_NOTICE.to_owned()).into());
+        }
+        guard.insert(client_socket, queue);
+        let count = guard.len();
+        info!(count, "Number of connected clients changed");
+        self.metrics.connected(count);
+        Ok(())
+    }
+
+    /// remove client after its handler ended
+    fn deregister(&self, client_socket: SocketAddr) {
+        // the others are told it went offline
+        if let Err(e) = self.distribute(self.state.disconnect(client_socket)) {
+            error!("{}", e);
+        }
+        if let Ok(mut guard) = self.clients.lock() {
+            if let Some(queue) = guard.remove(&client_socket) {
+                queue.close();
+            }
+            let count = guard.len();
+            info!(count, "Number of connected clients changed");
+            self.metrics.connected(count);
+        }
+    }
+
+    /// queue events for their recipients
+    fn distribute(&self, deliveries: Vec<Delivery>) -> Result<()> {
+        let guard = self
+            .clients
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock client list".to_owned()))?;
+
+        // queueing never blocks, an overflowing client is disconnected by its writer
+        queue_deliveries(
+            deliveries,
+            &self.metrics,
+            |client_socket, frames| {
+                let Some(queue) = guard.get(&client_socket) else {
+                    return Ok(false);
+                };
+                let queued = queue.push_event(frames)?;
+                self.metrics.queued(queue.len());
+                Ok(queued.is_queued())
+            },
+            // the writer of a kicked client ends once flushed, so does its handler
+            |client_socket| {
+                if let Some(queue) = guard.get(&client_socket) {
+                    queue.close();
+                }
+            },
+        )
+    }
+
+    async fn run(self: Arc<Self>) -> Result<()> {
+        info!("Hello to the Async Chat Server!");
+        info!(self.config.hostname, "HOSTNAME");
+        info!(self.config.port, "PORT");
+        info!(self.config.max_frame_size, "MAX_FRAME_SIZE");
+        info!(self.config.database, "DATABASE");
+        info!(self.config.history_size, "HISTORY_SIZE");
+        info!(self.config.outbound_queue_size, "OUTBOUND_QUEUE_SIZE");
+        info!(self.config.outbound_queue_bytes, "OUTBOUND_QUEUE_BYTES");
+        info!(?self.config.slow_client_policy, "SLOW_CLIENT_POLICY");
+        info!(self.config.shutdown_timeout, "SHUTDOWN_TIMEOUT");
+        info!(self.config.ping_interval, "PING_INTERVAL");
+        info!(self.config.idle_timeout, "IDLE_TIMEOUT");
+        info!(self.config.frame_timeout, "FRAME_TIMEOUT");
+        info!(self.config.rate_messages, "RATE_MESSAGES");
+        info!(self.config.rate_message_burst, "RATE_MESSAGE_BURST");
+        info!(self.config.rate_bytes, "RATE_BYTES");
+        info!(self.config.rate_byte_burst, "RATE_BYTE_BURST");
+        info!(self.config.flood_warnings, "FLOOD_WARNINGS");
+        info!(self.config.mute_duration, "MUTE_DURATION");
+        info!(self.config.flood_mutes, "FLOOD_MUTES");
+        info!(self.config.max_image_size, "MAX_IMAGE_SIZE");
+        info!(self.config.max_image_dimension, "MAX_IMAGE_DIMENSION");
+        info!(self.config.max_file_size, "MAX_FILE_SIZE");
+        info!(self.config.max_transfers, "MAX_TRANSFERS");
+        info!(self.config.transfer_timeout, "TRANSFER_TIMEOUT");
+        info!(?self.config.allowed_types, "ALLOWED_TYPES");
+        info!(?self.config.denied_types, "DENIED_TYPES");
+        info!(?self.config.admins, "ADMINS");
+        info!(self.config.metrics_port, "METRICS_PORT");
+        info!(self.config.metrics_hostname, "METRICS_HOSTNAME");
+        info!(self.config.ws_port, "WS_PORT");
+        match &self.config.tls_cert {
+            Some(tls_cert) => info!(tls_cert, "TLS_CERT"),
+            None => warn!("TLS is disabled, set TLS_CERT and TLS_KEY to enable it"),
+        }
+
+        let server_addr = format!("{}:{}", self.config.hostname, self.config.port);
+        let listener = TcpListener::bind(server_addr.clone())
+            .await
+            .context(AppError::TcpListenerError(server_addr))?;
+
+        // served on a thread, scraping does not compete with clients for tokio workers
+        if let Some(metrics_addr) = self.config.metrics_addr() {
+            self.metrics.clone().serve(metrics_addr)?;
+        }
+
+        // browser clients connect on a port of their own
+        if let Some(ws_port) = self.config.ws_port {
+            let ws_addr = format!("{}:{}", self.config.hostname, ws_port);
+            let ws_listener = TcpListener::bind(ws_addr.clone())
+                .await
+                .context(AppError::TcpListenerError(ws_addr))?;
+            tokio::spawn(self.clone().accept_browsers(ws_listener));
+        }
+
+        // SIGINT or SIGTERM stops accepting new connections
+        let signal = shutdown_signal();
+        tokio::pin!(signal);
+
+        // listen new connections
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                signalled = &mut signal => {
+                    signalled?;
+                    break;
+                }
+            };
+            let (stream, client_socket) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+
+            // spawn client handler
+            let server = self.clone();
+            tokio::spawn(async move {
+                match &server.tls {
+                    // a handshake is held to the same deadline as a frame
+                    Some(tls) => {
+                        match timeout(server.config.frame_timeout(), tls.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let handled = server.handle_client(client_socket, stream).await;
+                                log_disconnect(client_socket, &handled);
+                            }
+                            Ok(Err(e)) => error!("{}: TLS handshake failed: {}", client_socket, e),
+                            Err(_) => warn!("{}: TLS handshake timed out", client_socket),
+                        }
+                    }
+                    None => {
+                        let handled = server.handle_client(client_socket, stream).await;
+                        log_disconnect(client_socket, &handled);
+                    }
+                }
+                server.deregister(client_socket);
+            });
+        }
+
+        drop(listener);
+        self.shutdown().await
+    }
+
+    /// accept browser connections, they share rooms and users with native clients
+    async fn accept_browsers(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, client_socket) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let handled = match &server.tls {
+                    Some(tls) => {
+                        match timeout(server.config.frame_timeout(), tls.accept(stream)).await {
+                            Ok(Ok(stream)) => server.handle_browser(client_socket, stream).await,
+                            Ok(Err(e)) => Err(e.into()),
+                            Err(_) => {
+                                Err(AppError::OtherError("TLS handshake timed out".to_owned())
+                                    .into())
+                            }
+                        }
+                    }
+                    None => server.handle_browser(client_socket, stream).await,
+                };
+                log_disconnect(client_socket, &handled);
+                server.deregister(client_socket);
+            });
+        }
+    }
+
+    /// upgrade a browser connection to a WebSocket, plain page requests get the web client
+    async fn handle_browser<S>(&self, client_socket: SocketAddr, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let accepted = timeout(
+            self.config.frame_timeout(),
+            accept_websocket(stream, self.config.max_frame_size),
+        )
+        .await
+        .map_err(|_| AppError::OtherError("WebSocket handshake timed out".to_owned()))??;
+        match accepted {
+            Some(websocket) => self.handle_json_client(client_socket, websocket).await,
+            None => Ok(()),
+        }
+    }
+
+    /// send shutdown notice to all clients and wait until their queues are flushed
+    async fn shutdown(&self) -> Result<()> {
+        info!("Shutting down");
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let notice = Event::Shutdown(SHUTDOWN_NOTICE.to_owned());
+        let mut frames = EventFrames::new(&notice);
+        if let Ok(guard) = self.clients.lock() {
+            for queue in guard.values() {
+                queue.push_event(&mut frames)?;
+                queue.close();
+            }
+        }
+
+        // writers end once flushed, handlers deregister their clients then
+        let flushed = async {
+            while !self
+                .clients
+                .lock()
+                .map(|guard| guard.is_empty())
+                .unwrap_or(true)
+            {
+                tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+            }
+        };
+        let shutdown_timeout = Duration::from_secs(self.config.shutdown_timeout);
+        if timeout(shutdown_timeout, flushed).await.is_err() {
+            let count = self
+                .clients
+                .lock()
+                .map(|guard| guard.len())
+                .unwrap_or_default();
+            warn!(count, "Closing connections not flushed in time");
+        }
+        Ok(())
+    }
+}
+
+/// wait for SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).context(AppError::SignalError())?;
+        tokio::select! {
+            interrupted = tokio::signal::ctrl_c() => interrupted.context(AppError::SignalError())?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .context(AppError::SignalError())?;
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Result;
+use chatlib::{HistoryContent, HistoryEntry};
+use rusqlite::{params, Connection, Row};
+
+use crate::AppError;
+
+/// Largest page of history returned for a single request
+pub const MAX_HISTORY_PAGE: usize = 100;
+
+/// Room messages kept in a SQLite database
+/// ids of direct messages are not stored with them, the newest one is kept on its own
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    /// open the database at path, created on first use
+    pub fn open(path: &str) -> Result<History> {
+        History::init(Connection::open(path)?)
+    }
+
+    /// history which is lost when dropped
+    pub fn open_in_memory() -> Result<History> {
+        History::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<History> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY NOT NULL,
+                room TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                body TEXT NOT NULL,
+                ext TEXT,
+                size INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);
+            CREATE TABLE IF NOT EXISTS message_ids (
+                key INTEGER PRIMARY KEY CHECK (key = 0),
+                last_id INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// lock database connection
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>> {
+        let guard = self
+            .connection
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock history".to_owned()))?;
+        Ok(guard)
+    }
+
+    /// id of the newest message, stored or direct, message ids continue from it after a restart
+    pub fn last_id(&self) -> Result<u64> {
+        let id: Option<i64> = self.connection()?.query_row(
+            "SELECT MAX(id) FROM (
+                SELECT id FROM messages UNION ALL SELECT last_id FROM message_ids
+            )",
+            (),
+            |row| row.get(0),
+        )?;
+        Ok(id.unwrap_or_default() as u64)
+    }
+
+    /// remember the id of a message which is not stored, e.g. a direct message
+    pub fn record_id(&self, id: u64) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO message_ids (key, last_id) VALUES (0, ?1)
+             ON CONFLICT (key) DO UPDATE SET last_id = MAX(last_id, excluded.last_id)",
+            params![id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// store a room message
+    pub fn append(&self, entry: &HistoryEntry) -> Result<()> {
+        let (kind, body, ext, size) = match &entry.content {
+            HistoryContent::Text(text) => ("text", text, None, None),
+            HistoryContent::Image(name, ext, size) => ("image", name, Some(ext), Some(*size)),
+            HistoryContent::File(name, size) => ("file", name, None, Some(*size)),
+        };
+
+        self.connection()?.execute(
+            "INSERT INTO messages (id, room, sender, timestamp, kind, body, ext, size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.id as i64,
+                entry.room,
+                entry.sender,
+                entry.timestamp,
+                kind,
+                body,
+                ext,
+                size.map(|size| size as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// up to limit room messages older than the id, newest messages when there is no id
+    /// entries are ordered from the oldest one
+    pub fn page(&self, room: &str, before: Option<u64>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let before = before.map_or(i64::MAX, |id| i64::try_from(id).unwrap_or(i64::MAX));
+        let limit = limit.min(MAX_HISTORY_PAGE) as i64;
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare_cached(
+            "SELECT id, room, sender, timestamp, kind, body, ext, size FROM messages
+             WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+        )?;
+        let mut entries = statement
+            .query_map(params![room, before, limit], history_entry)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+/// construct history entry from a database row
+fn history_entry(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    let kind: String = row.get(4)?;
+    let body: String = row.get(5)?;
+    let size = row.get::<_, Option<i64>>(7)?.unwrap_or_default() as u64;
+
+    let content = match kind.as_str() {
+        "image" => HistoryContent::Image(
+            body,
+            row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            size,
+        ),
+        "file" => HistoryContent::File(body, size),
+        _ => HistoryContent::Text(body),
+    };
+
+    Ok(HistoryEntry {
+        id: row.get::<_, i64>(0)? as u64,
+        room: row.get(1)?,
+        sender: row.get(2)?,
+        timestamp: row.get(3)?,
+        content,
+    })
+}
@@ -0,0 +1,586 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use chatlib::{
+    Ack, Command, Envelope, Event, IdentityKey, Message, Presence, RoomInfo, Sanction, Target,
+    UserInfo, LOBBY,
+};
+use chrono::{DateTime, Utc};
+
+/// Longest accepted username
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Number of accepted message ids remembered for every user to recognize resent messages
+pub const RECENT_ACKS: usize = 256;
+
+/// Event addressed to a list of clients
+#[derive(Debug)]
+pub struct Delivery {
+    pub recipients: Vec<SocketAddr>,
+    pub event: Event,
+    /// sender and client id of a message, told how many recipients it was queued for
+    pub ack: Option<(SocketAddr, u64)>,
+    /// recipients are disconnected once the event is written
+    pub disconnect: bool,
+}
+
+/// Routing state shared by the threaded and the async server
+/// it only decides who receives what, writing to the sockets is up to the server
+#[derive(Debug)]
+pub struct Hub {
+    /// room name and its members
+    rooms: BTreeMap<String, BTreeSet<SocketAddr>>,
+    /// username of every registered client
+    names: HashMap<SocketAddr, String>,
+    /// client registered under every username
+    users: HashMap<String, SocketAddr>,
+    /// identity keys published by clients for end to end encryption
+    keys: HashMap<SocketAddr, IdentityKey>,
+    /// when every registered client logged in and last sent a command
+    activity: HashMap<SocketAddr, Activity>,
+    /// client and message ids of messages recently accepted from every user, oldest first
+    accepted: HashMap<String, VecDeque<(u64, u64)>>,
+    /// id of the last distributed message
+    message_id: u64,
+    /// usernames allowed to moderate
+    admins: HashSet<String>,
+    /// when the mute of every muted user ends
+    muted: HashMap<String, Instant>,
+}
+
+/// Connection time and last activity of a registered client
+#[derive(Debug)]
+struct Activity {
+    connected: DateTime<Utc>,
+    active: Instant,
+}
+
+impl Delivery {
+    /// construct a reply for a single client
+    pub fn reply(client: SocketAddr, event: Event) -> Delivery {
+        Delivery {
+            recipients: vec![client],
+            event,
+            ack: None,
+            disconnect: false,
+        }
+    }
+
+    /// construct an error reply for a single client
+    pub fn error(client: SocketAddr, error: String) -> Delivery {
+        Delivery::reply(client, Event::Error(error))
+    }
+
+    /// construct a rejection of the message the client sent under the id
+    pub fn rejected(client: SocketAddr, id: u64, reason: String) -> Delivery {
+        Delivery::reply(client, Event::Ack(id, Ack::Rejected(reason)))
+    }
+
+    /// acknowledgement telling the sender how many other clients the message was queued for
+    pub fn delivered(&self, queued: &[SocketAddr]) -> Option<Delivery> {
+        let (sender, id) = self.ack?;
+        let delivered = queued.iter().filter(|client| **client != sender).count();
+        Some(Delivery::reply(
+            sender,
+            Event::Ack(id, Ack::Delivered(delivered)),
+        ))
+    }
+}
+
+/// usernames are non empty, short and without whitespace or control characters
+pub fn valid_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_USERNAME_LEN
+        && !name.contains(|c: char| c.is_whitespace() || c.is_control())
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hub {
+    /// initialize new instance with an empty lobby
+    pub fn new() -> Self {
+        Self::with_message_id(0)
+    }
+
+    /// initialize new instance numbering messages after message_id
+    pub fn with_message_id(message_id: u64) -> Self {
+        Hub {
+            rooms: BTreeMap::from([(LOBBY.to_owned(), BTreeSet::new())]),
+            names: HashMap::new(),
+            users: HashMap::new(),
+            keys: HashMap::new(),
+            activity: HashMap::new(),
+            accepted: HashMap::new(),
+            message_id,
+            admins: HashSet::new(),
+            muted: HashMap::new(),
+        }
+    }
+
+    /// allow the usernames to moderate
+    pub fn with_admins(mut self, admins: Vec<String>) -> Self {
+        self.admins = admins.into_iter().collect();
+        self
+    }
+
+    /// whether the username belongs to an admin
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.admins.contains(name)
+    }
+
+    /// remove client from all rooms and release its username
+    /// users still online are told a registered client went offline
+    pub fn disconnect(&mut self, client: SocketAddr) -> Vec<Delivery> {
+        let name = self.names.remove(&client);
+        if let Some(name) = &name {
+            self.users.remove(name);
+        }
+        self.keys.remove(&client);
+        self.activity.remove(&client);
+        for members in self.rooms.values_mut() {
+            members.remove(&client);
+        }
+        self.remove_empty_rooms();
+
+        match name {
+            Some(name) => vec![self.presence(client, name, Presence::Offline)],
+            None => vec![],
+        }
+    }
+
+    /// presence change of a client addressed to all other registered clients
+    fn presence(&self, client: SocketAddr, name: String, presence: Presence) -> Delivery {
+        Delivery {
+            recipients: self
+                .names
+                .keys()
+                .copied()
+                .filter(|c| *c != client)
+                .collect(),
+            event: Event::Presence(name, presence),
+            ack: None,
+            disconnect: false,
+        }
+    }
+
+    /// drop rooms without members, the lobby is kept forever
+    fn remove_empty_rooms(&mut self) {
+        self.rooms
+            .retain(|room, members| room == LOBBY || !members.is_empty());
+    }
+
+    /// username of a registered client
+    pub fn username(&self, client: SocketAddr) -> Option<&str> {
+        self.names.get(&client).map(String::as_str)
+    }
+
+    /// whether the client is a member of the room
+    pub fn is_member(&self, client: SocketAddr, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|members| members.contains(&client))
+    }
+
+    /// process client command and return events to distribute
+    /// clients have to be logged in before anything else, credentials are checked by the server
+    pub fn handle(&mut self, client: SocketAddr, command: Command) -> Vec<Delivery> {
+        let Some(sender) = self.names.get(&client).cloned() else {
+            return match command {
+                // heartbeats are answered before the client logs in
+                Command::Ping(id) => vec![Delivery::reply(client, Event::Pong(id))],
+                Command::Pong(_) => vec![],
+                Command::Send(id, ..) => vec![Delivery::rejected(
+                    client,
+                    id,
+                    "Not logged in, log in or register first".to_owned(),
+                )],
+                _ => vec![Delivery::error(
+                    client,
+                    "Not logged in, log in or register first".to_owned(),
+                )],
+            };
+        };
+
+        // heartbeats keep the connection alive but do not count as activity
+        if !matches!(command, Command::Ping(_) | Command::Pong(_)) {
+            if let Some(activity) = self.activity.get_mut(&client) {
+                activity.active = Instant::now();
+            }
+        }
+
+        let event = match command {
+            Command::Login(..) | Command::Register(..) => {
+                Event::Error(format!("Already logged in as `{}`", sender))
+            }
+            Command::Nick(name) => return self.rename(client, &sender, name),
+            Command::Send(id, target, message) => {
+                return self.send(client, &sender, id, target, message)
+            }
+            Command::Join(room) => {
+                if room.is_empty() || room.contains(char::is_whitespace) {
+                    Event::Error(format!("Invalid room name `{}`", room))
+                } else {
+                    self.rooms.entry(room.clone()).or_default().insert(client);
+                    Event::Joined(room)
+                }
+            }
+            Command::Leave(room) => {
+                let removed = self
+                    .rooms
+                    .get_mut(&room)
+                    .is_some_and(|members| members.remove(&client));
+                if removed {
+                    self.remove_empty_rooms();
+                    Event::Left(room)
+                } else {
+                    Event::Error(format!("You are not a member of room `{}`", room))
+                }
+            }
+            Command::History(..) => Event::Error("History is not available".to_owned()),
+            Command::PublishKey(key) => {
+                self.keys.insert(client, key);
+                return vec![];
+            }
+            Command::GetKey(user) => {
+                let key = self
+                    .users
+                    .get(&user)
+                    .and_then(|c| self.keys.get(c))
+                    .copied();
+                Event::IdentityKey(user, key)
+            }
+            Command::Ping(id) => Event::Pong(id),
+            Command::Pong(_) => return vec![],
+            Command::Rooms => Event::Rooms(
+                self.rooms
+                    .iter()
+                    .map(|(name, members)| RoomInfo {
+                        name: name.clone(),
+                        members: members.len(),
+                        joined: members.contains(&client),
+                    })
+                    .collect(),
+            ),
+            Command::Who => Event::Users(self.online()),
+            Command::Moderate(sanction) => return self.moderate(client, &sender, sanction),
+        };
+
+        vec![Delivery::reply(client, event)]
+    }
+
+    /// registered users ordered by name
+    fn online(&self) -> Vec<UserInfo> {
+        let mut users = self
+            .names
+            .iter()
+            .filter_map(|(client, name)| {
+                let activity = self.activity.get(client)?;
+                Some(UserInfo {
+                    name: name.clone(),
+                    connected: activity.connected,
+                    idle: activity.active.elapsed().as_secs(),
+                })
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    /// validate a requested username
+    fn check_username(&self, name: &str) -> Result<(), String> {
+        if !valid_username(name) {
+            return Err(format!("Invalid username `{}`", name));
+        }
+        if self.users.contains_key(name) {
+            return Err(format!("Username `{}` is already taken", name));
+        }
+        if self.is_admin(name) {
+            return Err(format!("Username `{}` is reserved", name));
+        }
+        Ok(())
+    }
+
+    /// whether the client may take the sanction, admins can not be sanctioned
+    pub fn authorize(&self, client: SocketAddr, sanction: &Sanction) -> Result<(), String> {
+        if !self
+            .username(client)
+            .is_some_and(|name| self.is_admin(name))
+        {
+            return Err("Only admins can moderate".to_owned());
+        }
+        match sanction {
+            Sanction::Kick(user, _) | Sanction::Ban(user, _) | Sanction::Mute(user, _)
+                if self.is_admin(user) =>
+            {
+                Err(format!("Admin `{}` can not be sanctioned", user))
+            }
+            Sanction::Kick(user, _) | Sanction::Mute(user, _) if !self.users.contains_key(user) => {
+                Err(format!("User `{}` is not connected", user))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// apply the sanction of an admin, bans are persisted by the server beforehand
+    /// members of the rooms of the sanctioned users are told, kicked users are disconnected
+    fn moderate(&mut self, client: SocketAddr, admin: &str, sanction: Sanction) -> Vec<Delivery> {
+        if let Err(e) = self.authorize(client, &sanction) {
+            return vec![Delivery::error(client, e)];
+        }
+
+        let (sanctioned, kicked) = match &sanction {
+            Sanction::Kick(user, _) => (self.users.get(user).copied(), true),
+            Sanction::Ban(target, _) => match target.parse::<IpAddr>() {
+                Ok(ip) => return self.ban_ip(client, admin, ip, sanction),
+                Err(_) => (self.users.get(target).copied(), true),
+            },
+            Sanction::Mute(user, secs) => {
+                match secs {
+                    0 => self.muted.remove(user),
+                    _ => self
+                        .muted
+                        .insert(user.clone(), Instant::now() + Duration::from_secs(*secs)),
+                };
+                (self.users.get(user).copied(), false)
+            }
+            Sanction::Unban(_) => (None, false),
+        };
+
+        match sanctioned {
+            Some(user) if kicked => self.kick(client, admin, vec![user], sanction),
+            Some(user) => vec![self.notice(client, admin, &[user], sanction)],
+            None => vec![Delivery::reply(
+                client,
+                Event::Sanctioned(admin.to_owned(), sanction),
+            )],
+        }
+    }
+
+    /// kick all clients connecting from the address, admins stay connected
+    fn ban_ip(
+        &mut self,
+        client: SocketAddr,
+        admin: &str,
+        ip: IpAddr,
+        sanction: Sanction,
+    ) -> Vec<Delivery> {
+        let mut users = self
+            .names
+            .iter()
+            .filter(|(c, name)| c.ip() == ip && !self.is_admin(name))
+            .map(|(c, _)| *c)
+            .collect::<Vec<_>>();
+        users.sort();
+        self.kick(client, admin, users, sanction)
+    }
+
+    /// notice to the admin and the members of all rooms of the users
+    fn notice(
+        &self,
+        client: SocketAddr,
+        admin: &str,
+        users: &[SocketAddr],
+        sanction: Sanction,
+    ) -> Delivery {
+        let mut recipients = BTreeSet::from([client]);
+        for members in self.rooms.values() {
+            if users.iter().any(|user| members.contains(user)) {
+                recipients.extend(members);
+            }
+        }
+        Delivery {
+            recipients: recipients.into_iter().collect(),
+            event: Event::Sanctioned(admin.to_owned(), sanction),
+            ack: None,
+            disconnect: false,
+        }
+    }
+
+    /// notify about the sanction and disconnect the users, the others see them go offline
+    fn kick(
+        &mut self,
+        client: SocketAddr,
+        admin: &str,
+        users: Vec<SocketAddr>,
+        sanction: Sanction,
+    ) -> Vec<Delivery> {
+        let mut notice = self.notice(client, admin, &users, sanction.clone());
+        notice.recipients.retain(|c| !users.contains(c));
+        let kicked = Delivery {
+            recipients: users.clone(),
+            event: Event::Sanctioned(admin.to_owned(), sanction),
+            ack: None,
+            disconnect: true,
+        };
+
+        let mut deliveries = vec![notice, kicked];
+        for user in users {
+            deliveries.extend(self.disconnect(user));
+        }
+        deliveries
+    }
+
+    /// register username of an authenticated client, logged in clients start in the lobby
+    /// an account can be used by a single connection at a time
+    pub fn login(&mut self, client: SocketAddr, name: String) -> Vec<Delivery> {
+        if let Some(sender) = self.names.get(&client) {
+            return vec![Delivery::error(
+                client,
+                format!("Already logged in as `{}`", sender),
+            )];
+        }
+        if self.users.contains_key(&name) {
+            return vec![Delivery::error(
+                client,
+                format!("User `{}` is already connected", name),
+            )];
+        }
+
+        self.names.insert(client, name.clone());
+        self.users.insert(name.clone(), client);
+        self.activity.insert(
+            client,
+            Activity {
+                connected: Utc::now(),
+                active: Instant::now(),
+            },
+        );
+        self.rooms
+            .entry(LOBBY.to_owned())
+            .or_default()
+            .insert(client);
+
+        vec![
+            Delivery::reply(client, Event::Welcome(name.clone())),
+            self.presence(client, name, Presence::Online),
+        ]
+    }
+
+    /// change username of a registered client and notify everybody
+    fn rename(&mut self, client: SocketAddr, old: &str, name: String) -> Vec<Delivery> {
+        if let Err(e) = self.check_username(&name) {
+            return vec![Delivery::error(client, e)];
+        }
+
+        self.users.remove(old);
+        self.users.insert(name.clone(), client);
+        self.names.insert(client, name.clone());
+        if let Some(accepted) = self.accepted.remove(old) {
+            self.accepted.insert(name.clone(), accepted);
+        }
+        // a mute sticks to the user
+        if let Some(until) = self.muted.remove(old) {
+            self.muted.insert(name.clone(), until);
+        }
+
+        vec![Delivery {
+            recipients: self.names.keys().copied().collect(),
+            event: Event::Renamed(old.to_owned(), name),
+            ack: None,
+            disconnect: false,
+        }]
+    }
+
+    /// stamp message and address it to the room members or the named user
+    /// the sender is acknowledged under the client id, a resent message only so
+    fn send(
+        &mut self,
+        client: SocketAddr,
+        sender: &str,
+        id: u64,
+        target: Target,
+        message: Message,
+    ) -> Vec<Delivery> {
+        let resent = self
+            .accepted
+            .get(sender)
+            .and_then(|accepted| accepted.iter().find(|(client_id, _)| *client_id == id));
+        if let Some((_, message_id)) = resent {
+            return vec![Delivery::reply(
+                client,
+                Event::Ack(id, Ack::Accepted(*message_id)),
+            )];
+        }
+
+        if let Some(remaining) = self.muted_for(sender) {
+            return vec![Delivery::rejected(
+                client,
+                id,
+                format!(
+                    "You are muted for {} more seconds",
+                    remaining.as_secs_f64().ceil()
+                ),
+            )];
+        }
+
+        let recipients: Vec<SocketAddr> = match &target {
+            Target::Room(_) if matches!(message, Message::Sealed(_)) => {
+                return vec![Delivery::rejected(
+                    client,
+                    id,
+                    "Sealed messages can be sent to a single user only".to_owned(),
+                )];
+            }
+            Target::Room(room) => {
+                let Some(members) = self.rooms.get(room).filter(|m| m.contains(&client)) else {
+                    return vec![Delivery::rejected(
+                        client,
+                        id,
+                        format!("You are not a member of room `{}`", room),
+                    )];
+                };
+                members.iter().copied().collect()
+            }
+            Target::User(user) => {
+                let Some(recipient) = self.users.get(user).copied() else {
+                    return vec![Delivery::rejected(
+                        client,
+                        id,
+                        format!("User `{}` is not connected", user),
+                    )];
+                };
+
+                // sender receives a copy of the direct message
+                if recipient == client {
+                    vec![client]
+                } else {
+                    vec![recipient, client]
+                }
+            }
+        };
+
+        self.message_id += 1;
+        let accepted = self.accepted.entry(sender.to_owned()).or_default();
+        if accepted.len() >= RECENT_ACKS {
+            accepted.pop_front();
+        }
+        accepted.push_back((id, self.message_id));
+
+        let envelope = Envelope::new(self.message_id, sender, target, message);
+        vec![
+            Delivery::reply(client, Event::Ack(id, Ack::Accepted(self.message_id))),
+            Delivery {
+                recipients,
+                event: Event::Message(envelope),
+                ack: Some((client, id)),
+                disconnect: false,
+            },
+        ]
+    }
+
+    /// remaining time of the mute of a user, an ended mute is forgotten
+    fn muted_for(&mut self, name: &str) -> Option<Duration> {
+        let until = *self.muted.get(name)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            self.muted.remove(name);
+            return None;
+        }
+        Some(remaining)
+    }
+}
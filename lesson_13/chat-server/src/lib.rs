@@ -0,0 +1,452 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chatlib::{
+    tls_server_config, Command, FrameError, Heartbeat, Message, Target, TlsServerConfig,
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_IMAGE_DIMENSION,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+pub use attachment::{sniff, AttachmentFilter, AttachmentPolicy, Screened};
+pub use history::{History, MAX_HISTORY_PAGE};
+pub use hub::{valid_username, Delivery, Hub, MAX_USERNAME_LEN, RECENT_ACKS};
+pub use metrics::Metrics;
+pub use outbound::{
+    negotiate_codec, queue_deliveries, EventFrames, Frame, OutboundQueue, Queued, SlowClientPolicy,
+};
+pub use rate_limit::{Limit, RateLimiter, RateLimits};
+pub use state::State;
+pub use users::{hash_password, AuthError, UserStore, MIN_PASSWORD_LEN};
+pub use websocket::{accept_websocket, WEB_CLIENT};
+
+mod attachment;
+mod history;
+mod hub;
+mod metrics;
+mod outbound;
+mod rate_limit;
+mod state;
+mod users;
+mod websocket;
+
+/// Notice sent to all clients before the server exits
+pub const SHUTDOWN_NOTICE: &str = "Server is shutting down";
+
+#[derive(Deserialize, Debug)]
+pub struct ServerConfig {
+    #[serde(default = "server_config_default_port")]
+    pub port: u16,
+    #[serde(default = "server_config_default_hostname")]
+    pub hostname: String,
+    #[serde(default = "server_config_default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// SQLite database with user accounts and message history
+    #[serde(default = "server_config_default_database")]
+    pub database: String,
+    /// number of recent messages replayed when a room is entered
+    #[serde(default = "server_config_default_history_size")]
+    pub history_size: usize,
+    /// PEM certificate chain, TLS is enabled together with tls_key
+    pub tls_cert: Option<String>,
+    /// PEM private key of the certificate
+    pub tls_key: Option<String>,
+    /// number of events queued for a client before the slow client policy applies
+    #[serde(default = "server_config_default_outbound_queue_size")]
+    pub outbound_queue_size: usize,
+    /// bytes of events queued for a client before the slow client policy applies
+    #[serde(default = "server_config_default_outbound_queue_bytes")]
+    pub outbound_queue_bytes: usize,
+    #[serde(default = "server_config_default_slow_client_policy")]
+    pub slow_client_policy: SlowClientPolicy,
+    /// seconds queued events are flushed for on shutdown
+    #[serde(default = "server_config_default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// seconds without a frame before a client is pinged
+    #[serde(default = "server_config_default_ping_interval")]
+    pub ping_interval: u64,
+    /// seconds without a frame before a client is evicted
+    #[serde(default = "server_config_default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// seconds a started frame has to complete in
+    #[serde(default = "server_config_default_frame_timeout")]
+    pub frame_timeout: u64,
+    /// commands a client may send per second, heartbeats excluded, 0 disables the limit
+    #[serde(default = "server_config_default_rate_messages")]
+    pub rate_messages: u64,
+    /// commands a client may send at once
+    #[serde(default = "server_config_default_rate_message_burst")]
+    pub rate_message_burst: u64,
+    /// bytes a client may send per second, 0 disables the limit
+    #[serde(default = "server_config_default_rate_bytes")]
+    pub rate_bytes: u64,
+    /// bytes a client may send at once
+    #[serde(default = "server_config_default_rate_byte_burst")]
+    pub rate_byte_burst: u64,
+    /// commands refused with a warning before a client is muted
+    #[serde(default = "server_config_default_flood_warnings")]
+    pub flood_warnings: u32,
+    /// seconds a flooding client is muted for
+    #[serde(default = "server_config_default_mute_duration")]
+    pub mute_duration: u64,
+    /// mutes before a flooding client is disconnected
+    #[serde(default = "server_config_default_flood_mutes")]
+    pub flood_mutes: u32,
+    /// largest image accepted in bytes
+    #[serde(default = "server_config_default_max_image_size")]
+    pub max_image_size: u64,
+    /// largest image width and height accepted in pixels
+    #[serde(default = "server_config_default_max_image_dimension")]
+    pub max_image_dimension: u32,
+    /// largest file accepted in bytes, chunked transfers included
+    #[serde(default = "server_config_default_max_file_size")]
+    pub max_file_size: u64,
+    /// chunked transfers a client may have in progress
+    #[serde(default = "server_config_default_max_transfers")]
+    pub max_transfers: usize,
+    /// seconds a chunked transfer without a chunk is kept open
+    #[serde(default = "server_config_default_transfer_timeout")]
+    pub transfer_timeout: u64,
+    /// extensions and MIME types accepted, anything not denied when empty
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+    /// extensions and MIME types refused
+    #[serde(default = "server_config_default_denied_types")]
+    pub denied_types: Vec<String>,
+    /// usernames allowed to kick, ban and mute users
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// argon2 hash of the admin password, admin accounts are created with it on start
+    pub admin_password_hash: Option<String>,
+    /// port of the HTTP endpoint serving Prometheus metrics, disabled when not set
+    pub metrics_port: Option<u16>,
+    #[serde(default = "server_config_default_hostname")]
+    pub metrics_hostname: String,
+    /// port of the WebSocket gateway serving browser clients, disabled when not set
+    pub ws_port: Option<u16>,
+}
+
+fn server_config_default_port() -> u16 {
+    11111
+}
+
+fn server_config_default_hostname() -> String {
+    "localhost".to_owned()
+}
+
+fn server_config_default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn server_config_default_database() -> String {
+    "chat-server.db".to_owned()
+}
+
+fn server_config_default_history_size() -> usize {
+    20
+}
+
+fn server_config_default_outbound_queue_size() -> usize {
+    256
+}
+
+fn server_config_default_outbound_queue_bytes() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn server_config_default_slow_client_policy() -> SlowClientPolicy {
+    SlowClientPolicy::Disconnect
+}
+
+fn server_config_default_shutdown_timeout() -> u64 {
+    5
+}
+
+fn server_config_default_ping_interval() -> u64 {
+    15
+}
+
+fn server_config_default_idle_timeout() -> u64 {
+    45
+}
+
+fn server_config_default_frame_timeout() -> u64 {
+    10
+}
+
+fn server_config_default_rate_messages() -> u64 {
+    10
+}
+
+fn server_config_default_rate_message_burst() -> u64 {
+    50
+}
+
+fn server_config_default_rate_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn server_config_default_rate_byte_burst() -> u64 {
+    DEFAULT_MAX_FRAME_SIZE as u64
+}
+
+fn server_config_default_flood_warnings() -> u32 {
+    5
+}
+
+fn server_config_default_mute_duration() -> u64 {
+    30
+}
+
+fn server_config_default_flood_mutes() -> u32 {
+    3
+}
+
+fn server_config_default_max_image_dimension() -> u32 {
+    DEFAULT_MAX_IMAGE_DIMENSION
+}
+
+fn server_config_default_max_image_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn server_config_default_max_file_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn server_config_default_max_transfers() -> usize {
+    4
+}
+
+fn server_config_default_transfer_timeout() -> u64 {
+    60
+}
+
+fn server_config_default_denied_types() -> Vec<String> {
+    [
+        "exe",
+        "dll",
+        "bat",
+        "cmd",
+        "com",
+        "scr",
+        "msi",
+        "ps1",
+        "vbs",
+        "application/x-msdownload",
+    ]
+    .map(str::to_owned)
+    .to_vec()
+}
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("Configuration error")]
+    ConfigError(),
+
+    #[error("Unable to listen @ `{0}`")]
+    TcpListenerError(String),
+
+    #[error("Unable to open database `{0}`")]
+    DatabaseError(String),
+
+    #[error("Unable to load TLS certificate `{0}`")]
+    TlsError(String),
+
+    #[error("Unable to handle signals")]
+    SignalError(),
+
+    #[error("Nothing received for {0} seconds")]
+    IdleTimeout(u64),
+
+    #[error("Disconnected for flooding")]
+    Flooding(),
+
+    #[error("Error: `{0}`")]
+    OtherError(String),
+}
+
+impl ServerConfig {
+    /// TLS configuration when certificate and key are configured, plain TCP otherwise
+    pub fn tls(&self) -> Result<Option<Arc<TlsServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                let config =
+                    tls_server_config(cert, key).context(AppError::TlsError(cert.clone()))?;
+                Ok(Some(config))
+            }
+            (None, None) => Ok(None),
+            _ => Err(AppError::OtherError(
+                "TLS_CERT and TLS_KEY have to be set together".to_owned(),
+            )
+            .into()),
+        }
+    }
+
+    /// open the user store, admin accounts are created or get the configured password
+    /// admins can not register, without a password hash only existing accounts are admins
+    pub fn user_store(&self) -> Result<UserStore> {
+        let users = UserStore::open(&self.database)
+            .context(AppError::DatabaseError(self.database.clone()))?;
+        match &self.admin_password_hash {
+            Some(hash) => {
+                for admin in &self.admins {
+                    users
+                        .set_password_hash(admin, hash)
+                        .context(AppError::OtherError(format!(
+                            "Unable to create admin account `{}`",
+                            admin
+                        )))?;
+                }
+            }
+            None if !self.admins.is_empty() => {
+                warn!("ADMIN_PASSWORD_HASH is not set, admins have to exist already")
+            }
+            None => {}
+        }
+        Ok(users)
+    }
+
+    /// liveness check of a newly connected client
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat::new(
+            Duration::from_secs(self.ping_interval),
+            Duration::from_secs(self.idle_timeout),
+        )
+    }
+
+    /// time a started frame has to complete in
+    pub fn frame_timeout(&self) -> Duration {
+        Duration::from_secs(self.frame_timeout)
+    }
+
+    /// how long a client read waits before the heartbeat and frame timeout are checked
+    pub fn read_timeout(&self, heartbeat: &Heartbeat) -> Duration {
+        heartbeat.poll_interval().min(self.frame_timeout() / 2)
+    }
+
+    /// queue of events waiting to be written to a newly connected client
+    pub fn outbound_queue(&self, client: SocketAddr) -> OutboundQueue {
+        OutboundQueue::new(client, self.outbound_queue_size, self.slow_client_policy)
+            .with_max_bytes(self.outbound_queue_bytes)
+    }
+
+    /// attachment policy applied to a newly connected client
+    pub fn attachment_filter(&self) -> AttachmentFilter {
+        AttachmentFilter::new(AttachmentPolicy {
+            max_image_size: self.max_image_size,
+            max_image_dimension: self.max_image_dimension,
+            max_file_size: self.max_file_size,
+            max_transfers: self.max_transfers,
+            transfer_timeout: Duration::from_secs(self.transfer_timeout),
+            allowed_types: self.allowed_types.clone(),
+            denied_types: self.denied_types.clone(),
+        })
+    }
+
+    /// address of the metrics endpoint when enabled
+    pub fn metrics_addr(&self) -> Option<String> {
+        self.metrics_port
+            .map(|port| format!("{}:{}", self.metrics_hostname, port))
+    }
+
+    /// flood protection of a newly connected client
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            messages: (self.rate_messages, self.rate_message_burst),
+            bytes: (self.rate_bytes, self.rate_byte_burst),
+            warnings: self.flood_warnings,
+            mute_duration: Duration::from_secs(self.mute_duration),
+            mutes: self.flood_mutes,
+        })
+    }
+}
+
+/// log why a client handler ended, clients not keeping the connection alive are evicted
+pub fn log_disconnect(client: SocketAddr, result: &Result<()>) {
+    let Err(e) = result else {
+        return;
+    };
+    let client = client.to_string();
+    let evicted = matches!(
+        e.downcast_ref(),
+        Some(AppError::IdleTimeout(_) | AppError::Flooding())
+    ) || matches!(e.downcast_ref(), Some(FrameError::FrameTimeout(_)));
+
+    match e.downcast_ref() {
+        Some(FrameError::Closed) => info!(client, "Client disconnected"),
+        _ if evicted => warn!(client, reason = %e, "Client evicted"),
+        _ => info!(client, reason = %e, "Client disconnected"),
+    }
+}
+
+/// log incoming client command
+pub fn log_command(sender: &str, command: &Command) {
+    match command {
+        // passwords are never logged
+        Command::Login(name, _) => info!(sender, name, "Login"),
+        Command::Register(name, _) => info!(sender, name, "Register"),
+        Command::Nick(name) => info!(sender, name, "Nick"),
+        Command::Send(_, target, msg) => log_message(sender, target, msg),
+        Command::Join(room) => info!(sender, room, "Join"),
+        Command::Leave(room) => info!(sender, room, "Leave"),
+        Command::Rooms => info!(sender, "Rooms"),
+        Command::Who => info!(sender, "Who"),
+        Command::History(room, before, limit) => info!(sender, room, before, limit, "History"),
+        Command::PublishKey(_) => info!(sender, "PublishKey"),
+        Command::GetKey(user) => info!(sender, user, "GetKey"),
+        Command::Moderate(sanction) => info!(sender, ?sanction, "Moderate"),
+        Command::Ping(id) => debug!(sender, id, "Ping"),
+        Command::Pong(id) => debug!(sender, id, "Pong"),
+    };
+}
+
+/// log a command refused by the rate limiter
+pub fn log_limit(sender: &str, limit: &Limit) {
+    match limit {
+        Limit::Allowed => {}
+        Limit::Warned => debug!(sender, "Rate limit exceeded"),
+        Limit::Throttled(delay) => debug!(sender, ?delay, "Throttled"),
+        Limit::Muted(remaining) => debug!(sender, ?remaining, "Muted"),
+        Limit::Disconnect => warn!(sender, "Flooding"),
+    }
+}
+
+/// log a message refused by the attachment policy
+pub fn log_screened(sender: &str, screened: &Screened) {
+    match screened {
+        Screened::Pass => {}
+        Screened::Refused(e) => warn!(sender, reason = %e, "Attachment refused"),
+        Screened::Dropped => debug!(sender, "Attachment dropped"),
+    }
+}
+
+/// log incoming client message
+fn log_message(sender: &str, target: &Target, msg: &Message) {
+    let target = target.to_string();
+    match msg {
+        Message::Text(text) => info!(sender, target, text, "Message"),
+        Message::Image(image, ext, _, info) => info!(
+            sender,
+            target,
+            image,
+            ext,
+            width = info.width,
+            height = info.height,
+            "Message"
+        ),
+        Message::File(file, _) => info!(sender, target, file, "Message"),
+        Message::FileBegin(transfer_id, file, size) => {
+            info!(sender, target, transfer_id, file, size, "Message")
+        }
+        Message::FileChunk(transfer_id, bytes) => {
+            debug!(sender, target, transfer_id, len = bytes.len(), "Message")
+        }
+        Message::FileEnd(transfer_id) => info!(sender, target, transfer_id, "Message"),
+        // only the size of end to end encrypted messages is known
+        Message::Sealed(sealed) => info!(sender, target, len = sealed.ciphertext.len(), "Sealed"),
+    };
+}
@@ -1,47 +1,36 @@
 use std::collections::HashMap;
-
-use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use chatlib::Message;
-use serde::Deserialize;
-use thiserror::Error;
-use tracing::{error, info};
-
-#[derive(Deserialize, Debug)]
-pub struct ServerConfig {
-    #[serde(default = "server_config_default_port")]
-    pub port: u16,
-    #[serde(default = "server_config_default_hostname")]
-    pub hostname: String,
-}
-
-fn server_config_default_port() -> u16 {
-    11111
-}
-
-fn server_config_default_hostname() -> String {
-    "localhost".to_owned()
-}
+use chat_server::{
+    log_command, log_disconnect, log_limit, log_screened, negotiate_codec, queue_deliveries,
+    AppError, Delivery, EventFrames, History, Limit, Metrics, OutboundQueue, Queued, Screened,
+    ServerConfig, State, SHUTDOWN_NOTICE,
+};
+use chatlib::{
+    Beat, Codec, Command, Event, FrameError, FrameReader, FrameWriter, Stream, TlsServerConfig,
+    TlsStream,
+};
+use tracing::{error, info, warn};
+
+/// Outgoing events of a connected client and its connection, shut down on overflow
+type Clients = HashMap<SocketAddr, (Arc<OutboundQueue>, Stream)>;
+
+/// How often shutdown checks whether all clients were flushed
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 struct Server {
     config: ServerConfig,
-}
-
-#[derive(Error, Debug)]
-pub enum AppError {
-    #[error("Configuration error")]
-    ConfigError(),
-
-    #[error("Unable to listen @ `{0}`")]
-    TcpListenerError(String),
-
-    #[error("Error: `{0}`")]
-    OtherError(String),
+    /// rooms, message routing and user accounts
+    state: State,
+    /// connections are plain TCP without TLS configuration
+    tls: Option<Arc<TlsServerConfig>>,
+    metrics: Arc<Metrics>,
 }
 
 fn main() -> Result<()> {
@@ -53,28 +42,105 @@ fn main() -> Result<()> {
 impl Server {
     fn new() -> Result<Self> {
         let config = envy::from_env::<ServerConfig>().context(AppError::ConfigError())?;
-        Ok(Server { config })
+        let users = config.user_store()?;
+        let history = History::open(&config.database)
+            .context(AppError::DatabaseError(config.database.clone()))?;
+        Ok(Server {
+            state: State::new(users, history, config.history_size)?
+                .with_admins(config.admins.clone()),
+            tls: config.tls()?,
+            metrics: Arc::new(Metrics::new()?),
+            config,
+        })
     }
 
-    /// handle client messages and forward them to tx_distributor
-    fn handle_client(&self, tx_distributor: Sender<Message>, mut stream: TcpStream) -> Result<()> {
-        loop {
-            let mut len_bytes = [0u8; 4];
-            stream.read_exact(&mut len_bytes)?;
-            let len = u32::from_be_bytes(len_bytes) as usize;
-
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer)?;
-
-            let msg = Message::from_bytes(&buffer)?;
+    /// handle client commands and forward resulting events to tx_distributor
+    /// every message is stamped with an id, the sender and the time of arrival
+    fn handle_client(
+        &self,
+        tx_distributor: Sender<Delivery>,
+        client_socket: SocketAddr,
+        stream: Stream,
+        queue: Arc<OutboundQueue>,
+    ) -> Result<()> {
+        // commands are logged with the peer address, the hub stamps messages with the username
+        let sender = client_socket.to_string();
+
+        // reads wake up regularly to ping a quiet client and to evict a gone one
+        let mut heartbeat = self.config.heartbeat();
+        stream.set_read_timeout(Some(self.config.read_timeout(&heartbeat)))?;
+        let mut reader = FrameReader::new(stream, self.config.max_frame_size)
+            .with_frame_timeout(self.config.frame_timeout());
+        let mut limiter = self.config.rate_limiter();
+        let mut attachments = self.config.attachment_filter();
+        let mut codec = Codec::Bincode;
+        let mut negotiable = true;
 
-            match &msg {
-                Message::Text(text) => info!(text, "Message"),
-                Message::Image(image, ext, _) => info!(image, ext, "Message"),
-                Message::File(file, _) => info!(file, "Message"),
+        loop {
+            let frame = match reader.read_frame() {
+                Ok(frame) => frame,
+                Err(FrameError::Idle) => {
+                    match heartbeat.poll() {
+                        Beat::Wait => {}
+                        Beat::Ping(id) => {
+                            tx_distributor.send(Delivery::reply(client_socket, Event::Ping(id)))?
+                        }
+                        Beat::Expired => {
+                            return Err(AppError::IdleTimeout(self.config.idle_timeout).into())
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    self.metrics.frame_error(&e);
+                    return Err(e.into());
+                }
             };
+            heartbeat.received();
+
+            // a client may pick another codec with its first frame
+            if std::mem::take(&mut negotiable) {
+                if let Some(picked) = negotiate_codec(&frame, &queue)? {
+                    info!(client = sender, codec = picked.name(), "Codec negotiated");
+                    codec = picked;
+                    continue;
+                }
+            }
+            let command = codec
+                .decode::<Command>(&frame)
+                .inspect_err(|_| self.metrics.decode_error())?;
+            self.metrics.received(&command, frame.len());
+
+            log_command(&sender, &command);
+
+            // refused commands are answered right away, sustained flooding ends the connection
+            let limit = limiter.check(&command, frame.len());
+            if let Limit::Throttled(delay) = limit {
+                log_limit(&sender, &limit);
+                thread::sleep(delay);
+            }
+            if let Some(refusal) = limit.reply(client_socket, &command) {
+                log_limit(&sender, &limit);
+                tx_distributor.send(refusal)?;
+                if limit == Limit::Disconnect {
+                    return Err(AppError::Flooding().into());
+                }
+                continue;
+            }
+
+            // offending files and images are not forwarded
+            let screened = attachments.screen(&command);
+            if screened != Screened::Pass {
+                log_screened(&sender, &screened);
+                if let Some(refusal) = screened.reply(client_socket, &command) {
+                    tx_distributor.send(refusal)?;
+                }
+                continue;
+            }
 
-            tx_distributor.send(msg)?;
+            for delivery in self.state.handle(client_socket, command)? {
+                tx_distributor.send(delivery)?;
+            }
         }
     }
 
@@ -82,28 +148,79 @@ impl Server {
         info!("Hello to the Chat Server!");
         info!(self.config.hostname, "HOSTNAME");
         info!(self.config.port, "PORT");
+        info!(self.config.max_frame_size, "MAX_FRAME_SIZE");
+        info!(self.config.database, "DATABASE");
+        info!(self.config.history_size, "HISTORY_SIZE");
+        info!(self.config.outbound_queue_size, "OUTBOUND_QUEUE_SIZE");
+        info!(self.config.outbound_queue_bytes, "OUTBOUND_QUEUE_BYTES");
+        info!(?self.config.slow_client_policy, "SLOW_CLIENT_POLICY");
+        info!(self.config.shutdown_timeout, "SHUTDOWN_TIMEOUT");
+        info!(self.config.ping_interval, "PING_INTERVAL");
+        info!(self.config.idle_timeout, "IDLE_TIMEOUT");
+        info!(self.config.frame_timeout, "FRAME_TIMEOUT");
+        info!(self.config.rate_messages, "RATE_MESSAGES");
+        info!(self.config.rate_message_burst, "RATE_MESSAGE_BURST");
+        info!(self.config.rate_bytes, "RATE_BYTES");
+        info!(self.config.rate_byte_burst, "RATE_BYTE_BURST");
+        info!(self.config.flood_warnings, "FLOOD_WARNINGS");
+        info!(self.config.mute_duration, "MUTE_DURATION");
+        info!(self.config.flood_mutes, "FLOOD_MUTES");
+        info!(self.config.max_image_size, "MAX_IMAGE_SIZE");
+        info!(self.config.max_image_dimension, "MAX_IMAGE_DIMENSION");
+        info!(self.config.max_file_size, "MAX_FILE_SIZE");
+        info!(self.config.max_transfers, "MAX_TRANSFERS");
+        info!(self.config.transfer_timeout, "TRANSFER_TIMEOUT");
+        info!(?self.config.allowed_types, "ALLOWED_TYPES");
+        info!(?self.config.denied_types, "DENIED_TYPES");
+        info!(?self.config.admins, "ADMINS");
+        info!(self.config.metrics_port, "METRICS_PORT");
+        info!(self.config.metrics_hostname, "METRICS_HOSTNAME");
+        if self.config.ws_port.is_some() {
+            warn!("WS_PORT is ignored, browser clients are served by chat-server-async");
+        }
+        match &self.config.tls_cert {
+            Some(tls_cert) => info!(tls_cert, "TLS_CERT"),
+            None => warn!("TLS is disabled, set TLS_CERT and TLS_KEY to enable it"),
+        }
 
         let server_addr = format!("{}:{}", self.config.hostname, self.config.port);
         let listener = TcpListener::bind(server_addr.clone())
             .context(AppError::TcpListenerError(server_addr))?;
 
-        let clients: Arc<Mutex<HashMap<SocketAddr, TcpStream>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let listener_addr = listener.local_addr()?;
 
-        thread::scope(|scope| loop {
+        if let Some(metrics_addr) = self.config.metrics_addr() {
+            self.metrics.clone().serve(metrics_addr)?;
+        }
+
+        let clients: Arc<Mutex<Clients>> = Arc::new(Mutex::new(HashMap::new()));
+
+        // SIGINT or SIGTERM stops accepting new connections
+        let (tx_signal, rx_signal) = channel::<()>();
+        ctrlc::set_handler(move || _ = tx_signal.send(())).context(AppError::SignalError())?;
+        let shutting_down = AtomicBool::new(false);
+
+        thread::scope(|scope| {
             let (tx_deregister, rx_deregister) = channel::<SocketAddr>();
-            let (tx_distributor, rx_distributor) = channel::<Message>();
+            let (tx_distributor, rx_distributor) = channel::<Delivery>();
 
             // deregister thread
             let clients_deregister = clients.clone();
+            let tx_presence = tx_distributor.clone();
             scope.spawn(move || {
                 for socket_addr in rx_deregister.iter() {
                     // handler ended
-                    // remove this client
+                    // remove this client and tell the others it went offline
+                    for delivery in self.state.disconnect(socket_addr) {
+                        _ = tx_presence.send(delivery);
+                    }
                     if let Ok(mut guard) = clients_deregister.lock() {
-                        guard.remove(&socket_addr);
+                        if let Some((queue, _)) = guard.remove(&socket_addr) {
+                            queue.close();
+                        }
                         let count = guard.len();
                         info!(count, "Number of connected clients changed");
+                        self.metrics.connected(count);
                     }
                 }
             });
@@ -111,22 +228,36 @@ impl Server {
             // distributor thread
             let clients_distributor = clients.clone();
             scope.spawn(move || {
-                for msg in rx_distributor.iter() {
+                for delivery in rx_distributor.iter() {
                     // handler ended
                     let handler = || -> Result<()> {
-                        let mut guard = clients_distributor.lock().map_err(|_| {
+                        let guard = clients_distributor.lock().map_err(|_| {
                             AppError::OtherError("Unable to lock client list".to_owned())
                         })?;
-                        let bytes = msg.encode()?;
-                        let len = bytes.len() as u32;
-
-                        for stream in guard.values_mut() {
-                            // send message
-                            stream.write_all(&len.to_be_bytes())?;
-                            stream.write_all(&bytes)?;
-                        }
 
-                        Ok(())
+                        // queueing never blocks, a slow client does not stop the others
+                        queue_deliveries(
+                            vec![delivery],
+                            &self.metrics,
+                            |client_socket, frames| {
+                                let Some((queue, stream)) = guard.get(&client_socket) else {
+                                    return Ok(false);
+                                };
+                                let queued = queue.push_event(frames)?;
+                                self.metrics.queued(queue.len());
+                                if queued == Queued::Overflow {
+                                    // the writer may be stuck in a write, its handler ends too
+                                    _ = stream.shutdown();
+                                }
+                                Ok(queued.is_queued())
+                            },
+                            // the writer shuts a kicked client down once flushed
+                            |client_socket| {
+                                if let Some((queue, _)) = guard.get(&client_socket) {
+                                    queue.close();
+                                }
+                            },
+                        )
                     };
 
                     if let Err(e) = handler() {
@@ -135,8 +266,23 @@ impl Server {
                 }
             });
 
+            // signal thread
+            let shutting_down = &shutting_down;
+            scope.spawn(move || {
+                if rx_signal.recv().is_ok() {
+                    info!("Shutting down");
+                    shutting_down.store(true, Ordering::Relaxed);
+                    // wake the listener waiting for a connection
+                    _ = TcpStream::connect(listener_addr);
+                }
+            });
+
             // listen new connections
             for stream in listener.incoming() {
+                if shutting_down.load(Ordering::Relaxed) {
+                    break;
+                }
+
                 let tx_deregister = tx_deregister.clone();
                 let tx_distributor = tx_distributor.clone();
 
@@ -144,18 +290,44 @@ impl Server {
                 let handler = || -> Result<()> {
                     let stream = stream?;
                     let client_socket = stream.peer_addr()?;
+                    // the TLS handshake completes in the client handler on first read
+                    let stream = match &self.tls {
+                        Some(tls) => Stream::Tls(TlsStream::accept(stream, tls.clone())?),
+                        None => Stream::Plain(stream),
+                    };
 
                     // remember new client
+                    let queue = Arc::new(self.config.outbound_queue(client_socket));
                     let mut guard = clients.lock().map_err(|_| {
                         AppError::OtherError("Unable to lock client list".to_owned())
                     })?;
-                    guard.insert(client_socket, stream.try_clone()?);
+                    guard.insert(client_socket, (queue.clone(), stream.try_clone()?));
                     let count = guard.len();
                     info!(count, "Number of connected clients changed");
+                    self.metrics.connected(count);
+
+                    // spawn client writer, it ends once the client is deregistered
+                    let mut writer =
+                        FrameWriter::new(stream.try_clone()?, self.config.max_frame_size);
+                    let connection = stream.try_clone()?;
+                    let frames = queue.clone();
+                    scope.spawn(move || {
+                        while let Some(frame) = frames.pop_blocking() {
+                            if let Err(e) = writer.write_frame(&frame) {
+                                error!("{}: {}", client_socket, e);
+                                break;
+                            }
+                        }
+                        // wake the client handler when the client can not be written to
+                        frames.close();
+                        _ = connection.shutdown();
+                    });
 
                     // spawn client handler
                     scope.spawn(move || {
-                        _ = self.handle_client(tx_distributor, stream);
+                        let handled =
+                            self.handle_client(tx_distributor, client_socket, stream, queue);
+                        log_disconnect(client_socket, &handled);
                         _ = tx_deregister.send(client_socket);
                     });
 
@@ -167,6 +339,39 @@ impl Server {
                     error!("{}", e);
                 }
             }
+
+            // remaining threads end once all client handlers do
+            self.shutdown(&clients)
         })
     }
+
+    /// send shutdown notice to all clients and wait until their queues are flushed
+    fn shutdown(&self, clients: &Mutex<Clients>) -> Result<()> {
+        let notice = Event::Shutdown(SHUTDOWN_NOTICE.to_owned());
+        let mut frames = EventFrames::new(&notice);
+        if let Ok(guard) = clients.lock() {
+            for (queue, _) in guard.values() {
+                queue.push_event(&mut frames)?;
+                queue.close();
+            }
+        }
+
+        // writers close flushed connections, handlers deregister their clients then
+        let deadline = Instant::now() + Duration::from_secs(self.config.shutdown_timeout);
+        while Instant::now() < deadline {
+            if clients.lock().map(|guard| guard.is_empty()).unwrap_or(true) {
+                return Ok(());
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        if let Ok(guard) = clients.lock() {
+            let count = guard.len();
+            warn!(count, "Closing connections not flushed in time");
+            for (_, stream) in guard.values() {
+                _ = stream.shutdown();
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use chatlib::{Command, Envelope, Event, FrameError, Message};
+use chrono::Utc;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder, TEXT_FORMAT,
+};
+use tiny_http::{Header, Response, Server};
+use tracing::{error, info};
+
+use crate::AppError;
+
+/// Buckets of the outbound queue depth in frames
+const QUEUE_DEPTH_BUCKETS: [f64; 10] = [0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+/// Buckets of the broadcast latency in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Prometheus metrics of a server
+/// commands and events are counted by type, messages by the type of their content
+pub struct Metrics {
+    registry: Registry,
+    connected_clients: IntGauge,
+    commands: IntCounterVec,
+    received_bytes: IntCounterVec,
+    events: IntCounterVec,
+    sent_bytes: IntCounterVec,
+    frame_errors: IntCounterVec,
+    queue_depth: Histogram,
+    broadcast_latency: Histogram,
+}
+
+impl Metrics {
+    /// initialize new instance with all metrics at zero
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("chat".to_owned()), None)?;
+
+        let connected_clients = IntGauge::new("connected_clients", "Connected clients")?;
+        let commands = IntCounterVec::new(
+            Opts::new("commands_received_total", "Commands received by type"),
+            &["type"],
+        )?;
+        let received_bytes = IntCounterVec::new(
+            Opts::new("received_bytes_total", "Bytes of commands received by type"),
+            &["type"],
+        )?;
+        let events = IntCounterVec::new(
+            Opts::new("events_sent_total", "Events queued for clients by type"),
+            &["type"],
+        )?;
+        let sent_bytes = IntCounterVec::new(
+            Opts::new(
+                "sent_bytes_total",
+                "Bytes of events queued for clients by type",
+            ),
+            &["type"],
+        )?;
+        let frame_errors = IntCounterVec::new(
+            Opts::new("frame_errors_total", "Frames not decoded by reason"),
+            &["reason"],
+        )?;
+        let queue_depth = Histogram::with_opts(
+            HistogramOpts::new(
+                "outbound_queue_depth",
+                "Frames waiting in a client queue after queueing another one",
+            )
+            .buckets(QUEUE_DEPTH_BUCKETS.to_vec()),
+        )?;
+        let broadcast_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "broadcast_latency_seconds",
+                "Time from stamping a message until it is queued for all recipients",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+
+        registry.register(Box::new(connected_clients.clone()))?;
+        registry.register(Box::new(commands.clone()))?;
+        registry.register(Box::new(received_bytes.clone()))?;
+        registry.register(Box::new(events.clone()))?;
+        registry.register(Box::new(sent_bytes.clone()))?;
+        registry.register(Box::new(frame_errors.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(broadcast_latency.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            connected_clients,
+            commands,
+            received_bytes,
+            events,
+            sent_bytes,
+            frame_errors,
+            queue_depth,
+            broadcast_latency,
+        })
+    }
+
+    /// number of connected clients changed
+    pub fn connected(&self, count: usize) {
+        self.connected_clients.set(count as i64);
+    }
+
+    /// command received in a frame of len bytes
+    pub fn received(&self, command: &Command, len: usize) {
+        let kind = command_type(command);
+        self.commands.with_label_values(&[kind]).inc();
+        self.received_bytes
+            .with_label_values(&[kind])
+            .inc_by(len as u64);
+    }
+
+    /// event queued for a number of recipients in a frame of len bytes
+    pub fn sent(&self, event: &Event, len: usize, recipients: usize) {
+        let kind = event_type(event);
+        self.events
+            .with_label_values(&[kind])
+            .inc_by(recipients as u64);
+        self.sent_bytes
+            .with_label_values(&[kind])
+            .inc_by((len * recipients) as u64);
+    }
+
+    /// frame failed to read, a peer closing the connection or staying idle is no error
+    pub fn frame_error(&self, e: &FrameError) {
+        let reason = match e {
+            FrameError::Closed | FrameError::Idle | FrameError::Io(_) => return,
+            FrameError::Oversized { .. } => "oversized",
+            FrameError::Truncated { .. } => "truncated",
+            FrameError::FrameTimeout(_) => "timeout",
+        };
+        self.frame_errors.with_label_values(&[reason]).inc();
+    }
+
+    /// frame not decoded into a command
+    pub fn decode_error(&self) {
+        self.frame_errors.with_label_values(&["decode"]).inc();
+    }
+
+    /// frames waiting in a client queue
+    pub fn queued(&self, depth: usize) {
+        self.queue_depth.observe(depth as f64);
+    }
+
+    /// message queued for all of its recipients
+    pub fn broadcast(&self, envelope: &Envelope) {
+        let latency = (Utc::now() - envelope.timestamp)
+            .to_std()
+            .unwrap_or_default();
+        self.broadcast_latency.observe(latency.as_secs_f64());
+    }
+
+    /// all metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+
+    /// serve the metrics at http://addr/metrics on a thread of its own
+    pub fn serve(self: Arc<Self>, addr: String) -> Result<()> {
+        let server = Server::http(&addr)
+            .map_err(|e| AppError::OtherError(e.to_string()))
+            .context(AppError::TcpListenerError(addr.clone()))?;
+        let content_type = Header::from_bytes("Content-Type", TEXT_FORMAT)
+            .map_err(|_| AppError::OtherError("Invalid content type".to_owned()))?;
+        info!(addr, "Serving metrics");
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match (request.method(), request.url()) {
+                    (tiny_http::Method::Get, "/metrics") => match self.encode() {
+                        Ok(metrics) => {
+                            Response::from_string(metrics).with_header(content_type.clone())
+                        }
+                        Err(e) => {
+                            error!("Unable to encode metrics: {:#}", e);
+                            Response::from_string("").with_status_code(500)
+                        }
+                    },
+                    _ => Response::from_string("Not found").with_status_code(404),
+                };
+                if let Err(e) = request.respond(response) {
+                    error!("Unable to respond to metrics request: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// label of a received command
+fn command_type(command: &Command) -> &'static str {
+    match command {
+        Command::Login(..) => "login",
+        Command::Register(..) => "register",
+        Command::Nick(_) => "nick",
+        Command::Send(_, _, message) => message_type(message),
+        Command::Join(_) => "join",
+        Command::Leave(_) => "leave",
+        Command::Rooms => "rooms",
+        Command::Who => "who",
+        Command::History(..) => "history",
+        Command::PublishKey(_) => "publish_key",
+        Command::GetKey(_) => "get_key",
+        Command::Moderate(_) => "moderate",
+        Command::Ping(_) => "ping",
+        Command::Pong(_) => "pong",
+    }
+}
+
+/// label of a sent event
+fn event_type(event: &Event) -> &'static str {
+    match event {
+        Event::Welcome(_) => "welcome",
+        Event::Renamed(..) => "renamed",
+        Event::Message(envelope) => message_type(&envelope.message),
+        Event::Ack(..) => "ack",
+        Event::Joined(_) => "joined",
+        Event::Left(_) => "left",
+        Event::Rooms(_) => "rooms",
+        Event::Presence(..) => "presence",
+        Event::Users(_) => "users",
+        Event::History(..) => "history",
+        Event::IdentityKey(..) => "identity_key",
+        Event::Sanctioned(..) => "sanctioned",
+        Event::Shutdown(_) => "shutdown",
+        Event::Ping(_) => "ping",
+        Event::Pong(_) => "pong",
+        Event::Error(_) => "error",
+    }
+}
+
+/// label of a message sent or distributed
+fn message_type(message: &Message) -> &'static str {
+    match message {
+        Message::Text(_) => "message_text",
+        Message::Image(..) => "message_image",
+        Message::File(..) => "message_file",
+        Message::FileBegin(..) => "message_file_begin",
+        Message::FileChunk(..) => "message_file_chunk",
+        Message::FileEnd(_) => "message_file_end",
+        Message::Sealed(_) => "message_sealed",
+    }
+}
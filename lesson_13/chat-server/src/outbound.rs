@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use anyhow::Result;
+use chatlib::{Codec, Event};
+use serde::Deserialize;
+use tokio::sync::{watch, Notify};
+use tracing::warn;
+
+use crate::{Delivery, Metrics};
+
+/// Encoded event shared by all recipients
+pub type Frame = Arc<Vec<u8>>;
+
+/// Event encoded at most once per codec, recipients speaking the same codec share the frame
+pub struct EventFrames<'a> {
+    event: &'a Event,
+    frames: Vec<(Codec, Frame)>,
+    /// bytes queued for all recipients so far
+    queued_bytes: usize,
+}
+
+/// What happens when a client does not read its events fast enough
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowClientPolicy {
+    /// the oldest queued events are dropped, chunked file transfers may arrive incomplete
+    DropOldest,
+    /// the client is disconnected
+    Disconnect,
+}
+
+/// Result of queueing a frame
+#[derive(Debug, Eq, PartialEq)]
+pub enum Queued {
+    Queued,
+    /// the queue was full and its oldest frame dropped
+    DroppedOldest,
+    /// the queue was full and is closed now, the client has to be disconnected
+    Overflow,
+    /// the client is already disconnected
+    Closed,
+}
+
+impl Queued {
+    /// whether the frame is going to be written
+    pub fn is_queued(&self) -> bool {
+        matches!(self, Queued::Queued | Queued::DroppedOldest)
+    }
+}
+
+/// queue events with push for every recipient, each event is encoded once per codec
+/// push tells whether the frame was queued, senders of messages are told the count
+/// recipients to be disconnected are closed after the frame, their writers end once flushed
+pub fn queue_deliveries(
+    deliveries: Vec<Delivery>,
+    metrics: &Metrics,
+    mut push: impl FnMut(SocketAddr, &mut EventFrames) -> Result<bool>,
+    mut close: impl FnMut(SocketAddr),
+) -> Result<()> {
+    for delivery in deliveries {
+        let mut frames = EventFrames::new(&delivery.event);
+        let mut queued = Vec::new();
+        for client in delivery.recipients.iter().copied() {
+            if push(client, &mut frames)? {
+                queued.push(client);
+            }
+        }
+        metrics.sent(&delivery.event, frames.queued_bytes, queued.len());
+        if let Event::Message(envelope) = &delivery.event {
+            metrics.broadcast(envelope);
+        }
+        if delivery.disconnect {
+            delivery.recipients.iter().copied().for_each(&mut close);
+        }
+
+        if let Some(ack) = delivery.delivered(&queued) {
+            let mut frames = EventFrames::new(&ack.event);
+            let mut queued = 0;
+            for client in ack.recipients.iter().copied() {
+                if push(client, &mut frames)? {
+                    queued += 1;
+                }
+            }
+            metrics.sent(&ack.event, frames.queued_bytes, queued);
+        }
+    }
+    Ok(())
+}
+
+/// answer the codec offer a client may send as its first frame, none when the frame is no offer
+/// the answer is queued ahead of any event in the codec picked
+pub fn negotiate_codec(frame: &[u8], queue: &OutboundQueue) -> Result<Option<Codec>> {
+    let Some(offered) = Codec::parse_offer(frame) else {
+        return Ok(None);
+    };
+    let codec = Codec::negotiate(&offered?)?;
+    queue.negotiated(codec);
+    Ok(Some(codec))
+}
+
+impl<'a> EventFrames<'a> {
+    /// event not encoded yet
+    pub fn new(event: &'a Event) -> Self {
+        EventFrames {
+            event,
+            frames: Vec::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    /// event encoded in the codec, encoded on first use only
+    pub fn frame(&mut self, codec: Codec) -> Result<Frame> {
+        if let Some((_, frame)) = self.frames.iter().find(|(c, _)| *c == codec) {
+            return Ok(frame.clone());
+        }
+        let frame = Arc::new(codec.encode(self.event)?);
+        self.frames.push((codec, frame.clone()));
+        Ok(frame)
+    }
+}
+
+/// Bounded queue of frames waiting to be written to a single client
+/// frames are encoded in the codec of the client, bincode until another one is negotiated
+/// queueing never blocks, a queue full of frames or bytes is handled by the slow client policy
+/// the writer waits for frames either on a thread or in a tokio task
+pub struct OutboundQueue {
+    client: SocketAddr,
+    capacity: usize,
+    /// most bytes queued, a single frame is queued regardless of its size
+    max_bytes: usize,
+    policy: SlowClientPolicy,
+    state: Mutex<QueueState>,
+    /// wakes a writer thread
+    ready: Condvar,
+    /// wakes a writer task
+    notify: Notify,
+    /// tells tasks the queue overflowed
+    overflowed: watch::Sender<bool>,
+}
+
+struct QueueState {
+    frames: VecDeque<Frame>,
+    /// bytes of the queued frames
+    bytes: usize,
+    codec: Codec,
+    closed: bool,
+    /// frames dropped since the writer last caught up
+    dropped: usize,
+}
+
+impl OutboundQueue {
+    /// initialize new empty queue holding at most capacity frames
+    pub fn new(client: SocketAddr, capacity: usize, policy: SlowClientPolicy) -> Self {
+        OutboundQueue {
+            client,
+            capacity: capacity.max(1),
+            max_bytes: usize::MAX,
+            policy,
+            state: Mutex::new(QueueState {
+                frames: VecDeque::new(),
+                bytes: 0,
+                codec: Codec::Bincode,
+                closed: false,
+                dropped: 0,
+            }),
+            ready: Condvar::new(),
+            notify: Notify::new(),
+            overflowed: watch::Sender::new(false),
+        }
+    }
+
+    /// hold at most max_bytes of frames, a single frame is queued regardless of its size
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// encode frames in the codec from the start, for clients not negotiating one
+    pub fn with_codec(self, codec: Codec) -> Self {
+        self.state().codec = codec;
+        self
+    }
+
+    /// lock queue state, the state stays consistent even if a holder panicked
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// queue frame for the writer, it has to be encoded in the codec of the client
+    pub fn push(&self, frame: Frame) -> Queued {
+        let state = self.state();
+        self.push_locked(state, frame)
+    }
+
+    /// queue event for the writer encoded in the codec of the client
+    pub fn push_event(&self, frames: &mut EventFrames) -> Result<Queued> {
+        let state = self.state();
+        if state.closed {
+            return Ok(Queued::Closed);
+        }
+        // encoded under the lock, the codec does not change meanwhile
+        let frame = frames.frame(state.codec)?;
+        let len = frame.len();
+        let queued = self.push_locked(state, frame);
+        if queued.is_queued() {
+            frames.queued_bytes += len;
+        }
+        Ok(queued)
+    }
+
+    /// queue the answer to the codec offer, later frames are encoded in the codec picked
+    pub fn negotiated(&self, codec: Codec) -> Queued {
+        let mut state = self.state();
+        state.codec = codec;
+        self.push_locked(state, Arc::new(codec.answer()))
+    }
+
+    /// codec frames are encoded in
+    pub fn codec(&self) -> Codec {
+        self.state().codec
+    }
+
+    fn push_locked(&self, mut state: MutexGuard<'_, QueueState>, frame: Frame) -> Queued {
+        if state.closed {
+            return Queued::Closed;
+        }
+
+        let mut queued = Queued::Queued;
+        while !state.frames.is_empty()
+            && (state.frames.len() >= self.capacity || state.bytes + frame.len() > self.max_bytes)
+        {
+            match self.policy {
+                SlowClientPolicy::DropOldest => {
+                    if let Some(dropped) = state.frames.pop_front() {
+                        state.bytes -= dropped.len();
+                    }
+                    state.dropped += 1;
+                    if state.dropped == 1 {
+                        warn!(client = %self.client, "Slow client, dropping oldest events");
+                    }
+                    queued = Queued::DroppedOldest;
+                }
+                SlowClientPolicy::Disconnect => {
+                    warn!(client = %self.client, "Slow client, disconnecting");
+                    state.frames.clear();
+                    state.bytes = 0;
+                    drop(state);
+                    self.overflowed.send_replace(true);
+                    self.close();
+                    return Queued::Overflow;
+                }
+            }
+        }
+
+        state.bytes += frame.len();
+        state.frames.push_back(frame);
+        drop(state);
+        self.wake();
+        queued
+    }
+
+    /// stop accepting frames, writers end once the queue is empty
+    pub fn close(&self) {
+        self.state().closed = true;
+        self.wake();
+    }
+
+    /// wait until the queue overflows and the client has to be disconnected
+    pub async fn overflowed(&self) {
+        _ = self
+            .overflowed
+            .subscribe()
+            .wait_for(|overflowed| *overflowed)
+            .await;
+    }
+
+    /// whether the queue was closed
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    /// number of frames waiting
+    pub fn len(&self) -> usize {
+        self.state().frames.len()
+    }
+
+    /// bytes of the frames waiting
+    pub fn bytes(&self) -> usize {
+        self.state().bytes
+    }
+
+    /// whether no frame is waiting
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// next frame, blocks the thread while the queue is empty
+    /// None once the queue is closed and empty
+    pub fn pop_blocking(&self) -> Option<Frame> {
+        let mut state = self.state();
+        loop {
+            if let Some(frame) = self.take(&mut state) {
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// next frame, waits without blocking the tokio worker while the queue is empty
+    /// None once the queue is closed and empty
+    pub async fn pop(&self) -> Option<Frame> {
+        loop {
+            {
+                let mut state = self.state();
+                if let Some(frame) = self.take(&mut state) {
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // a push in between stores a permit, the wake up is not lost
+            self.notify.notified().await;
+        }
+    }
+
+    /// take the oldest frame, a drained queue means the writer caught up
+    fn take(&self, state: &mut QueueState) -> Option<Frame> {
+        let frame = state.frames.pop_front()?;
+        state.bytes -= frame.len();
+        if state.frames.is_empty() && state.dropped > 0 {
+            warn!(client = %self.client, dropped = state.dropped, "Slow client caught up");
+            state.dropped = 0;
+        }
+        Some(frame)
+    }
+
+    fn wake(&self) {
+        self.ready.notify_all();
+        self.notify.notify_one();
+    }
+}
@@ -0,0 +1,221 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use chatlib::{Command, Event, Message};
+
+use crate::Delivery;
+
+/// Frames at least this large are file chunks or attachments, also when they are sealed
+const BULK_FRAME_SIZE: usize = 256 * 1024;
+
+/// Tokens refilling at a steady rate up to a burst
+#[derive(Debug)]
+struct TokenBucket {
+    /// tokens per second, zero disables the limit
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// initialize new full bucket
+    fn new(rate: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// whether the amount can be taken now
+    /// a full bucket covers any amount, the debt has to be refilled before the next one
+    fn available(&mut self, amount: u64, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        self.tokens >= (amount as f64).min(self.burst)
+    }
+
+    /// how long until the amount can be taken
+    fn delay(&mut self, amount: u64, now: Instant) -> Duration {
+        if self.available(amount, now) {
+            return Duration::ZERO;
+        }
+        let missing = (amount as f64).min(self.burst) - self.tokens;
+        Duration::from_secs_f64(missing / self.rate)
+    }
+
+    fn take(&mut self, amount: u64) {
+        if self.rate != 0.0 {
+            self.tokens -= amount as f64;
+        }
+    }
+}
+
+/// Limits applied to every connection
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// commands per second and how many may arrive at once
+    pub messages: (u64, u64),
+    /// bytes per second and how many may arrive at once
+    pub bytes: (u64, u64),
+    /// commands over the limit before the client is muted
+    pub warnings: u32,
+    pub mute_duration: Duration,
+    /// mutes before the client is disconnected
+    pub mutes: u32,
+}
+
+/// What happens to a command
+#[derive(Debug, Eq, PartialEq)]
+pub enum Limit {
+    Allowed,
+    /// over the limit, the command is refused with a warning
+    Warned,
+    /// allowed once the client waited, its socket is not read meanwhile
+    Throttled(Duration),
+    /// refused for the remaining time of a mute
+    Muted(Duration),
+    /// muted too often, the client has to be disconnected
+    Disconnect,
+}
+
+/// Flood protection of a single connection
+/// commands over the limit are refused, repeated abuse mutes the client and finally disconnects it
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    mutes: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// initialize new instance with full buckets
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(limits.messages.0, limits.messages.1),
+            bytes: TokenBucket::new(limits.bytes.0, limits.bytes.1),
+            limits,
+            violations: 0,
+            mutes: 0,
+            last_violation: None,
+            muted_until: None,
+        }
+    }
+
+    /// check a command received in a frame of len bytes
+    /// file chunks and other bulk payloads count as bytes only, sealed ones are told by their size
+    /// those over the byte limit are throttled, a transfer admitted by its begin is not cut short
+    pub fn check(&mut self, command: &Command, len: usize) -> Limit {
+        match command {
+            Command::Ping(_) | Command::Pong(_) => self.heartbeat_at(Instant::now(), len as u64),
+            Command::Send(_, _, Message::FileChunk(..)) => {
+                self.throttle_at(Instant::now(), len as u64)
+            }
+            Command::Send(..) if len >= BULK_FRAME_SIZE => {
+                self.throttle_at(Instant::now(), len as u64)
+            }
+            _ => self.check_at(Instant::now(), 1, len as u64),
+        }
+    }
+
+    /// heartbeats within the limit pass a mute, a muted client is not dropped as idle
+    fn heartbeat_at(&mut self, now: Instant, bytes: u64) -> Limit {
+        if self.messages.available(1, now) && self.bytes.available(bytes, now) {
+            self.messages.take(1);
+            self.bytes.take(bytes);
+            return Limit::Allowed;
+        }
+        self.check_at(now, 1, bytes)
+    }
+
+    /// remaining time of a mute
+    fn muted(&self, now: Instant) -> Option<Duration> {
+        self.muted_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn throttle_at(&mut self, now: Instant, bytes: u64) -> Limit {
+        if let Some(remaining) = self.muted(now) {
+            return Limit::Muted(remaining);
+        }
+
+        // the bytes are taken right away, following chunks wait for the debt as well
+        let delay = self.bytes.delay(bytes, now);
+        self.bytes.take(bytes);
+        match delay.is_zero() {
+            true => Limit::Allowed,
+            false => Limit::Throttled(delay),
+        }
+    }
+
+    fn check_at(&mut self, now: Instant, messages: u64, bytes: u64) -> Limit {
+        let muted = self.muted(now);
+
+        // both buckets are drawn from while muted, flooding through a mute does not pay off
+        if self.messages.available(messages, now) && self.bytes.available(bytes, now) {
+            self.messages.take(messages);
+            self.bytes.take(bytes);
+            return match muted {
+                Some(remaining) => Limit::Muted(remaining),
+                None => Limit::Allowed,
+            };
+        }
+
+        // violations far apart do not add up
+        let forgiven = self
+            .last_violation
+            .is_some_and(|at| now.saturating_duration_since(at) >= self.limits.mute_duration);
+        if forgiven && muted.is_none() {
+            self.violations = 0;
+            self.mutes = 0;
+        }
+        self.last_violation = Some(now);
+        self.violations += 1;
+
+        if self.violations <= self.limits.warnings {
+            return match muted {
+                Some(remaining) => Limit::Muted(remaining),
+                None => Limit::Warned,
+            };
+        }
+
+        self.violations = 0;
+        self.mutes += 1;
+        if self.mutes > self.limits.mutes {
+            return Limit::Disconnect;
+        }
+        self.muted_until = Some(now + self.limits.mute_duration);
+        Limit::Muted(self.limits.mute_duration)
+    }
+}
+
+impl Limit {
+    /// refusal of the command, messages are rejected under their id
+    pub fn reply(&self, client: SocketAddr, command: &Command) -> Option<Delivery> {
+        let reason = match self {
+            Limit::Allowed | Limit::Throttled(_) => return None,
+            Limit::Warned => "Rate limit exceeded, slow down".to_owned(),
+            Limit::Muted(remaining) => format!(
+                "Muted for flooding, {} seconds left",
+                remaining.as_secs_f64().ceil()
+            ),
+            Limit::Disconnect => "Disconnected for flooding".to_owned(),
+        };
+        Some(match command {
+            Command::Send(id, ..) => Delivery::rejected(client, *id, reason),
+            _ => Delivery::reply(client, Event::Error(reason)),
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Result;
+use chatlib::{Command, Event, HistoryEntry, Sanction, LOBBY};
+use tracing::{error, info};
+
+use crate::{AppError, AuthError, Delivery, History, Hub, UserStore};
+
+/// Routing state, user accounts and message history shared by all client handlers
+/// credentials are checked before the hub is locked, password hashing takes a while
+pub struct State {
+    hub: Mutex<Hub>,
+    users: UserStore,
+    history: History,
+    /// number of messages replayed when a room is entered
+    replay: usize,
+}
+
+impl State {
+    /// initialize new instance with an empty hub, message ids continue after the stored history
+    pub fn new(users: UserStore, history: History, replay: usize) -> Result<Self> {
+        Ok(State {
+            hub: Mutex::new(Hub::with_message_id(history.last_id()?)),
+            users,
+            history,
+            replay,
+        })
+    }
+
+    /// allow the usernames to moderate, nobody can register or rename to them
+    pub fn with_admins(self, admins: Vec<String>) -> Self {
+        let hub = self.hub.into_inner().unwrap_or_else(|e| e.into_inner());
+        State {
+            hub: Mutex::new(hub.with_admins(admins)),
+            ..self
+        }
+    }
+
+    /// lock routing state
+    fn hub(&self) -> Result<MutexGuard<'_, Hub>> {
+        let guard = self
+            .hub
+            .lock()
+            .map_err(|_| AppError::OtherError("Unable to lock hub".to_owned()))?;
+        Ok(guard)
+    }
+
+    /// process client command and return events to distribute
+    /// blocks while a password is hashed or verified
+    pub fn handle(&self, client: SocketAddr, command: Command) -> Result<Vec<Delivery>> {
+        let username = self.hub()?.username(client).map(str::to_owned);
+
+        let result = match (&username, &command) {
+            (None, Command::Register(name, _)) | (Some(_), Command::Nick(name))
+                if self.hub()?.is_admin(name) =>
+            {
+                return Ok(vec![Delivery::error(
+                    client,
+                    format!("Username `{}` is reserved", name),
+                )]);
+            }
+            (None, Command::Register(name, password)) => self
+                .users
+                .check_ban(&client.ip().to_string())
+                .and_then(|_| self.users.register(name, password)),
+            // admins sharing a banned address can still log in and lift the ban
+            (None, Command::Login(name, password)) if self.hub()?.is_admin(name) => {
+                self.users.verify(name, password)
+            }
+            (None, Command::Login(name, password)) => self
+                .users
+                .check_ban(&client.ip().to_string())
+                .and_then(|_| self.users.verify(name, password)),
+            (Some(old), Command::Nick(name)) => self.users.rename(old, name),
+            // bans outlive the connections, they are stored before the hub applies them
+            (Some(admin), Command::Moderate(sanction)) => {
+                if let Err(e) = self.hub()?.authorize(client, sanction) {
+                    return Ok(vec![Delivery::error(client, e)]);
+                }
+                match sanction {
+                    Sanction::Ban(target, reason) => self.users.ban(target, reason, admin),
+                    Sanction::Unban(target) => self.users.unban(target),
+                    Sanction::Kick(..) | Sanction::Mute(..) => Ok(()),
+                }
+            }
+            (Some(_), Command::History(room, before, limit)) => {
+                return self.history_page(client, room, *before, *limit)
+            }
+            _ => return Ok(self.record(client, self.hub()?.handle(client, command))),
+        };
+
+        if let Err(e) = result {
+            if let AuthError::StoreError(_) = e {
+                error!("{}", e);
+            }
+            return Ok(vec![Delivery::error(client, e.to_string())]);
+        }
+
+        let deliveries = match command {
+            Command::Register(name, _) | Command::Login(name, _) => {
+                info!(client = client.to_string(), name, "Authenticated");
+                self.hub()?.login(client, name)
+            }
+            command => self.hub()?.handle(client, command),
+        };
+        Ok(self.record(client, deliveries))
+    }
+
+    /// store distributed room messages and replay recent history of rooms the client entered
+    fn record(&self, client: SocketAddr, mut deliveries: Vec<Delivery>) -> Vec<Delivery> {
+        let mut entered = Vec::new();
+        let mut unstored_id = None;
+
+        for delivery in &deliveries {
+            match &delivery.event {
+                Event::Message(envelope) => match HistoryEntry::from_envelope(envelope) {
+                    // history is best effort, the message is delivered anyway
+                    Some(entry) => {
+                        if let Err(e) = self.history.append(&entry) {
+                            error!("Unable to store message {}: {:#}", entry.id, e);
+                        }
+                    }
+                    // recipients and the sender get copies of the same message
+                    None => unstored_id = unstored_id.max(Some(envelope.id)),
+                },
+                Event::Welcome(_) => entered.push(LOBBY.to_owned()),
+                Event::Joined(room) => entered.push(room.clone()),
+                _ => {}
+            }
+        }
+
+        // ids of messages not stored, like direct messages and file chunks, are not reused after a restart
+        if let Some(id) = unstored_id {
+            if let Err(e) = self.history.record_id(id) {
+                error!("Unable to record message id {}: {:#}", id, e);
+            }
+        }
+
+        // replay is best effort as well, the client is logged in or joined already
+        for room in entered {
+            match self.history.page(&room, None, self.replay) {
+                Ok(entries) => {
+                    deliveries.push(Delivery::reply(client, Event::History(room, entries)))
+                }
+                Err(e) => error!(room, "Unable to replay history: {:#}", e),
+            }
+        }
+        deliveries
+    }
+
+    /// page of older messages of a room the client is a member of
+    fn history_page(
+        &self,
+        client: SocketAddr,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Delivery>> {
+        if !self.hub()?.is_member(client, room) {
+            return Ok(vec![Delivery::error(
+                client,
+                format!("You are not a member of room `{}`", room),
+            )]);
+        }
+
+        let entries = self.history.page(room, before, limit)?;
+        Ok(vec![Delivery::reply(
+            client,
+            Event::History(room.to_owned(), entries),
+        )])
+    }
+
+    /// remove client from all rooms and release its username, returns presence events
+    pub fn disconnect(&self, client: SocketAddr) -> Vec<Delivery> {
+        match self.hub() {
+            Ok(mut hub) => hub.disconnect(client),
+            Err(_) => vec![],
+        }
+    }
+}
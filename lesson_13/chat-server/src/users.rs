@@ -0,0 +1,246 @@
+use std::sync::{Mutex, MutexGuard};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::Utc;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use thiserror::Error;
+
+use crate::hub::valid_username;
+
+/// Shortest accepted password
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum AuthError {
+    #[error("Invalid username `{0}`")]
+    InvalidUsername(String),
+
+    #[error("Password has to be at least {MIN_PASSWORD_LEN} characters long")]
+    WeakPassword,
+
+    #[error("Username `{0}` is already taken")]
+    UsernameTaken(String),
+
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+
+    #[error("`{0}` is banned")]
+    Banned(String),
+
+    #[error("`{0}` is not banned")]
+    NotBanned(String),
+
+    #[error("User store error: {0}")]
+    StoreError(String),
+}
+
+/// Accounts with argon2 hashed passwords and bans kept in a SQLite database
+/// hashing runs outside of the database lock, it is deliberately slow
+pub struct UserStore {
+    connection: Mutex<Connection>,
+    /// hash of a random password verified against for unknown users
+    dummy_hash: String,
+}
+
+impl From<rusqlite::Error> for AuthError {
+    fn from(e: rusqlite::Error) -> Self {
+        AuthError::StoreError(e.to_string())
+    }
+}
+
+impl From<argon2::password_hash::Error> for AuthError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        AuthError::StoreError(e.to_string())
+    }
+}
+
+impl UserStore {
+    /// open the database at path, created on first use
+    pub fn open(path: &str) -> Result<UserStore, AuthError> {
+        UserStore::init(Connection::open(path)?)
+    }
+
+    /// store which is lost when dropped
+    pub fn open_in_memory() -> Result<UserStore, AuthError> {
+        UserStore::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<UserStore, AuthError> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY NOT NULL,
+                password_hash TEXT NOT NULL
+            )",
+            (),
+        )?;
+        // usernames and IP addresses
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS bans (
+                target TEXT PRIMARY KEY NOT NULL,
+                reason TEXT NOT NULL,
+                admin TEXT NOT NULL,
+                created TEXT NOT NULL
+            )",
+            (),
+        )?;
+
+        // computed up front, the first unknown user takes as long as any other
+        let password = SaltString::generate(&mut OsRng);
+        Ok(UserStore {
+            connection: Mutex::new(connection),
+            dummy_hash: hash_password(password.as_str())?,
+        })
+    }
+
+    /// lock database connection
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, AuthError> {
+        self.connection
+            .lock()
+            .map_err(|_| AuthError::StoreError("Unable to lock user store".to_owned()))
+    }
+
+    /// create a new account
+    pub fn register(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        if !valid_username(username) {
+            return Err(AuthError::InvalidUsername(username.to_owned()));
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AuthError::WeakPassword);
+        }
+        self.check_ban(username)?;
+
+        let hash = hash_password(password)?;
+        self.connection()?
+            .execute(
+                "INSERT INTO users (username, password_hash) VALUES (?1, ?2)",
+                params![username, hash],
+            )
+            .map_err(|e| taken_or_store_error(e, username))?;
+
+        Ok(())
+    }
+
+    /// create an account or replace its password with an argon2 hash made elsewhere
+    pub fn set_password_hash(&self, username: &str, hash: &str) -> Result<(), AuthError> {
+        if !valid_username(username) {
+            return Err(AuthError::InvalidUsername(username.to_owned()));
+        }
+        PasswordHash::new(hash)?;
+
+        self.connection()?.execute(
+            "INSERT INTO users (username, password_hash) VALUES (?1, ?2)
+            ON CONFLICT (username) DO UPDATE SET password_hash = excluded.password_hash",
+            params![username, hash],
+        )?;
+        Ok(())
+    }
+
+    /// check password of an existing account
+    pub fn verify(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let hash: Option<String> = self
+            .connection()?
+            .query_row(
+                "SELECT password_hash FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        // unknown users and wrong passwords are not distinguished,
+        // not even by the time it takes to refuse them
+        let Some(hash) = hash else {
+            if let Ok(dummy) = PasswordHash::new(&self.dummy_hash) {
+                _ = Argon2::default().verify_password(password.as_bytes(), &dummy);
+            }
+            return Err(AuthError::InvalidCredentials);
+        };
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &PasswordHash::new(&hash)?)
+            .map_err(|_| AuthError::InvalidCredentials)?;
+        self.check_ban(username)
+    }
+
+    /// move an account to another username keeping its password
+    pub fn rename(&self, username: &str, new_username: &str) -> Result<(), AuthError> {
+        if !valid_username(new_username) {
+            return Err(AuthError::InvalidUsername(new_username.to_owned()));
+        }
+        self.check_ban(new_username)?;
+
+        let updated = self
+            .connection()?
+            .execute(
+                "UPDATE users SET username = ?2 WHERE username = ?1",
+                params![username, new_username],
+            )
+            .map_err(|e| taken_or_store_error(e, new_username))?;
+
+        if updated == 0 {
+            return Err(AuthError::StoreError(format!(
+                "Account `{}` does not exist",
+                username
+            )));
+        }
+        Ok(())
+    }
+
+    /// refuse a username or IP address for good, banning again replaces the reason
+    pub fn ban(&self, target: &str, reason: &str, admin: &str) -> Result<(), AuthError> {
+        self.connection()?.execute(
+            "INSERT OR REPLACE INTO bans (target, reason, admin, created)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![target, reason, admin, Utc::now()],
+        )?;
+        Ok(())
+    }
+
+    /// lift the ban of a username or IP address
+    pub fn unban(&self, target: &str) -> Result<(), AuthError> {
+        let deleted = self
+            .connection()?
+            .execute("DELETE FROM bans WHERE target = ?1", params![target])?;
+        match deleted {
+            0 => Err(AuthError::NotBanned(target.to_owned())),
+            _ => Ok(()),
+        }
+    }
+
+    /// whether a username or IP address is banned
+    pub fn is_banned(&self, target: &str) -> Result<bool, AuthError> {
+        let banned = self
+            .connection()?
+            .query_row(
+                "SELECT 1 FROM bans WHERE target = ?1",
+                params![target],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(banned.is_some())
+    }
+
+    /// refuse a banned username or IP address
+    pub fn check_ban(&self, target: &str) -> Result<(), AuthError> {
+        match self.is_banned(target)? {
+            true => Err(AuthError::Banned(target.to_owned())),
+            false => Ok(()),
+        }
+    }
+}
+
+/// salted argon2 hash of a password
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// unique constraint violation means the username belongs to another account
+fn taken_or_store_error(e: rusqlite::Error, username: &str) -> AuthError {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => AuthError::UsernameTaken(username.to_owned()),
+        _ => e.into(),
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::AppError;
+
+/// Browser client served on the WebSocket port
+pub const WEB_CLIENT: &str = include_str!("../static/index.html");
+
+/// Longest accepted HTTP request head
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Most headers of an accepted HTTP request
+const MAX_HEADERS: usize = 32;
+
+/// upgrade a connection to a WebSocket, plain HTTP requests are answered with the web client
+/// none when a page was served instead
+/// the handshake is done here so a TLS stream can be upgraded just like a plain one
+pub async fn accept_websocket<S>(
+    mut stream: S,
+    max_frame_size: usize,
+) -> Result<Option<WebSocketStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = read_request_head(&mut stream).await?;
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(&head)?;
+
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+    };
+    let upgrade = header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+
+    match (request.method, request.path, upgrade) {
+        (Some("GET"), _, true) => {
+            let Some(key) = header("sec-websocket-key") else {
+                respond(stream, "400 Bad Request", "text/plain", "Missing key").await?;
+                return Ok(None);
+            };
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Accept: {}\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            );
+            stream.write_all(response.as_bytes()).await?;
+
+            let config = WebSocketConfig {
+                max_message_size: Some(max_frame_size),
+                max_frame_size: Some(max_frame_size),
+                ..Default::default()
+            };
+            let websocket = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config));
+            Ok(Some(websocket.await))
+        }
+        (Some("GET"), Some("/" | "/index.html"), false) => {
+            respond(stream, "200 OK", "text/html; charset=utf-8", WEB_CLIENT).await?;
+            Ok(None)
+        }
+        _ => {
+            respond(stream, "404 Not Found", "text/plain", "Not found").await?;
+            Ok(None)
+        }
+    }
+}
+
+/// read the request line and headers, clients wait for the upgrade before sending frames
+async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD {
+            return Err(AppError::OtherError("HTTP request head too large".to_owned()).into());
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(AppError::OtherError("HTTP request incomplete".to_owned()).into());
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(head)
+}
+
+/// answer a plain HTTP request and close the connection
+async fn respond<S>(mut stream: S, status: &str, content_type: &str, body: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
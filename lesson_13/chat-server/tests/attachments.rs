@@ -0,0 +1,255 @@
+mod common;
+
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use chat_server::{sniff, AttachmentFilter, AttachmentPolicy, Screened};
+use chatlib::{Ack, AttachmentError, Command, Event, ImageInfo, Message, Presence, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, SERVERS};
+
+fn rust_jpg_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../chat-lib/resources/test/rust.jpg")
+}
+
+fn rust_jpg() -> Vec<u8> {
+    std::fs::read(rust_jpg_path()).unwrap()
+}
+
+/// dimensions and thumbnail of the image as the client sends them
+fn rust_info() -> ImageInfo {
+    match Message::new_image_message(rust_jpg_path().to_str().unwrap()).unwrap() {
+        Message::Image(_, _, _, info) => info,
+        message => panic!("unexpected {:?}", message),
+    }
+}
+
+fn rust_image(ext: &str, bytes: Vec<u8>) -> Message {
+    Message::Image("rust".to_owned(), ext.to_owned(), bytes, rust_info())
+}
+
+fn attachment_policy(allowed: &[&str]) -> AttachmentPolicy {
+    AttachmentPolicy {
+        max_image_size: 200_000,
+        max_image_dimension: 4096,
+        max_file_size: 1000,
+        max_transfers: 2,
+        transfer_timeout: Duration::from_millis(200),
+        allowed_types: allowed.iter().map(|t| t.to_string()).collect(),
+        denied_types: vec!["exe".to_owned(), "application/x-msdownload".to_owned()],
+    }
+}
+
+fn policy(allowed: &[&str]) -> AttachmentFilter {
+    AttachmentFilter::new(attachment_policy(allowed))
+}
+
+fn command(message: Message) -> Command {
+    Command::Send(1, Target::Room(LOBBY.to_owned()), message)
+}
+
+#[test]
+fn sniffed_types() {
+    assert_eq!(sniff(&rust_jpg()), Some("image/jpeg"));
+    assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+    assert_eq!(sniff(b"MZ\x90\x00"), Some("application/x-msdownload"));
+    assert_eq!(sniff(b"hello"), None);
+}
+
+#[test]
+fn images_and_files() {
+    let mut filter = policy(&[]);
+    let image = |ext: &str, bytes: Vec<u8>| command(rust_image(ext, bytes));
+    let file = |name: &str, bytes: &[u8]| command(Message::File(name.to_owned(), bytes.to_vec()));
+
+    assert_eq!(filter.screen(&image("jpg", rust_jpg())), Screened::Pass);
+    assert_eq!(
+        filter.screen(&image("png", b"not an image".to_vec())),
+        Screened::Refused(AttachmentError::InvalidImage("rust.png".to_owned()))
+    );
+    // thumbnails are shown by clients, only PNG is accepted
+    let mut info = rust_info();
+    info.thumbnail = b"GIF89a".to_vec();
+    assert_eq!(
+        filter.screen(&command(Message::Image(
+            "rust".to_owned(),
+            "jpg".to_owned(),
+            rust_jpg(),
+            info
+        ))),
+        Screened::Refused(AttachmentError::InvalidImage("rust.jpg".to_owned()))
+    );
+
+    // the header has to announce the dimensions of the info, within the limit
+    let mut info = rust_info();
+    info.width += 1;
+    let lying = Message::Image("rust".to_owned(), "jpg".to_owned(), rust_jpg(), info);
+    assert_eq!(
+        filter.screen(&command(lying)),
+        Screened::Refused(AttachmentError::InvalidImage("rust.jpg".to_owned()))
+    );
+    let info = rust_info();
+    let mut small = AttachmentFilter::new(AttachmentPolicy {
+        max_image_dimension: 100,
+        ..attachment_policy(&[])
+    });
+    assert_eq!(
+        small.screen(&image("jpg", rust_jpg())),
+        Screened::Refused(AttachmentError::ImageTooLarge(info.width, info.height, 100))
+    );
+
+    assert_eq!(filter.screen(&file("notes.txt", b"hello")), Screened::Pass);
+    assert_eq!(
+        filter.screen(&file("notes.txt", &[0; 2000])),
+        Screened::Refused(AttachmentError::TooLarge(2000, 1000))
+    );
+
+    // denied by extension or by content whatever the name
+    assert_eq!(
+        filter.screen(&file("setup.EXE", b"hello")),
+        Screened::Refused(AttachmentError::Denied("exe".to_owned()))
+    );
+    assert_eq!(
+        filter.screen(&file("notes.txt", b"MZ\x90\x00")),
+        Screened::Refused(AttachmentError::Denied(
+            "application/x-msdownload".to_owned()
+        ))
+    );
+
+    // text is never screened
+    assert_eq!(
+        filter.screen(&command(Message::new_text_message("hi"))),
+        Screened::Pass
+    );
+    assert_eq!(filter.screen(&Command::Rooms), Screened::Pass);
+}
+
+#[test]
+fn allow_list() {
+    let mut filter = policy(&["image/*", "pdf"]);
+    let file = |name: &str, bytes: &[u8]| command(Message::File(name.to_owned(), bytes.to_vec()));
+
+    assert_eq!(
+        filter.screen(&command(rust_image("jpg", rust_jpg()))),
+        Screened::Pass
+    );
+    assert_eq!(
+        filter.screen(&file("paper.pdf", b"%PDF-1.7")),
+        Screened::Pass
+    );
+    assert_eq!(
+        filter.screen(&file("notes.txt", b"hello")),
+        Screened::Refused(AttachmentError::NotAllowed("txt".to_owned()))
+    );
+}
+
+#[test]
+fn chunked_transfers() {
+    let mut filter = policy(&[]);
+    let begin = |id, name: &str, size| command(Message::FileBegin(id, name.to_owned(), size));
+    let chunk = |id, bytes: &[u8]| command(Message::FileChunk(id, bytes.to_vec()));
+    let end = |id| command(Message::FileEnd(id));
+
+    assert_eq!(filter.screen(&begin(1, "notes.txt", 10)), Screened::Pass);
+    assert_eq!(filter.screen(&chunk(1, b"hello")), Screened::Pass);
+    assert_eq!(filter.screen(&chunk(1, b"world")), Screened::Pass);
+    assert_eq!(filter.screen(&end(1)), Screened::Pass);
+
+    // refused on begin, the rest of the transfer never reaches anybody
+    assert_eq!(
+        filter.screen(&begin(2, "big.bin", 5000)),
+        Screened::Refused(AttachmentError::TooLarge(5000, 1000))
+    );
+    assert_eq!(filter.screen(&chunk(2, b"hello")), Screened::Dropped);
+    assert_eq!(filter.screen(&end(2)), Screened::Dropped);
+
+    // content of the first chunk is sniffed, the end lets recipients discard the file
+    assert_eq!(filter.screen(&begin(3, "notes.txt", 10)), Screened::Pass);
+    assert!(matches!(
+        filter.screen(&chunk(3, b"MZ\x90\x00")),
+        Screened::Refused(AttachmentError::Denied(_))
+    ));
+    assert_eq!(filter.screen(&chunk(3, b"hello")), Screened::Dropped);
+    assert_eq!(filter.screen(&end(3)), Screened::Pass);
+
+    // more content than announced
+    assert_eq!(filter.screen(&begin(4, "notes.txt", 4)), Screened::Pass);
+    assert_eq!(
+        filter.screen(&chunk(4, b"hello")),
+        Screened::Refused(AttachmentError::TooLarge(5, 4))
+    );
+
+    // parts of transfers never begun are not forwarded
+    assert_eq!(filter.screen(&chunk(9, b"hello")), Screened::Dropped);
+    assert_eq!(filter.screen(&end(9)), Screened::Dropped);
+}
+
+#[test]
+fn transfers_are_limited_and_expire() {
+    let mut filter = policy(&[]);
+    let begin = |id| command(Message::FileBegin(id, "notes.txt".to_owned(), 10));
+    let chunk = |id| command(Message::FileChunk(id, b"hello".to_vec()));
+    let end = |id| command(Message::FileEnd(id));
+
+    // refused transfers take a slot as well
+    assert_eq!(filter.screen(&begin(1)), Screened::Pass);
+    assert!(matches!(
+        filter.screen(&command(Message::FileBegin(2, "setup.exe".to_owned(), 10))),
+        Screened::Refused(AttachmentError::Denied(_))
+    ));
+    assert_eq!(
+        filter.screen(&begin(3)),
+        Screened::Refused(AttachmentError::TooManyTransfers(2))
+    );
+    assert_eq!(filter.screen(&chunk(3)), Screened::Dropped);
+
+    // a finished transfer frees its slot
+    assert_eq!(filter.screen(&end(2)), Screened::Dropped);
+    assert_eq!(filter.screen(&begin(3)), Screened::Pass);
+
+    // a transfer without a chunk for too long is forgotten
+    sleep(Duration::from_millis(150));
+    assert_eq!(filter.screen(&chunk(3)), Screened::Pass);
+    sleep(Duration::from_millis(100));
+    assert_eq!(filter.screen(&begin(4)), Screened::Pass);
+    assert_eq!(filter.screen(&chunk(1)), Screened::Dropped);
+    assert_eq!(filter.screen(&chunk(3)), Screened::Pass);
+}
+
+#[test]
+fn refused_image_is_not_forwarded() {
+    for bin in SERVERS {
+        let env = [("MAX_IMAGE_SIZE", "100000".to_owned())];
+        let server = TestServer::start(bin, test_dir("attachments", bin), &env);
+        let (mut alice, mut alice_writer) = server.register("alice");
+        let (mut bob, _) = server.register("bob");
+        assert_eq!(
+            receive(&mut alice),
+            Event::Presence("bob".to_owned(), Presence::Online)
+        );
+
+        send(&mut alice_writer, command(rust_image("jpg", rust_jpg())));
+        let size = rust_jpg().len() + rust_info().thumbnail.len();
+        assert_eq!(
+            receive(&mut alice),
+            Event::Ack(
+                1,
+                Ack::Refused(AttachmentError::TooLarge(size as u64, 100000))
+            )
+        );
+
+        // the next message is the first one bob sees
+        send(
+            &mut alice_writer,
+            Command::Send(
+                2,
+                Target::Room(LOBBY.to_owned()),
+                Message::new_text_message("hi"),
+            ),
+        );
+        let Event::Message(envelope) = receive(&mut bob) else {
+            panic!("{}: message expected", bin);
+        };
+        assert_eq!(envelope.message, Message::new_text_message("hi"), "{}", bin);
+    }
+}
@@ -0,0 +1,312 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use chat_server::{hash_password, AuthError, History, State, UserStore};
+use chatlib::{Ack, Command, Event, Message, Sanction, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn state() -> State {
+    state_with_users(UserStore::open_in_memory().unwrap())
+}
+
+fn state_with_users(users: UserStore) -> State {
+    State::new(users, History::open_in_memory().unwrap(), 0).unwrap()
+}
+
+/// state with the admin account root created out of band
+fn admin_state() -> State {
+    let users = UserStore::open_in_memory().unwrap();
+    let hash = hash_password("correct horse").unwrap();
+    users.set_password_hash("root", &hash).unwrap();
+    state_with_users(users).with_admins(vec!["root".to_owned()])
+}
+
+fn register(name: &str, password: &str) -> Command {
+    Command::Register(name.to_owned(), password.to_owned())
+}
+
+fn login(name: &str, password: &str) -> Command {
+    Command::Login(name.to_owned(), password.to_owned())
+}
+
+#[test]
+fn user_store() {
+    let users = UserStore::open_in_memory().unwrap();
+
+    users.register("alice", "correct horse").unwrap();
+    assert_eq!(
+        users.register("alice", "battery staple"),
+        Err(AuthError::UsernameTaken("alice".to_owned()))
+    );
+    assert_eq!(users.register("bob", "short"), Err(AuthError::WeakPassword));
+    assert_eq!(
+        users.register("bob smith", "correct horse"),
+        Err(AuthError::InvalidUsername("bob smith".to_owned()))
+    );
+
+    assert_eq!(users.verify("alice", "correct horse"), Ok(()));
+    assert_eq!(
+        users.verify("alice", "battery staple"),
+        Err(AuthError::InvalidCredentials)
+    );
+    assert_eq!(
+        users.verify("nobody", "correct horse"),
+        Err(AuthError::InvalidCredentials)
+    );
+
+    // renamed account keeps its password
+    users.register("bob", "battery staple").unwrap();
+    assert_eq!(
+        users.rename("alice", "bob"),
+        Err(AuthError::UsernameTaken("bob".to_owned()))
+    );
+    users.rename("alice", "alicia").unwrap();
+    assert_eq!(users.verify("alicia", "correct horse"), Ok(()));
+    assert_eq!(
+        users.verify("alice", "correct horse"),
+        Err(AuthError::InvalidCredentials)
+    );
+}
+
+#[test]
+fn user_store_persistence() {
+    let path = std::env::temp_dir().join(format!("chat-users-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    _ = std::fs::remove_file(path);
+
+    UserStore::open(path)
+        .unwrap()
+        .register("alice", "correct horse")
+        .unwrap();
+    let verified = UserStore::open(path)
+        .unwrap()
+        .verify("alice", "correct horse");
+
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(verified, Ok(()));
+}
+
+#[test]
+fn commands_refused_until_authenticated() {
+    let state = state();
+    let hello = Command::Send(
+        1,
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message("hello"),
+    );
+
+    let deliveries = state.handle(client(1), hello).unwrap();
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(1, Ack::Rejected(_))
+    ));
+
+    let deliveries = state
+        .handle(client(1), login("alice", "correct horse"))
+        .unwrap();
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("Invalid username or password".to_owned())
+    );
+
+    let deliveries = state
+        .handle(client(1), register("alice", "correct horse"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+
+    // wrong password does not log in
+    let deliveries = state
+        .handle(client(2), login("alice", "wrong horse"))
+        .unwrap();
+    assert!(matches!(deliveries[0].event, Event::Error(_)));
+
+    // account is in use
+    let deliveries = state
+        .handle(client(2), login("alice", "correct horse"))
+        .unwrap();
+    assert!(matches!(&deliveries[0].event, Event::Error(e) if e.contains("already connected")));
+
+    // released on disconnect
+    state.disconnect(client(1));
+    let deliveries = state
+        .handle(client(2), login("alice", "correct horse"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+}
+
+#[test]
+fn nick_renames_account() {
+    let state = state();
+    state
+        .handle(client(1), register("alice", "correct horse"))
+        .unwrap();
+    state
+        .handle(client(2), register("bob", "battery staple"))
+        .unwrap();
+    state.disconnect(client(2));
+
+    // offline accounts cannot be taken over
+    let deliveries = state
+        .handle(client(1), Command::Nick("bob".to_owned()))
+        .unwrap();
+    assert!(matches!(&deliveries[0].event, Event::Error(e) if e.contains("already taken")));
+
+    let deliveries = state
+        .handle(client(1), Command::Nick("alicia".to_owned()))
+        .unwrap();
+    assert_eq!(
+        deliveries[0].event,
+        Event::Renamed("alice".to_owned(), "alicia".to_owned())
+    );
+
+    state.disconnect(client(1));
+    let deliveries = state
+        .handle(client(1), login("alicia", "correct horse"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("alicia".to_owned()));
+}
+
+#[test]
+fn banned_accounts_are_refused() {
+    let users = UserStore::open_in_memory().unwrap();
+    users.register("alice", "correct horse").unwrap();
+
+    users.ban("alice", "spam", "root").unwrap();
+    assert_eq!(users.is_banned("alice"), Ok(true));
+    assert_eq!(
+        users.verify("alice", "correct horse"),
+        Err(AuthError::Banned("alice".to_owned()))
+    );
+    // wrong passwords do not reveal the ban
+    assert_eq!(
+        users.verify("alice", "wrong password"),
+        Err(AuthError::InvalidCredentials)
+    );
+    assert_eq!(
+        users
+            .register("bob", "battery staple")
+            .and(users.rename("bob", "alice")),
+        Err(AuthError::Banned("alice".to_owned()))
+    );
+
+    users.unban("alice").unwrap();
+    assert_eq!(users.verify("alice", "correct horse"), Ok(()));
+    assert_eq!(
+        users.unban("alice"),
+        Err(AuthError::NotBanned("alice".to_owned()))
+    );
+}
+
+#[test]
+fn bans_are_taken_by_admins() {
+    let state = admin_state();
+    state
+        .handle(client(1), login("root", "correct horse"))
+        .unwrap();
+    state
+        .handle(client(2), register("alice", "battery staple"))
+        .unwrap();
+
+    let ban = |target: &str| Command::Moderate(Sanction::Ban(target.to_owned(), String::new()));
+    let deliveries = state.handle(client(2), ban("root")).unwrap();
+    assert!(matches!(&deliveries[0].event, Event::Error(e) if e.contains("Only admins")));
+
+    // banned addresses can not log in anymore
+    state.handle(client(1), ban("127.0.0.1")).unwrap();
+    let deliveries = state
+        .handle(client(3), login("alice", "battery staple"))
+        .unwrap();
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error(AuthError::Banned("127.0.0.1".to_owned()).to_string())
+    );
+
+    // except admins, who can lift the ban again
+    state.disconnect(client(1));
+    let deliveries = state
+        .handle(client(4), login("root", "correct horse"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("root".to_owned()));
+    let unban = Command::Moderate(Sanction::Unban("127.0.0.1".to_owned()));
+    state.handle(client(4), unban).unwrap();
+    let deliveries = state
+        .handle(client(3), login("alice", "battery staple"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+}
+
+#[test]
+fn admins_can_not_register() {
+    let state = admin_state();
+    let deliveries = state
+        .handle(client(1), register("root", "battery staple"))
+        .unwrap();
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("Username `root` is reserved".to_owned())
+    );
+
+    let deliveries = state
+        .handle(client(1), login("root", "correct horse"))
+        .unwrap();
+    assert_eq!(deliveries[0].event, Event::Welcome("root".to_owned()));
+}
+
+#[test]
+fn password_hashes_are_set() {
+    let users = UserStore::open_in_memory().unwrap();
+    users.register("root", "battery staple").unwrap();
+
+    // an existing account gets the new password
+    let hash = hash_password("correct horse").unwrap();
+    users.set_password_hash("root", &hash).unwrap();
+    assert_eq!(users.verify("root", "correct horse"), Ok(()));
+    assert_eq!(
+        users.verify("root", "battery staple"),
+        Err(AuthError::InvalidCredentials)
+    );
+
+    assert!(matches!(
+        users.set_password_hash("root", "not a hash"),
+        Err(AuthError::StoreError(_))
+    ));
+    assert_eq!(users.verify("root", "correct horse"), Ok(()));
+}
+
+#[test]
+fn unknown_users_take_as_long_as_wrong_passwords() {
+    let users = UserStore::open_in_memory().unwrap();
+    users.register("alice", "correct horse").unwrap();
+
+    let refused = |username: &str| {
+        let started = Instant::now();
+        assert_eq!(
+            users.verify(username, "battery staple"),
+            Err(AuthError::InvalidCredentials)
+        );
+        started.elapsed()
+    };
+
+    // the very first unknown user does not pay for computing the dummy hash
+    let first_unknown_user = refused("nobody");
+
+    // fastest of a few attempts
+    let fastest = |username: &str| (0..5).map(|_| refused(username)).min().unwrap();
+    let wrong_password = fastest("alice");
+    let unknown_user = fastest("nobody");
+    assert!(
+        first_unknown_user * 4 <= wrong_password * 7,
+        "first unknown user refused in {:?}, wrong password in {:?}",
+        first_unknown_user,
+        wrong_password
+    );
+    assert!(
+        unknown_user * 4 >= wrong_password,
+        "unknown user refused in {:?}, wrong password in {:?}",
+        unknown_user,
+        wrong_password
+    );
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use chatlib::{
+    Codec, Command, Event, FrameReader, FrameWriter, Message, Presence, Stream, Target, LOBBY,
+};
+use common::{receive, test_dir, TestServer, SERVERS};
+
+/// connection speaking the codec picked by the server
+fn connect(
+    server: &TestServer,
+    offered: &[Codec],
+) -> (FrameReader<Stream>, FrameWriter<Stream>, Codec) {
+    let (mut reader, mut writer) = server.connect();
+    writer.write_frame(&Codec::offer(offered)).unwrap();
+    let answer = reader.read_frame().unwrap();
+    let codec = Codec::parse_answer(&answer).unwrap().unwrap();
+    (reader, writer, codec)
+}
+
+fn send_with(writer: &mut FrameWriter<Stream>, codec: Codec, command: Command) {
+    writer
+        .write_frame(&codec.encode(&command).unwrap())
+        .unwrap();
+}
+
+fn receive_with(reader: &mut FrameReader<Stream>, codec: Codec) -> Event {
+    codec.decode(&reader.read_frame().unwrap()).unwrap()
+}
+
+#[test]
+fn clients_pick_their_codec() {
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("codecs", bin), &[]);
+        let (mut alice, _) = server.register("alice");
+        let mut connected = Vec::new();
+
+        for (name, codec) in [("bob", Codec::Json), ("carol", Codec::MessagePack)] {
+            let (mut reader, mut writer, picked) = connect(&server, &[codec, Codec::Bincode]);
+            assert_eq!(picked, codec, "{}", bin);
+
+            let register = Command::Register(name.to_owned(), "correct horse".to_owned());
+            send_with(&mut writer, codec, register);
+            assert_eq!(
+                receive_with(&mut reader, codec),
+                Event::Welcome(name.to_owned())
+            );
+            assert!(matches!(
+                receive_with(&mut reader, codec),
+                Event::History(..)
+            ));
+            assert_eq!(
+                receive(&mut alice),
+                Event::Presence(name.to_owned(), Presence::Online)
+            );
+
+            // events queued once for everybody reach each client in its own codec
+            let text = Message::new_text_message(name);
+            let command = Command::Send(1, Target::Room(LOBBY.to_owned()), text.clone());
+            send_with(&mut writer, codec, command);
+            assert!(
+                matches!(receive(&mut alice),
+                Event::Message(envelope) if envelope.message == text),
+                "{}",
+                bin
+            );
+            connected.push((reader, writer));
+        }
+
+        // nothing but bincode left to pick
+        let (_, _, picked) = connect(&server, &[Codec::Bincode]);
+        assert_eq!(picked, Codec::Bincode);
+
+        // an offer is only understood as the first frame
+        let (mut reader, mut writer) = server.connect();
+        writer.write_frame(&Command::Who.encode().unwrap()).unwrap();
+        assert!(matches!(receive(&mut reader), Event::Error(_)), "{}", bin);
+        writer.write_frame(&Codec::offer(&[Codec::Json])).unwrap();
+        assert!(reader.read_frame().is_err(), "{}", bin);
+    }
+}
@@ -0,0 +1,141 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command as Process, ExitStatus, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use chatlib::{Command, Event, FrameReader, FrameWriter, Stream, DEFAULT_MAX_FRAME_SIZE, LOBBY};
+
+/// How long a test waits for the server to answer
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server binaries speaking the same protocol
+pub const SERVERS: [&str; 2] = ["chat-server", "chat-server-async"];
+
+/// server binary running with its own database in its own directory
+pub struct TestServer {
+    child: Child,
+    pub dir: PathBuf,
+    pub port: u16,
+}
+
+/// empty directory for the files of a single test server
+pub fn test_dir(name: &str, bin: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chat-{}-{}-{}", name, bin, std::process::id()));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+impl TestServer {
+    /// start the server binary on a free port with additional environment
+    pub fn start(bin: &str, dir: PathBuf, env: &[(&str, String)]) -> TestServer {
+        // free port picked by the OS
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let child = Process::new(bin_path(bin))
+            .env("HOSTNAME", "127.0.0.1")
+            .env("PORT", port.to_string())
+            .env("DATABASE", dir.join("chat.db"))
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = TestServer { child, dir, port };
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "{} did not start",
+                bin
+            );
+            sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// send SIGTERM and wait for the server to exit
+    pub fn terminate(&mut self) -> ExitStatus {
+        let pid = self.child.id().to_string();
+        let killed = Process::new("kill").args(["-TERM", &pid]).status().unwrap();
+        assert!(killed.success());
+
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(started.elapsed() < READ_TIMEOUT, "server did not exit");
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// plain TCP connection, reads fail instead of hanging when the server stops answering
+    pub fn connect(&self) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+        let socket = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        socket.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        frames(Stream::Plain(socket))
+    }
+
+    /// plain TCP connection registered under the username, welcome and history are consumed
+    pub fn register(&self, name: &str) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+        self.authenticate(Command::Register(
+            name.to_owned(),
+            "correct horse".to_owned(),
+        ))
+    }
+
+    /// plain TCP connection logged in with an existing account, welcome and history are consumed
+    pub fn login(&self, name: &str) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+        self.authenticate(Command::Login(name.to_owned(), "correct horse".to_owned()))
+    }
+
+    fn authenticate(&self, credentials: Command) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+        let (mut reader, mut writer) = self.connect();
+        let name = match &credentials {
+            Command::Register(name, _) | Command::Login(name, _) => name.clone(),
+            _ => unreachable!(),
+        };
+        send(&mut writer, credentials);
+        assert_eq!(receive(&mut reader), Event::Welcome(name));
+        assert!(matches!(receive(&mut reader), Event::History(room, _) if room == LOBBY));
+        (reader, writer)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+        _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn bin_path(bin: &str) -> &'static str {
+    match bin {
+        "chat-server" => env!("CARGO_BIN_EXE_chat-server"),
+        _ => env!("CARGO_BIN_EXE_chat-server-async"),
+    }
+}
+
+pub fn frames(stream: Stream) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+    let writer = FrameWriter::new(stream.try_clone().unwrap(), DEFAULT_MAX_FRAME_SIZE);
+    (FrameReader::new(stream, DEFAULT_MAX_FRAME_SIZE), writer)
+}
+
+pub fn send(writer: &mut FrameWriter<Stream>, command: Command) {
+    writer.write_frame(&command.encode().unwrap()).unwrap();
+}
+
+pub fn receive(reader: &mut FrameReader<Stream>) -> Event {
+    Event::from_bytes(&reader.read_frame().unwrap()).unwrap()
+}
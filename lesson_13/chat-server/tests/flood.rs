@@ -0,0 +1,136 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use chatlib::{Ack, Command, Event, Message, Presence, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, SERVERS};
+
+/// Messages the flooding client sends at most
+const MESSAGES: u64 = 100;
+
+/// Chunks of the file streamed past the byte burst
+const CHUNKS: u64 = 20;
+const CHUNK_SIZE: usize = 10_000;
+
+fn flood_env() -> [(&'static str, String); 5] {
+    [
+        ("RATE_MESSAGES", "1".to_owned()),
+        ("RATE_MESSAGE_BURST", "5".to_owned()),
+        ("FLOOD_WARNINGS", "3".to_owned()),
+        ("MUTE_DURATION", "60".to_owned()),
+        ("FLOOD_MUTES", "1".to_owned()),
+    ]
+}
+
+#[test]
+fn flooding_client_is_muted_then_disconnected() {
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("flood", bin), &flood_env());
+        let (mut bob, mut bob_writer) = server.register("bob");
+        let (mut mallory, mut mallory_writer) = server.register("mallory");
+
+        // every message is answered before the next one, the server hangs up eventually
+        let (mut accepted, mut warned, mut muted) = (0, 0, 0);
+        'flood: for id in 1..=MESSAGES {
+            let spam = Command::Send(
+                id,
+                Target::Room(LOBBY.to_owned()),
+                Message::new_text_message("spam"),
+            );
+            if mallory_writer.write_frame(&spam.encode().unwrap()).is_err() {
+                break;
+            }
+            loop {
+                let Ok(frame) = mallory.read_frame() else {
+                    break 'flood;
+                };
+                match Event::from_bytes(&frame).unwrap() {
+                    Event::Ack(i, Ack::Delivered(_)) if i == id => accepted += 1,
+                    Event::Ack(i, Ack::Rejected(reason)) if i == id => match reason {
+                        _ if reason.contains("slow down") => warned += 1,
+                        _ if reason.contains("Muted") => muted += 1,
+                        _ => {}
+                    },
+                    _ => continue,
+                }
+                break;
+            }
+        }
+        // the burst is shared with the registration, storing messages takes a while
+        assert!((4..=6).contains(&accepted), "{}: {}", bin, accepted);
+        assert_eq!(warned, 3, "{}", bin);
+        assert!(muted >= 1, "{}", bin);
+
+        // others only see the accepted messages and stay connected
+        assert_eq!(
+            receive(&mut bob),
+            Event::Presence("mallory".to_owned(), Presence::Online)
+        );
+        let mut spam = 0;
+        loop {
+            match receive(&mut bob) {
+                Event::Message(_) => spam += 1,
+                Event::Presence(name, Presence::Offline) if name == "mallory" => break,
+                event => panic!("{}: unexpected event {:?}", bin, event),
+            }
+        }
+        assert_eq!(spam, accepted, "{}", bin);
+        send(&mut bob_writer, Command::Who);
+        assert!(matches!(receive(&mut bob), Event::Users(users) if users.len() == 1));
+    }
+}
+
+#[test]
+fn file_larger_than_byte_burst_is_throttled() {
+    let env = [
+        ("RATE_BYTES", "100000".to_owned()),
+        ("RATE_BYTE_BURST", "50000".to_owned()),
+    ];
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("throttle", bin), &env);
+        let (mut bob, _bob_writer) = server.register("bob");
+        let (mut alice, mut alice_writer) = server.register("alice");
+        assert_eq!(
+            receive(&mut bob),
+            Event::Presence("alice".to_owned(), Presence::Online)
+        );
+
+        // streamed without waiting for acks, like the client does
+        let size = CHUNKS * CHUNK_SIZE as u64;
+        let started = Instant::now();
+        let lobby = Target::Room(LOBBY.to_owned());
+        let begin = Message::FileBegin(1, "data.bin".to_owned(), size);
+        send(&mut alice_writer, Command::Send(1, lobby.clone(), begin));
+        for id in 2..CHUNKS + 2 {
+            let chunk = Message::FileChunk(1, vec![b'x'; CHUNK_SIZE]);
+            send(&mut alice_writer, Command::Send(id, lobby.clone(), chunk));
+        }
+        let end = Message::FileEnd(1);
+        send(&mut alice_writer, Command::Send(CHUNKS + 2, lobby, end));
+
+        // every chunk is delivered, none is refused
+        let mut delivered = 0;
+        while delivered < CHUNKS + 2 {
+            match receive(&mut alice) {
+                Event::Ack(_, Ack::Delivered(1)) => delivered += 1,
+                Event::Ack(_, Ack::Accepted(_)) | Event::Message(_) => {}
+                event => panic!("{}: unexpected event {:?}", bin, event),
+            }
+        }
+        let mut received = 0;
+        loop {
+            match receive(&mut bob) {
+                Event::Message(envelope) => match envelope.message {
+                    Message::FileChunk(1, bytes) => received += bytes.len() as u64,
+                    Message::FileEnd(1) => break,
+                    _ => {}
+                },
+                event => panic!("{}: unexpected event {:?}", bin, event),
+            }
+        }
+        assert_eq!(received, size, "{}", bin);
+
+        // the bytes past the burst arrive at the configured rate
+        assert!(started.elapsed() >= Duration::from_millis(1400), "{}", bin);
+    }
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use chatlib::{Command, Event, FrameReader, Stream};
+use common::{frames, receive, send, test_dir, TestServer, READ_TIMEOUT, SERVERS};
+
+/// short heartbeat so that the tests do not wait long
+fn heartbeat_env(idle_timeout: u64) -> [(&'static str, String); 3] {
+    [
+        ("PING_INTERVAL", "1".to_owned()),
+        ("IDLE_TIMEOUT", idle_timeout.to_string()),
+        ("FRAME_TIMEOUT", "1".to_owned()),
+    ]
+}
+
+/// read events until the server closes the connection, returns how long it took
+fn wait_for_eviction(reader: &mut FrameReader<Stream>) -> Duration {
+    let started = Instant::now();
+    while let Ok(frame) = reader.read_frame() {
+        assert!(matches!(Event::from_bytes(&frame), Ok(Event::Ping(_))));
+    }
+    started.elapsed()
+}
+
+#[test]
+fn answered_pings_keep_client_connected() {
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("heartbeat", bin), &heartbeat_env(2));
+        let (mut reader, mut writer) = server.register("alice");
+
+        // quiet for longer than the idle timeout, pings are answered
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(3) {
+            match receive(&mut reader) {
+                Event::Ping(id) => send(&mut writer, Command::Pong(id)),
+                event => panic!("{}: unexpected event {:?}", bin, event),
+            }
+        }
+
+        send(&mut writer, Command::Ping(42));
+        loop {
+            match receive(&mut reader) {
+                Event::Ping(id) => send(&mut writer, Command::Pong(id)),
+                event => {
+                    assert_eq!(event, Event::Pong(42), "{}", bin);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn silent_client_is_evicted() {
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("idle-client", bin), &heartbeat_env(2));
+        let (mut reader, _writer) = server.connect();
+
+        let evicted = wait_for_eviction(&mut reader);
+        assert!(evicted < READ_TIMEOUT, "{}", bin);
+    }
+}
+
+#[test]
+fn stalled_frame_is_evicted() {
+    for bin in SERVERS {
+        // idle eviction would take much longer than the frame timeout
+        let server = TestServer::start(bin, test_dir("stalled-frame", bin), &heartbeat_env(30));
+        let mut socket = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        socket.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+
+        // half of a length prefix and nothing more
+        socket.write_all(&[0, 0]).unwrap();
+        let (mut reader, _writer) = frames(Stream::Plain(socket));
+
+        let evicted = wait_for_eviction(&mut reader);
+        assert!(evicted < Duration::from_secs(5), "{}: {:?}", bin, evicted);
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chat_server::{History, State, UserStore, MAX_HISTORY_PAGE};
+use chatlib::{Command, Envelope, Event, HistoryContent, HistoryEntry, Message, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// every message gets its own client id, resent ones are not stored twice
+fn send(target: Target, text: &str) -> Command {
+    static ID: AtomicU64 = AtomicU64::new(1);
+    let id = ID.fetch_add(1, Ordering::Relaxed);
+    Command::Send(id, target, Message::new_text_message(text))
+}
+
+fn entry(id: u64, room: &str, text: &str) -> HistoryEntry {
+    let envelope = Envelope::new(
+        id,
+        "alice",
+        Target::Room(room.to_owned()),
+        Message::new_text_message(text),
+    );
+    HistoryEntry::from_envelope(&envelope).unwrap()
+}
+
+/// events of all deliveries addressed to the client
+fn events_for(client: SocketAddr, deliveries: Vec<chat_server::Delivery>) -> Vec<Event> {
+    deliveries
+        .into_iter()
+        .filter(|delivery| delivery.recipients.contains(&client))
+        .map(|delivery| delivery.event)
+        .collect()
+}
+
+fn ids(entries: &[HistoryEntry]) -> Vec<u64> {
+    entries.iter().map(|entry| entry.id).collect()
+}
+
+#[test]
+fn history_paging() {
+    let history = History::open_in_memory().unwrap();
+    assert_eq!(history.last_id().unwrap(), 0);
+
+    for id in 1..=10 {
+        history.append(&entry(id, LOBBY, "hello")).unwrap();
+    }
+    history.append(&entry(11, "rust", "hello")).unwrap();
+    assert_eq!(history.last_id().unwrap(), 11);
+
+    // newest page first, ordered from the oldest message
+    let page = history.page(LOBBY, None, 3).unwrap();
+    assert_eq!(ids(&page), vec![8, 9, 10]);
+    let page = history.page(LOBBY, Some(u64::MAX), 3).unwrap();
+    assert_eq!(ids(&page), vec![8, 9, 10]);
+    let page = history.page(LOBBY, Some(8), 3).unwrap();
+    assert_eq!(ids(&page), vec![5, 6, 7]);
+    let page = history.page(LOBBY, Some(3), 3).unwrap();
+    assert_eq!(ids(&page), vec![1, 2]);
+    assert!(history.page(LOBBY, Some(1), 3).unwrap().is_empty());
+
+    let page = history.page("rust", None, MAX_HISTORY_PAGE + 1).unwrap();
+    assert_eq!(ids(&page), vec![11]);
+    assert_eq!(page[0].content, HistoryContent::Text("hello".to_owned()));
+}
+
+#[test]
+fn attachment_metadata() {
+    let history = History::open_in_memory().unwrap();
+    let mut image = entry(1, LOBBY, "");
+    image.content = HistoryContent::Image("cat".to_owned(), "png".to_owned(), 10);
+    let mut file = entry(2, LOBBY, "");
+    file.content = HistoryContent::File("a.dat".to_owned(), 2048);
+
+    history.append(&image).unwrap();
+    history.append(&file).unwrap();
+
+    assert_eq!(history.page(LOBBY, None, 10).unwrap(), vec![image, file]);
+}
+
+#[test]
+fn replay_on_login_and_join() {
+    let path = std::env::temp_dir().join(format!("chat-history-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    _ = std::fs::remove_file(path);
+
+    let state = |replay| {
+        State::new(
+            UserStore::open(path).unwrap(),
+            History::open(path).unwrap(),
+            replay,
+        )
+        .unwrap()
+    };
+
+    let first = state(2);
+    let register = Command::Register("alice".to_owned(), "correct horse".to_owned());
+    first.handle(client(1), register).unwrap();
+    first
+        .handle(client(1), Command::Join("rust".to_owned()))
+        .unwrap();
+    for text in ["one", "two", "three"] {
+        first
+            .handle(client(1), send(Target::Room(LOBBY.to_owned()), text))
+            .unwrap();
+    }
+    first
+        .handle(client(1), send(Target::Room("rust".to_owned()), "four"))
+        .unwrap();
+    // direct messages are not stored
+    first
+        .handle(client(1), send(Target::User("alice".to_owned()), "psst"))
+        .unwrap();
+    drop(first);
+
+    // history survives a restart, message ids continue after the direct message
+    let second = state(2);
+    let login = Command::Login("alice".to_owned(), "correct horse".to_owned());
+    let events = events_for(client(2), second.handle(client(2), login).unwrap());
+    assert_eq!(events[0], Event::Welcome("alice".to_owned()));
+    let Event::History(room, entries) = &events[1] else {
+        panic!("unexpected event {:?}", events[1]);
+    };
+    assert_eq!(room, LOBBY);
+    assert_eq!(ids(entries), vec![2, 3]);
+
+    let deliveries = second
+        .handle(client(2), Command::Join("rust".to_owned()))
+        .unwrap();
+    let events = events_for(client(2), deliveries);
+    assert_eq!(events[0], Event::Joined("rust".to_owned()));
+    let Event::History(room, entries) = &events[1] else {
+        panic!("unexpected event {:?}", events[1]);
+    };
+    assert_eq!(room, "rust");
+    assert_eq!(ids(entries), vec![4]);
+
+    let deliveries = second
+        .handle(client(2), send(Target::Room(LOBBY.to_owned()), "five"))
+        .unwrap();
+    let Event::Message(envelope) = &deliveries[1].event else {
+        panic!("unexpected event {:?}", deliveries[1].event);
+    };
+    assert_eq!(envelope.id, 6);
+
+    // paging older messages
+    let page = Command::History(LOBBY.to_owned(), Some(2), 10);
+    let events = events_for(client(2), second.handle(client(2), page).unwrap());
+    let Event::History(_, entries) = &events[0] else {
+        panic!("unexpected event {:?}", events[0]);
+    };
+    assert_eq!(ids(entries), vec![1]);
+
+    // only members can read room history
+    let page = Command::History("secret".to_owned(), None, 10);
+    let events = events_for(client(2), second.handle(client(2), page).unwrap());
+    assert!(matches!(events[0], Event::Error(_)));
+
+    drop(second);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn replay_errors_do_not_refuse_login() {
+    let path = std::env::temp_dir().join(format!("chat-history-broken-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    _ = std::fs::remove_file(path);
+
+    let state = State::new(
+        UserStore::open_in_memory().unwrap(),
+        History::open(path).unwrap(),
+        2,
+    )
+    .unwrap();
+    rusqlite::Connection::open(path)
+        .unwrap()
+        .execute("DROP TABLE messages", ())
+        .unwrap();
+
+    // the client is logged in and joined without any history
+    let register = Command::Register("alice".to_owned(), "correct horse".to_owned());
+    let events = events_for(client(1), state.handle(client(1), register).unwrap());
+    assert_eq!(events, vec![Event::Welcome("alice".to_owned())]);
+    let deliveries = state
+        .handle(client(1), Command::Join("rust".to_owned()))
+        .unwrap();
+    let events = events_for(client(1), deliveries);
+    assert_eq!(events, vec![Event::Joined("rust".to_owned())]);
+
+    drop(state);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn direct_message_ids_are_not_reused() {
+    let path = std::env::temp_dir().join(format!("chat-history-ids-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    _ = std::fs::remove_file(path);
+
+    let state = State::new(
+        UserStore::open_in_memory().unwrap(),
+        History::open(path).unwrap(),
+        2,
+    )
+    .unwrap();
+    for (port, name) in [(1, "alice"), (2, "bob")] {
+        let register = Command::Register(name.to_owned(), "correct horse".to_owned());
+        state.handle(client(port), register).unwrap();
+    }
+    state
+        .handle(client(1), send(Target::Room(LOBBY.to_owned()), "hello"))
+        .unwrap();
+    let deliveries = state
+        .handle(client(1), send(Target::User("bob".to_owned()), "psst"))
+        .unwrap();
+    let Some(Event::Message(direct)) = events_for(client(2), deliveries).pop() else {
+        panic!("direct message not delivered");
+    };
+    drop(state);
+
+    // numbering continues after the direct message, the newest stored one is older
+    let history = History::open(path).unwrap();
+    assert_eq!(history.last_id().unwrap(), direct.id);
+    history.record_id(direct.id - 1).unwrap();
+    assert_eq!(history.last_id().unwrap(), direct.id);
+
+    drop(history);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+use chat_server::{queue_deliveries, Hub, Metrics};
+use chatlib::{Ack, Codec, Command, Event, Message, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn send(id: u64, target: Target) -> Command {
+    Command::Send(id, target, Message::new_text_message("hello"))
+}
+
+/// hub with alice, bob and carol registered
+fn hub() -> Hub {
+    let mut hub = Hub::new();
+    for (port, name) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+        hub.login(client(port), name.to_owned());
+    }
+    hub
+}
+
+#[test]
+fn accepted_and_delivered() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), send(7, Target::Room(LOBBY.to_owned())));
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(deliveries[0].event, Event::Ack(7, Ack::Accepted(1)));
+    assert_eq!(deliveries[1].ack, Some((client(1), 7)));
+
+    // carol's queue refuses the message, the sender is not counted
+    let mut queued = Vec::new();
+    queue_deliveries(
+        deliveries,
+        &Metrics::new().unwrap(),
+        |client_socket, frames| {
+            let frame = frames.frame(Codec::Bincode)?;
+            queued.push((client_socket, Event::from_bytes(&frame).unwrap()));
+            Ok(client_socket != client(3))
+        },
+        |_| panic!("nobody is disconnected"),
+    )
+    .unwrap();
+
+    let acks = queued
+        .into_iter()
+        .filter(|(_, event)| matches!(event, Event::Ack(..)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        acks,
+        vec![
+            (client(1), Event::Ack(7, Ack::Accepted(1))),
+            (client(1), Event::Ack(7, Ack::Delivered(1))),
+        ]
+    );
+}
+
+#[test]
+fn resent_message_is_not_distributed_again() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), send(7, Target::User("bob".to_owned())));
+    assert_eq!(deliveries[0].event, Event::Ack(7, Ack::Accepted(1)));
+
+    // a reconnected client resends the message under the same id
+    hub.disconnect(client(1));
+    hub.login(client(4), "alice".to_owned());
+    let deliveries = hub.handle(client(4), send(7, Target::User("bob".to_owned())));
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].recipients, vec![client(4)]);
+    assert_eq!(deliveries[0].event, Event::Ack(7, Ack::Accepted(1)));
+
+    // ids are remembered per user
+    let deliveries = hub.handle(client(2), send(7, Target::User("alice".to_owned())));
+    assert_eq!(deliveries[0].event, Event::Ack(7, Ack::Accepted(2)));
+}
+
+#[test]
+fn rejected() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), send(1, Target::Room("rust".to_owned())));
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].ack, None);
+    assert!(
+        matches!(&deliveries[0].event, Event::Ack(1, Ack::Rejected(e)) if e.contains("not a member"))
+    );
+
+    let deliveries = hub.handle(client(1), send(2, Target::User("nobody".to_owned())));
+    assert!(
+        matches!(&deliveries[0].event, Event::Ack(2, Ack::Rejected(e)) if e.contains("not connected"))
+    );
+
+    // a rejected id is not remembered, the message can be sent once the problem is fixed
+    let deliveries = hub.handle(client(1), send(2, Target::User("bob".to_owned())));
+    assert_eq!(deliveries[0].event, Event::Ack(2, Ack::Accepted(1)));
+}
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+use chat_server::Hub;
+use chatlib::{Ack, Command, Event, Message, SealedMessage, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn dm(user: &str, text: &str) -> Command {
+    Command::Send(
+        1,
+        Target::User(user.to_owned()),
+        Message::new_text_message(text),
+    )
+}
+
+#[test]
+fn direct_message() {
+    let mut hub = Hub::new();
+    for (port, name) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+        hub.login(client(port), name.to_owned());
+    }
+
+    let deliveries = hub.handle(client(1), dm("bob", "psst"));
+
+    // only the addressee and the sender receive the message
+    assert_eq!(deliveries.len(), 2);
+    let mut recipients = deliveries[1].recipients.clone();
+    recipients.sort();
+    assert_eq!(recipients, vec![client(1), client(2)]);
+
+    let Event::Message(envelope) = &deliveries[1].event else {
+        panic!("unexpected event {:?}", deliveries[1].event);
+    };
+    assert_eq!(envelope.sender, "alice");
+    assert_eq!(envelope.target, Target::User("bob".to_owned()));
+}
+
+#[test]
+fn direct_message_to_unknown_user() {
+    let mut hub = Hub::new();
+    hub.login(client(1), "alice".to_owned());
+
+    let deliveries = hub.handle(client(1), dm("nobody", "psst"));
+
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(1, Ack::Rejected(_))
+    ));
+}
+
+#[test]
+fn identity_key_exchange() {
+    let mut hub = Hub::new();
+    hub.login(client(1), "alice".to_owned());
+    hub.login(client(2), "bob".to_owned());
+
+    // publishing is silent
+    assert!(hub
+        .handle(client(2), Command::PublishKey([7; 32]))
+        .is_empty());
+
+    let deliveries = hub.handle(client(1), Command::GetKey("bob".to_owned()));
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(
+        deliveries[0].event,
+        Event::IdentityKey("bob".to_owned(), Some([7; 32]))
+    );
+
+    // users without a key and disconnected users have none
+    let deliveries = hub.handle(client(2), Command::GetKey("alice".to_owned()));
+    assert_eq!(
+        deliveries[0].event,
+        Event::IdentityKey("alice".to_owned(), None)
+    );
+    hub.disconnect(client(2));
+    let deliveries = hub.handle(client(1), Command::GetKey("bob".to_owned()));
+    assert_eq!(
+        deliveries[0].event,
+        Event::IdentityKey("bob".to_owned(), None)
+    );
+}
+
+#[test]
+fn sealed_message_to_room_is_refused() {
+    let mut hub = Hub::new();
+    hub.login(client(1), "alice".to_owned());
+
+    let sealed = Message::Sealed(SealedMessage {
+        sender_key: [1; 32],
+        recipient_key: [2; 32],
+        nonce: [3; 24],
+        ciphertext: vec![4; 16],
+    });
+    let deliveries = hub.handle(
+        client(1),
+        Command::Send(1, Target::Room(LOBBY.to_owned()), sealed),
+    );
+
+    assert_eq!(deliveries.len(), 1);
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(1, Ack::Rejected(_))
+    ));
+}
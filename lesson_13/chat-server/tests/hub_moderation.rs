@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+
+use chat_server::Hub;
+use chatlib::{Ack, Command, Event, Message, Presence, Sanction, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// admin alice, bob and carol in the lobby, dave alone in another room
+fn hub() -> Hub {
+    let mut hub = Hub::new().with_admins(vec!["alice".to_owned()]);
+    for (port, name) in [(1, "alice"), (2, "bob"), (3, "carol"), (4, "dave")] {
+        hub.login(client(port), name.to_owned());
+    }
+    hub.handle(client(4), Command::Leave(LOBBY.to_owned()));
+    hub.handle(client(4), Command::Join("rust".to_owned()));
+    hub
+}
+
+fn moderate(sanction: Sanction) -> Command {
+    Command::Moderate(sanction)
+}
+
+fn text(id: u64) -> Command {
+    Command::Send(
+        id,
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message("hello"),
+    )
+}
+
+#[test]
+fn only_admins_moderate() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(
+        client(2),
+        moderate(Sanction::Kick("carol".to_owned(), "spam".to_owned())),
+    );
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("Only admins can moderate".to_owned())
+    );
+
+    // admins can not be sanctioned, nor can users who are not around
+    let deliveries = hub.handle(client(1), moderate(Sanction::Mute("alice".to_owned(), 60)));
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("Admin `alice` can not be sanctioned".to_owned())
+    );
+    let deliveries = hub.handle(
+        client(1),
+        moderate(Sanction::Kick("erin".to_owned(), String::new())),
+    );
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("User `erin` is not connected".to_owned())
+    );
+
+    // nobody can take the name of an admin
+    hub.disconnect(client(1));
+    let deliveries = hub.handle(client(2), Command::Nick("alice".to_owned()));
+    assert_eq!(
+        deliveries[0].event,
+        Event::Error("Username `alice` is reserved".to_owned())
+    );
+}
+
+#[test]
+fn kick_disconnects_user() {
+    let mut hub = hub();
+    let kick = Sanction::Kick("bob".to_owned(), "spam".to_owned());
+
+    let deliveries = hub.handle(client(1), moderate(kick.clone()));
+    let notice = Event::Sanctioned("alice".to_owned(), kick);
+
+    // the room is told, the kicked user is told before being disconnected
+    assert_eq!(deliveries[0].recipients, vec![client(1), client(3)]);
+    assert_eq!(deliveries[0].event, notice);
+    assert!(!deliveries[0].disconnect);
+    assert_eq!(deliveries[1].recipients, vec![client(2)]);
+    assert_eq!(deliveries[1].event, notice);
+    assert!(deliveries[1].disconnect);
+    assert_eq!(
+        deliveries[2].event,
+        Event::Presence("bob".to_owned(), Presence::Offline)
+    );
+
+    assert_eq!(hub.username(client(2)), None);
+    assert!(hub.disconnect(client(2)).is_empty());
+}
+
+#[test]
+fn ban_by_address_spares_admins() {
+    let mut hub = hub();
+    let ban = Sanction::Ban("127.0.0.1".to_owned(), "spam".to_owned());
+
+    let deliveries = hub.handle(client(1), moderate(ban));
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(
+        deliveries[1].recipients,
+        vec![client(2), client(3), client(4)]
+    );
+    assert!(deliveries[1].disconnect);
+    assert_eq!(hub.username(client(1)), Some("alice"));
+    assert_eq!(hub.username(client(3)), None);
+
+    // nobody to kick, the admin is told anyway
+    let unban = Sanction::Unban("127.0.0.1".to_owned());
+    let deliveries = hub.handle(client(1), moderate(unban.clone()));
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(
+        deliveries[0].event,
+        Event::Sanctioned("alice".to_owned(), unban)
+    );
+}
+
+#[test]
+fn muted_user_can_not_send() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), moderate(Sanction::Mute("dave".to_owned(), 60)));
+    assert_eq!(deliveries[0].recipients, vec![client(1), client(4)]);
+
+    // the mute sticks to the renamed user
+    hub.handle(client(4), Command::Nick("erin".to_owned()));
+    hub.handle(client(4), Command::Join(LOBBY.to_owned()));
+    let deliveries = hub.handle(client(4), text(1));
+    assert_eq!(
+        deliveries[0].event,
+        Event::Ack(
+            1,
+            Ack::Rejected("You are muted for 60 more seconds".to_owned())
+        )
+    );
+
+    // other commands still work
+    let deliveries = hub.handle(client(4), Command::Rooms);
+    assert!(matches!(deliveries[0].event, Event::Rooms(_)));
+
+    hub.handle(client(1), moderate(Sanction::Mute("erin".to_owned(), 0)));
+    let deliveries = hub.handle(client(4), text(2));
+    assert_eq!(deliveries[0].event, Event::Ack(2, Ack::Accepted(1)));
+}
@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+
+use chat_server::Hub;
+use chatlib::{Command, Event, Presence};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn presence_is_broadcast_to_others() {
+    let mut hub = Hub::new();
+
+    // nobody else is online yet
+    let deliveries = hub.login(client(1), "alice".to_owned());
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+    assert!(deliveries[1].recipients.is_empty());
+
+    let deliveries = hub.login(client(2), "bob".to_owned());
+    assert_eq!(deliveries[1].recipients, vec![client(1)]);
+    assert_eq!(
+        deliveries[1].event,
+        Event::Presence("bob".to_owned(), Presence::Online)
+    );
+
+    let deliveries = hub.disconnect(client(2));
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(
+        deliveries[0].event,
+        Event::Presence("bob".to_owned(), Presence::Offline)
+    );
+
+    // clients not logged in come and go unnoticed
+    assert!(hub.disconnect(client(3)).is_empty());
+}
+
+#[test]
+fn who_lists_online_users() {
+    let mut hub = Hub::new();
+    hub.login(client(1), "bob".to_owned());
+    hub.login(client(2), "alice".to_owned());
+
+    let deliveries = hub.handle(client(1), Command::Who);
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    let Event::Users(users) = &deliveries[0].event else {
+        panic!("user list expected");
+    };
+    let names = users
+        .iter()
+        .map(|user| user.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["alice", "bob"]);
+    assert!(users.iter().all(|user| user.idle == 0));
+
+    // renamed users are listed under the new name
+    hub.handle(client(1), Command::Nick("carol".to_owned()));
+    let deliveries = hub.handle(client(2), Command::Who);
+    let Event::Users(users) = &deliveries[0].event else {
+        panic!("user list expected");
+    };
+    let names = users
+        .iter()
+        .map(|user| user.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["alice", "carol"]);
+}
@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+
+use chat_server::Hub;
+use chatlib::{Ack, Command, Event, Message, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn send(room: &str, text: &str) -> Command {
+    Command::Send(
+        1,
+        Target::Room(room.to_owned()),
+        Message::new_text_message(text),
+    )
+}
+
+/// hub with alice and bob registered
+fn hub() -> Hub {
+    let mut hub = Hub::new();
+    hub.login(client(1), "alice".to_owned());
+    hub.login(client(2), "bob".to_owned());
+    hub
+}
+
+#[test]
+fn lobby_broadcast() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), send(LOBBY, "hello"));
+
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].recipients, vec![client(1)]);
+    assert_eq!(deliveries[0].event, Event::Ack(1, Ack::Accepted(1)));
+    assert_eq!(deliveries[1].recipients, vec![client(1), client(2)]);
+    let Event::Message(envelope) = &deliveries[1].event else {
+        panic!("unexpected event {:?}", deliveries[1].event);
+    };
+    assert_eq!(envelope.id, 1);
+    assert_eq!(envelope.sender, "alice");
+    assert_eq!(envelope.target, Target::Room(LOBBY.to_owned()));
+}
+
+#[test]
+fn room_membership() {
+    let mut hub = hub();
+
+    let deliveries = hub.handle(client(1), Command::Join("rust".to_owned()));
+    assert_eq!(deliveries[0].event, Event::Joined("rust".to_owned()));
+
+    // only members receive room messages
+    let deliveries = hub.handle(client(1), send("rust", "hello"));
+    assert_eq!(deliveries[1].recipients, vec![client(1)]);
+
+    // non members cannot send to the room
+    let deliveries = hub.handle(client(2), send("rust", "hello"));
+    assert_eq!(deliveries[0].recipients, vec![client(2)]);
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(1, Ack::Rejected(_))
+    ));
+
+    let deliveries = hub.handle(client(1), Command::Leave("rust".to_owned()));
+    assert_eq!(deliveries[0].event, Event::Left("rust".to_owned()));
+
+    // empty rooms are removed, lobby stays
+    let deliveries = hub.handle(client(2), Command::Rooms);
+    let Event::Rooms(rooms) = &deliveries[0].event else {
+        panic!("unexpected event {:?}", deliveries[0].event);
+    };
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].name, LOBBY);
+    assert_eq!(rooms[0].members, 2);
+    assert!(rooms[0].joined);
+}
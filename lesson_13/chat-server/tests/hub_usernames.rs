@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use chat_server::Hub;
+use chatlib::{Ack, Command, Event, Message, Target, LOBBY};
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn unique_usernames() {
+    let mut hub = Hub::new();
+
+    let deliveries = hub.login(client(1), "alice".to_owned());
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+    assert_eq!(hub.username(client(1)), Some("alice"));
+
+    // account is in use by a single connection, second alice stays logged out
+    let deliveries = hub.login(client(2), "alice".to_owned());
+    assert_eq!(deliveries[0].recipients, vec![client(2)]);
+    assert!(matches!(&deliveries[0].event, Event::Error(e) if e.contains("already connected")));
+    assert_eq!(hub.username(client(2)), None);
+
+    // logged out clients cannot send messages
+    let deliveries = hub.handle(
+        client(2),
+        Command::Send(
+            1,
+            Target::Room(LOBBY.to_owned()),
+            Message::new_text_message("hi"),
+        ),
+    );
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(1, Ack::Rejected(_))
+    ));
+
+    // heartbeats are answered before logging in
+    let deliveries = hub.handle(client(2), Command::Ping(7));
+    assert_eq!(deliveries[0].recipients, vec![client(2)]);
+    assert_eq!(deliveries[0].event, Event::Pong(7));
+    assert!(hub.handle(client(2), Command::Pong(1)).is_empty());
+
+    // username is released on disconnect
+    hub.disconnect(client(1));
+    let deliveries = hub.login(client(2), "alice".to_owned());
+    assert_eq!(deliveries[0].event, Event::Welcome("alice".to_owned()));
+}
+
+#[test]
+fn nick_change() {
+    let mut hub = Hub::new();
+    hub.login(client(1), "alice".to_owned());
+    hub.login(client(2), "bob".to_owned());
+
+    let deliveries = hub.handle(client(1), Command::Nick("bob".to_owned()));
+    assert!(matches!(deliveries[0].event, Event::Error(_)));
+
+    // rename notice goes to everybody
+    let deliveries = hub.handle(client(1), Command::Nick("alicia".to_owned()));
+    let mut recipients = deliveries[0].recipients.clone();
+    recipients.sort();
+    assert_eq!(recipients, vec![client(1), client(2)]);
+    assert_eq!(
+        deliveries[0].event,
+        Event::Renamed("alice".to_owned(), "alicia".to_owned())
+    );
+
+    // direct messages follow the new name
+    let deliveries = hub.handle(
+        client(2),
+        Command::Send(
+            1,
+            Target::User("alicia".to_owned()),
+            Message::new_text_message("hi"),
+        ),
+    );
+    assert!(deliveries[1].recipients.contains(&client(1)));
+    let deliveries = hub.handle(
+        client(2),
+        Command::Send(
+            2,
+            Target::User("alice".to_owned()),
+            Message::new_text_message("hi"),
+        ),
+    );
+    assert!(matches!(
+        deliveries[0].event,
+        Event::Ack(2, Ack::Rejected(_))
+    ));
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use chatlib::{Ack, Command, Event, Message, Presence, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, READ_TIMEOUT, SERVERS};
+
+/// response of the metrics endpoint, retried until the endpoint is up
+fn get(port: u16, path: &str) -> String {
+    let started = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(e) => {
+                assert!(started.elapsed() < READ_TIMEOUT, "{}", e);
+                sleep(Duration::from_millis(50));
+            }
+        }
+    };
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_are_served() {
+    for bin in SERVERS {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let env = [
+            ("METRICS_PORT", port.to_string()),
+            ("METRICS_HOSTNAME", "127.0.0.1".to_owned()),
+        ];
+        let server = TestServer::start(bin, test_dir("metrics", bin), &env);
+        let (mut alice, mut alice_writer) = server.register("alice");
+        let (mut bob, _) = server.register("bob");
+        assert_eq!(
+            receive(&mut alice),
+            Event::Presence("bob".to_owned(), Presence::Online)
+        );
+        send(
+            &mut alice_writer,
+            Command::Send(
+                1,
+                Target::Room(LOBBY.to_owned()),
+                Message::new_text_message("hi"),
+            ),
+        );
+        assert!(matches!(receive(&mut bob), Event::Message(_)));
+        // counted before the sender is told
+        while receive(&mut alice) != Event::Ack(1, Ack::Delivered(1)) {}
+
+        let response = get(port, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        for line in [
+            "chat_connected_clients 2",
+            r#"chat_commands_received_total{type="register"} 2"#,
+            r#"chat_commands_received_total{type="message_text"} 1"#,
+            r#"chat_events_sent_total{type="message_text"} 2"#,
+            r#"chat_events_sent_total{type="welcome"} 2"#,
+            "chat_broadcast_latency_seconds_count 1",
+        ] {
+            assert!(response.contains(line), "{}: {} missing", bin, line);
+        }
+        assert!(response.contains("chat_sent_bytes_total"), "{}", bin);
+        assert!(
+            response.contains("chat_outbound_queue_depth_bucket"),
+            "{}",
+            bin
+        );
+
+        assert!(get(port, "/").starts_with("HTTP/1.0 404"), "{}", bin);
+    }
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use chat_server::{hash_password, AuthError};
+use chatlib::{Command, Event, FrameReader, Presence, Sanction, Stream};
+use common::{receive, send, test_dir, TestServer, SERVERS};
+
+fn admin_env() -> [(&'static str, String); 2] {
+    [
+        ("ADMINS", "root".to_owned()),
+        (
+            "ADMIN_PASSWORD_HASH",
+            hash_password("correct horse").unwrap(),
+        ),
+    ]
+}
+
+/// the connection is closed, the sanction notice may or may not make it
+fn assert_disconnected(reader: &mut FrameReader<Stream>, bin: &str) {
+    for _ in 0..2 {
+        if reader.read_frame().is_err() {
+            return;
+        }
+    }
+    panic!("{}: connection still open", bin);
+}
+
+fn ban(target: &str) -> Sanction {
+    Sanction::Ban(target.to_owned(), "spam".to_owned())
+}
+
+#[test]
+fn kicked_and_banned_users_are_disconnected() {
+    for bin in SERVERS {
+        let mut server = TestServer::start(bin, test_dir("moderation", bin), &admin_env());
+
+        // admin accounts are created by the server, nobody can register them
+        let (mut mallory, mut mallory_writer) = server.connect();
+        send(
+            &mut mallory_writer,
+            Command::Register("root".to_owned(), "battery staple".to_owned()),
+        );
+        assert_eq!(
+            receive(&mut mallory),
+            Event::Error("Username `root` is reserved".to_owned()),
+            "{}",
+            bin
+        );
+
+        let (mut root, mut root_writer) = server.login("root");
+        let (mut bob, _) = server.register("bob");
+        let (mut carol, _) = server.register("carol");
+        for name in ["bob", "carol"] {
+            assert_eq!(
+                receive(&mut root),
+                Event::Presence(name.to_owned(), Presence::Online)
+            );
+        }
+        assert_eq!(
+            receive(&mut bob),
+            Event::Presence("carol".to_owned(), Presence::Online)
+        );
+
+        let kick = Sanction::Kick("bob".to_owned(), "spam".to_owned());
+        send(&mut root_writer, Command::Moderate(kick.clone()));
+        let notice = Event::Sanctioned("root".to_owned(), kick);
+        for reader in [&mut root, &mut carol] {
+            assert_eq!(receive(reader), notice, "{}", bin);
+            assert_eq!(
+                receive(reader),
+                Event::Presence("bob".to_owned(), Presence::Offline)
+            );
+        }
+        assert_disconnected(&mut bob, bin);
+
+        send(&mut root_writer, Command::Moderate(ban("carol")));
+        assert_eq!(
+            receive(&mut root),
+            Event::Sanctioned("root".to_owned(), ban("carol"))
+        );
+        assert_disconnected(&mut carol, bin);
+
+        // the ban survives a restart
+        assert!(server.terminate().success(), "{}", bin);
+        let mut server = TestServer::start(bin, server.dir.clone(), &admin_env());
+        let (mut carol, mut carol_writer) = server.connect();
+        send(
+            &mut carol_writer,
+            Command::Login("carol".to_owned(), "correct horse".to_owned()),
+        );
+        assert_eq!(
+            receive(&mut carol),
+            Event::Error(AuthError::Banned("carol".to_owned()).to_string()),
+            "{}",
+            bin
+        );
+
+        // admins stay connected, nobody else can log in from the address
+        let (mut root, mut root_writer) = server.connect();
+        send(
+            &mut root_writer,
+            Command::Login("root".to_owned(), "correct horse".to_owned()),
+        );
+        assert_eq!(receive(&mut root), Event::Welcome("root".to_owned()));
+        receive(&mut root);
+        send(&mut root_writer, Command::Moderate(ban("127.0.0.1")));
+        assert_eq!(
+            receive(&mut root),
+            Event::Sanctioned("root".to_owned(), ban("127.0.0.1"))
+        );
+        let (mut dave, mut dave_writer) = server.connect();
+        send(
+            &mut dave_writer,
+            Command::Register("dave".to_owned(), "correct horse".to_owned()),
+        );
+        assert_eq!(
+            receive(&mut dave),
+            Event::Error(AuthError::Banned("127.0.0.1".to_owned()).to_string()),
+            "{}",
+            bin
+        );
+        send(&mut root_writer, Command::Who);
+        assert!(matches!(receive(&mut root), Event::Users(users) if users.len() == 1));
+
+        // admins on the address can still log in after a restart and lift the ban
+        drop(root_writer);
+        assert!(server.terminate().success(), "{}", bin);
+        let server = TestServer::start(bin, server.dir.clone(), &admin_env());
+        let (mut root, mut root_writer) = server.login("root");
+        let unban = Sanction::Unban("127.0.0.1".to_owned());
+        send(&mut root_writer, Command::Moderate(unban.clone()));
+        assert_eq!(
+            receive(&mut root),
+            Event::Sanctioned("root".to_owned(), unban)
+        );
+        server.register("dave");
+    }
+}
@@ -0,0 +1,151 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use chat_server::{EventFrames, Frame, OutboundQueue, Queued, SlowClientPolicy};
+use chatlib::{Codec, Event};
+
+fn queue(policy: SlowClientPolicy) -> OutboundQueue {
+    OutboundQueue::new(SocketAddr::from(([127, 0, 0, 1], 1)), 2, policy)
+}
+
+fn frame(byte: u8) -> Frame {
+    Arc::new(vec![byte])
+}
+
+#[test]
+fn drop_oldest() {
+    let queue = queue(SlowClientPolicy::DropOldest);
+    assert_eq!(queue.push(frame(1)), Queued::Queued);
+    assert_eq!(queue.push(frame(2)), Queued::Queued);
+    assert_eq!(queue.push(frame(3)), Queued::DroppedOldest);
+    assert_eq!(queue.len(), 2);
+
+    assert_eq!(queue.pop_blocking(), Some(frame(2)));
+    assert_eq!(queue.pop_blocking(), Some(frame(3)));
+    assert!(!queue.is_closed());
+}
+
+#[test]
+fn disconnect() {
+    let queue = queue(SlowClientPolicy::Disconnect);
+    queue.push(frame(1));
+    queue.push(frame(2));
+    assert_eq!(queue.push(frame(3)), Queued::Overflow);
+
+    // nothing more is written to the client
+    assert!(queue.is_closed());
+    assert!(queue.is_empty());
+    assert_eq!(queue.push(frame(4)), Queued::Closed);
+    assert_eq!(queue.pop_blocking(), None);
+}
+
+#[test]
+fn close_wakes_writer() {
+    let queue = Arc::new(queue(SlowClientPolicy::Disconnect));
+    let writer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            let mut written = vec![];
+            while let Some(frame) = queue.pop_blocking() {
+                written.push(frame);
+            }
+            written
+        })
+    };
+
+    queue.push(frame(1));
+    queue.close();
+    // frames queued before closing are still written
+    assert_eq!(writer.join().unwrap(), vec![frame(1)]);
+}
+
+#[tokio::test]
+async fn async_writer() {
+    let queue = Arc::new(queue(SlowClientPolicy::DropOldest));
+    let writer = {
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            let mut written = vec![];
+            while let Some(frame) = queue.pop().await {
+                written.push(frame);
+            }
+            written
+        })
+    };
+
+    queue.push(frame(1));
+    queue.close();
+    assert_eq!(writer.await.unwrap(), vec![frame(1)]);
+}
+
+#[tokio::test]
+async fn overflow_is_signalled() {
+    let queue = queue(SlowClientPolicy::Disconnect);
+    queue.push(frame(1));
+    queue.push(frame(2));
+    queue.push(frame(3));
+    queue.overflowed().await;
+}
+
+#[test]
+fn bounded_by_bytes() {
+    let queue = OutboundQueue::new(
+        SocketAddr::from(([127, 0, 0, 1], 1)),
+        100,
+        SlowClientPolicy::DropOldest,
+    )
+    .with_max_bytes(10);
+    assert_eq!(queue.push(Arc::new(vec![1; 4])), Queued::Queued);
+    assert_eq!(queue.push(Arc::new(vec![2; 4])), Queued::Queued);
+    assert_eq!(queue.bytes(), 8);
+
+    // the oldest frames make room for a new one
+    assert_eq!(queue.push(Arc::new(vec![3; 4])), Queued::DroppedOldest);
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.bytes(), 8);
+
+    // a single frame larger than the bound is still queued on its own
+    assert_eq!(queue.push(Arc::new(vec![4; 20])), Queued::DroppedOldest);
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.pop_blocking(), Some(Arc::new(vec![4; 20])));
+    assert_eq!(queue.bytes(), 0);
+
+    let queue = OutboundQueue::new(
+        SocketAddr::from(([127, 0, 0, 1], 1)),
+        100,
+        SlowClientPolicy::Disconnect,
+    )
+    .with_max_bytes(10);
+    queue.push(Arc::new(vec![1; 8]));
+    assert_eq!(queue.push(Arc::new(vec![2; 4])), Queued::Overflow);
+    assert!(queue.is_closed());
+}
+
+#[test]
+fn events_encoded_once_per_codec() {
+    let event = Event::Error("oops".to_owned());
+    let mut frames = EventFrames::new(&event);
+    let bincode = queue(SlowClientPolicy::DropOldest);
+    let json = queue(SlowClientPolicy::DropOldest).with_codec(Codec::Json);
+    let negotiated = queue(SlowClientPolicy::DropOldest);
+    negotiated.negotiated(Codec::Json);
+    assert_eq!(negotiated.codec(), Codec::Json);
+    let answer = negotiated.pop_blocking().unwrap();
+    assert_eq!(Codec::parse_answer(&answer).unwrap().unwrap(), Codec::Json);
+
+    for queue in [&bincode, &json, &negotiated] {
+        assert_eq!(queue.push_event(&mut frames).unwrap(), Queued::Queued);
+    }
+
+    let bincode = bincode.pop_blocking().unwrap();
+    let json = json.pop_blocking().unwrap();
+    assert_eq!(Event::from_bytes(&bincode).unwrap(), event);
+    assert_eq!(Codec::Json.decode::<Event>(&json).unwrap(), event);
+    // clients speaking the same codec share the encoded frame
+    assert!(Arc::ptr_eq(&json, &negotiated.pop_blocking().unwrap()));
+    assert!(Arc::ptr_eq(
+        &bincode,
+        &frames.frame(Codec::Bincode).unwrap()
+    ));
+}
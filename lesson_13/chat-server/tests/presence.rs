@@ -0,0 +1,38 @@
+mod common;
+
+use chatlib::{Command, Event, Presence};
+use common::{receive, send, test_dir, TestServer, SERVERS};
+
+#[test]
+fn users_see_each_other_come_and_go() {
+    for bin in SERVERS {
+        let server = TestServer::start(bin, test_dir("presence", bin), &[]);
+        let (mut alice, mut alice_writer) = server.register("alice");
+        let bob = server.register("bob");
+        assert_eq!(
+            receive(&mut alice),
+            Event::Presence("bob".to_owned(), Presence::Online),
+            "{}",
+            bin
+        );
+
+        send(&mut alice_writer, Command::Who);
+        let Event::Users(users) = receive(&mut alice) else {
+            panic!("{}: user list expected", bin);
+        };
+        let names = users
+            .iter()
+            .map(|user| user.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["alice", "bob"], "{}", bin);
+
+        // the server notices the closed connection
+        drop(bob);
+        assert_eq!(
+            receive(&mut alice),
+            Event::Presence("bob".to_owned(), Presence::Offline),
+            "{}",
+            bin
+        );
+    }
+}
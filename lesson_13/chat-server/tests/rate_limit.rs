@@ -0,0 +1,170 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use chat_server::{Limit, RateLimiter, RateLimits};
+use chatlib::{Command, Message, SealedMessage, Target, LOBBY};
+
+fn limits(messages: (u64, u64), bytes: (u64, u64)) -> RateLimits {
+    RateLimits {
+        messages,
+        bytes,
+        warnings: 2,
+        mute_duration: Duration::from_millis(300),
+        mutes: 1,
+    }
+}
+
+fn text() -> Command {
+    Command::Send(
+        1,
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message("hello"),
+    )
+}
+
+fn chunk() -> Command {
+    Command::Send(
+        1,
+        Target::Room(LOBBY.to_owned()),
+        Message::FileChunk(1, vec![0; 16]),
+    )
+}
+
+/// direct message sealed end to end, the server can not tell a file chunk from text
+fn sealed(len: usize) -> Command {
+    let sealed = SealedMessage {
+        sender_key: [1; 32],
+        recipient_key: [2; 32],
+        nonce: [0; 24],
+        ciphertext: vec![0; len],
+    };
+    Command::Send(1, Target::User("bob".to_owned()), Message::Sealed(sealed))
+}
+
+#[test]
+fn flooding_is_warned_muted_then_disconnected() {
+    let mut limiter = RateLimiter::new(limits((10, 3), (0, 0)));
+
+    for _ in 0..3 {
+        assert_eq!(limiter.check(&text(), 10), Limit::Allowed);
+    }
+    assert_eq!(limiter.check(&text(), 10), Limit::Warned);
+    assert_eq!(limiter.check(&text(), 10), Limit::Warned);
+    assert_eq!(
+        limiter.check(&text(), 10),
+        Limit::Muted(Duration::from_millis(300))
+    );
+
+    // heartbeats within the limit pass even while muted
+    sleep(Duration::from_millis(100));
+    assert_eq!(limiter.check(&Command::Ping(1), 10), Limit::Allowed);
+
+    // flooding on through the mute
+    assert!(matches!(limiter.check(&text(), 10), Limit::Muted(_)));
+    assert!(matches!(limiter.check(&text(), 10), Limit::Muted(_)));
+    assert_eq!(limiter.check(&text(), 10), Limit::Disconnect);
+}
+
+#[test]
+fn mute_ends_and_violations_are_forgiven() {
+    let mut limiter = RateLimiter::new(limits((10, 1), (0, 0)));
+
+    assert_eq!(limiter.check(&text(), 10), Limit::Allowed);
+    for _ in 0..2 {
+        assert_eq!(limiter.check(&text(), 10), Limit::Warned);
+    }
+    assert!(matches!(limiter.check(&text(), 10), Limit::Muted(_)));
+
+    // commands within the limit are refused until the mute ends
+    sleep(Duration::from_millis(150));
+    assert!(matches!(limiter.check(&text(), 10), Limit::Muted(_)));
+    sleep(Duration::from_millis(200));
+    assert_eq!(limiter.check(&text(), 10), Limit::Allowed);
+
+    // a violation long after the last one starts over with warnings
+    sleep(Duration::from_millis(300));
+    assert_eq!(limiter.check(&text(), 10), Limit::Allowed);
+    assert_eq!(limiter.check(&text(), 10), Limit::Warned);
+}
+
+#[test]
+fn bytes_are_limited() {
+    let mut limiter = RateLimiter::new(limits((0, 0), (1000, 1000)));
+
+    for _ in 0..10 {
+        assert_eq!(limiter.check(&text(), 100), Limit::Allowed);
+    }
+    assert_eq!(limiter.check(&text(), 100), Limit::Warned);
+
+    // a full bucket covers a frame larger than the burst, the debt is paid off first
+    limiter = RateLimiter::new(limits((0, 0), (1000, 1000)));
+    assert_eq!(limiter.check(&text(), 5000), Limit::Allowed);
+    assert_eq!(limiter.check(&text(), 10), Limit::Warned);
+}
+
+#[test]
+fn file_chunks_are_throttled() {
+    let mut limiter = RateLimiter::new(limits((1, 1), (1000, 1000)));
+
+    // file chunks count as bytes only, those over the limit wait instead of being refused
+    for _ in 0..10 {
+        assert_eq!(limiter.check(&chunk(), 100), Limit::Allowed);
+    }
+    let Limit::Throttled(delay) = limiter.check(&chunk(), 100) else {
+        panic!("chunk over the limit not throttled");
+    };
+    assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+
+    // the next chunk waits for the previous one as well
+    let Limit::Throttled(delay) = limiter.check(&chunk(), 100) else {
+        panic!("chunk over the limit not throttled");
+    };
+    assert!(delay > Duration::from_millis(150) && delay <= Duration::from_millis(200));
+
+    // throttling does not count as flooding
+    for _ in 0..20 {
+        assert!(matches!(limiter.check(&chunk(), 100), Limit::Throttled(_)));
+    }
+}
+
+#[test]
+fn sealed_chunks_are_throttled() {
+    let mut limiter = RateLimiter::new(limits((1, 5), (1 << 30, 1 << 30)));
+    let chunk = sealed(1 << 20);
+    let len = chunk.encode().unwrap().len();
+
+    // far more sealed chunks than the message burst, bulk frames count as bytes only
+    for _ in 0..50 {
+        assert_eq!(limiter.check(&chunk, len), Limit::Allowed);
+    }
+
+    // small sealed messages are still counted
+    for _ in 0..5 {
+        assert_eq!(limiter.check(&sealed(100), 200), Limit::Allowed);
+    }
+    assert_eq!(limiter.check(&sealed(100), 200), Limit::Warned);
+}
+
+#[test]
+fn heartbeats_are_limited() {
+    let mut limiter = RateLimiter::new(limits((10, 3), (0, 0)));
+
+    for id in 0..3 {
+        assert_eq!(limiter.check(&Command::Ping(id), 10), Limit::Allowed);
+    }
+    assert_eq!(limiter.check(&Command::Ping(3), 10), Limit::Warned);
+    assert_eq!(limiter.check(&Command::Pong(4), 10), Limit::Warned);
+    assert!(matches!(
+        limiter.check(&Command::Ping(5), 10),
+        Limit::Muted(_)
+    ));
+    assert!(matches!(
+        limiter.check(&Command::Ping(6), 10),
+        Limit::Muted(_)
+    ));
+    assert!(matches!(
+        limiter.check(&Command::Ping(7), 10),
+        Limit::Muted(_)
+    ));
+    assert_eq!(limiter.check(&Command::Ping(8), 10), Limit::Disconnect);
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use std::net::TcpStream;
+
+use chat_server::SHUTDOWN_NOTICE;
+use chatlib::{Ack, Command, Event, Message, Presence, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, SERVERS};
+
+#[test]
+fn clients_are_notified_on_shutdown() {
+    for bin in SERVERS {
+        let mut server = TestServer::start(bin, test_dir("shutdown", bin), &[]);
+        let (mut alice, mut alice_writer) = server.register("alice");
+        let (mut bob, _) = server.register("bob");
+        assert_eq!(
+            receive(&mut alice),
+            Event::Presence("bob".to_owned(), Presence::Online)
+        );
+
+        // both clients are chatting when the signal arrives
+        send(
+            &mut alice_writer,
+            Command::Send(
+                1,
+                Target::Room(LOBBY.to_owned()),
+                Message::new_text_message("bye"),
+            ),
+        );
+        assert!(matches!(
+            receive(&mut alice),
+            Event::Ack(1, Ack::Accepted(_))
+        ));
+        assert!(matches!(receive(&mut alice), Event::Message(_)));
+        assert_eq!(receive(&mut alice), Event::Ack(1, Ack::Delivered(1)));
+        assert!(matches!(receive(&mut bob), Event::Message(_)));
+
+        assert!(server.terminate().success(), "{}", bin);
+
+        for reader in [&mut alice, &mut bob] {
+            assert_eq!(receive(reader), Event::Shutdown(SHUTDOWN_NOTICE.to_owned()));
+            assert!(reader.read_frame().is_err());
+        }
+        assert!(TcpStream::connect(("127.0.0.1", server.port)).is_err());
+    }
+}
+
+#[test]
+fn idle_server_exits_on_signal() {
+    for bin in SERVERS {
+        let mut server = TestServer::start(bin, test_dir("idle-shutdown", bin), &[]);
+        assert!(server.terminate().success(), "{}", bin);
+    }
+}
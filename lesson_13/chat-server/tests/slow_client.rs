@@ -0,0 +1,103 @@
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use chatlib::{Command, Envelope, Event, FrameReader, FrameWriter, Message, Stream, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, READ_TIMEOUT, SERVERS};
+
+/// Messages sent while the slow client is not reading, far more than socket buffers hold
+const MESSAGES: usize = 320;
+
+type Connection = (FrameReader<Stream>, FrameWriter<Stream>);
+
+fn text(i: usize) -> Message {
+    Message::new_text_message(&format!("{} {}", i, "x".repeat(64 * 1024)))
+}
+
+/// a fast client chats in the lobby while the slow client never reads
+fn chat_next_to_slow_client(bin: &str, policy: &str) -> (TestServer, Connection, Connection) {
+    let env = [
+        ("OUTBOUND_QUEUE_SIZE", "8".to_owned()),
+        ("SLOW_CLIENT_POLICY", policy.to_owned()),
+        // the fast client sends far more than flood protection allows
+        ("RATE_MESSAGES", "0".to_owned()),
+    ];
+    let server = TestServer::start(bin, test_dir(policy, bin), &env);
+    let slow = server.register("slow");
+    let (mut reader, mut writer) = server.register("fast");
+
+    for i in 0..MESSAGES {
+        send(
+            &mut writer,
+            Command::Send(i as u64, Target::Room(LOBBY.to_owned()), text(i)),
+        );
+        // a stalled server fails the read with a timeout
+        let envelope = next_message(&mut reader);
+        assert_eq!(envelope.message, text(i));
+    }
+
+    (server, slow, (reader, writer))
+}
+
+/// next message, presence changes are skipped
+fn next_message(reader: &mut FrameReader<Stream>) -> Envelope {
+    loop {
+        match receive(reader) {
+            Event::Message(envelope) => return envelope,
+            Event::Presence(..) | Event::Ack(..) => continue,
+            event => panic!("message expected, got {:?}", event),
+        }
+    }
+}
+
+/// number of lobby members
+fn lobby_members((reader, writer): &mut Connection) -> usize {
+    send(writer, Command::Rooms);
+    loop {
+        if let Event::Rooms(rooms) = receive(reader) {
+            let lobby = rooms.iter().find(|room| room.name == LOBBY).unwrap();
+            return lobby.members;
+        }
+    }
+}
+
+#[test]
+fn slow_client_is_disconnected() {
+    for bin in SERVERS {
+        let (_server, (mut slow_reader, _), mut fast) = chat_next_to_slow_client(bin, "disconnect");
+
+        let started = Instant::now();
+        while lobby_members(&mut fast) > 1 {
+            assert!(
+                started.elapsed() < READ_TIMEOUT,
+                "{}: slow client stays",
+                bin
+            );
+            sleep(Duration::from_millis(50));
+        }
+
+        // events buffered before the disconnect are followed by the end of the stream
+        while slow_reader.read_frame().is_ok() {}
+    }
+}
+
+#[test]
+fn slow_client_misses_oldest_events() {
+    for bin in SERVERS {
+        let (_server, (mut slow_reader, _), mut fast) =
+            chat_next_to_slow_client(bin, "drop-oldest");
+        assert_eq!(lobby_members(&mut fast), 2, "{}", bin);
+
+        // the slow client catches up with the latest message
+        let mut received = 0;
+        loop {
+            let envelope = next_message(&mut slow_reader);
+            received += 1;
+            if envelope.message == text(MESSAGES - 1) {
+                break;
+            }
+        }
+        assert!(received < MESSAGES, "{}: nothing dropped", bin);
+    }
+}
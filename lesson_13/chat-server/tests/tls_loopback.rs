@@ -0,0 +1,79 @@
+mod common;
+
+use std::fs;
+use std::net::TcpStream;
+
+use chatlib::{
+    generate_self_signed_cert, tls_client_config_with_ca, Ack, Command, Event, FrameReader,
+    FrameWriter, Message, Stream, Target, TlsStream, LOBBY,
+};
+use common::{frames, receive, send, test_dir, TestServer};
+
+/// server binary running with a fresh certificate
+fn start_tls(bin: &str) -> TestServer {
+    let dir = test_dir("tls", bin);
+    let (cert, key) = generate_self_signed_cert(vec!["localhost".to_owned()]).unwrap();
+    fs::write(dir.join("cert.pem"), cert).unwrap();
+    fs::write(dir.join("key.pem"), key).unwrap();
+
+    let env = [
+        ("TLS_CERT", dir.join("cert.pem").display().to_string()),
+        ("TLS_KEY", dir.join("key.pem").display().to_string()),
+    ];
+    TestServer::start(bin, dir, &env)
+}
+
+fn connect_tls(server: &TestServer) -> (FrameReader<Stream>, FrameWriter<Stream>) {
+    let ca = server.dir.join("cert.pem");
+    let config = tls_client_config_with_ca(ca.to_str().unwrap()).unwrap();
+    let socket = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let stream = TlsStream::connect(socket, config, "localhost").unwrap();
+    stream.handshake().unwrap();
+    frames(Stream::Tls(stream))
+}
+
+fn chat_over_tls(bin: &str) {
+    let server = start_tls(bin);
+    let (mut reader, mut writer) = connect_tls(&server);
+
+    let register = Command::Register("alice".to_owned(), "correct horse".to_owned());
+    send(&mut writer, register);
+    assert_eq!(receive(&mut reader), Event::Welcome("alice".to_owned()));
+    assert_eq!(
+        receive(&mut reader),
+        Event::History(LOBBY.to_owned(), vec![])
+    );
+
+    let text = Message::new_text_message("hello over TLS");
+    send(
+        &mut writer,
+        Command::Send(1, Target::Room(LOBBY.to_owned()), text),
+    );
+    assert!(matches!(
+        receive(&mut reader),
+        Event::Ack(1, Ack::Accepted(_))
+    ));
+    let Event::Message(envelope) = receive(&mut reader) else {
+        panic!("message expected");
+    };
+    assert_eq!(envelope.sender, "alice");
+    assert_eq!(
+        envelope.message,
+        Message::new_text_message("hello over TLS")
+    );
+
+    // plain TCP clients are not understood by a TLS server
+    let (mut reader, mut writer) = server.connect();
+    send(&mut writer, Command::Rooms);
+    assert!(reader.read_frame().is_err());
+}
+
+#[test]
+fn threaded_server_over_tls() {
+    chat_over_tls("chat-server");
+}
+
+#[test]
+fn async_server_over_tls() {
+    chat_over_tls("chat-server-async");
+}
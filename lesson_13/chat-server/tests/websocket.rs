@@ -0,0 +1,120 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use chatlib::{Ack, Command, Event, Message, Presence, Target, LOBBY};
+use common::{receive, send, test_dir, TestServer, READ_TIMEOUT};
+use tokio_tungstenite::tungstenite::{client, Message as WsMessage, WebSocket};
+
+const BIN: &str = "chat-server-async";
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// connection to the gateway, retried until it is up
+fn connect(port: u16) -> TcpStream {
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+                return stream;
+            }
+            Err(e) => {
+                assert!(started.elapsed() < READ_TIMEOUT, "{}", e);
+                sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+fn send_json(socket: &mut WebSocket<TcpStream>, command: Command) {
+    let json = command.to_json().unwrap();
+    socket.send(WsMessage::Text(json)).unwrap();
+}
+
+fn receive_json(socket: &mut WebSocket<TcpStream>) -> Event {
+    loop {
+        match socket.read().unwrap() {
+            WsMessage::Text(json) => return Event::from_json(&json).unwrap(),
+            WsMessage::Ping(_) | WsMessage::Pong(_) => continue,
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+}
+
+fn text(id: u64, text: &str) -> Command {
+    Command::Send(
+        id,
+        Target::Room(LOBBY.to_owned()),
+        Message::new_text_message(text),
+    )
+}
+
+#[test]
+fn browsers_chat_with_native_clients() {
+    let ws_port = free_port();
+    let env = [("WS_PORT", ws_port.to_string())];
+    let server = TestServer::start(BIN, test_dir("websocket", BIN), &env);
+    let (mut alice, mut alice_writer) = server.register("alice");
+
+    let url = format!("ws://127.0.0.1:{}/", ws_port);
+    let (mut bob, _) = client(url, connect(ws_port)).unwrap();
+    send_json(
+        &mut bob,
+        Command::Register("bob".to_owned(), "correct horse".to_owned()),
+    );
+    assert_eq!(receive_json(&mut bob), Event::Welcome("bob".to_owned()));
+    assert!(matches!(receive_json(&mut bob), Event::History(room, _) if room == LOBBY));
+    assert_eq!(
+        receive(&mut alice),
+        Event::Presence("bob".to_owned(), Presence::Online)
+    );
+
+    send_json(&mut bob, text(1, "from the browser"));
+    assert!(matches!(receive(&mut alice),
+        Event::Message(envelope) if envelope.sender == "bob"
+            && envelope.message == Message::new_text_message("from the browser")));
+    assert_eq!(receive_json(&mut bob), Event::Ack(1, Ack::Accepted(1)));
+    // the room is echoed back to the sender
+    while receive_json(&mut bob) != Event::Ack(1, Ack::Delivered(1)) {}
+
+    send(&mut alice_writer, text(1, "from the terminal"));
+    assert!(matches!(receive_json(&mut bob),
+        Event::Message(envelope) if envelope.sender == "alice"
+            && envelope.message == Message::new_text_message("from the terminal")));
+
+    // a malformed command is answered, the connection stays open
+    bob.send(WsMessage::Text("{\"Join\":".to_owned())).unwrap();
+    assert!(matches!(receive_json(&mut bob),
+        Event::Error(error) if error.starts_with("Invalid command")));
+    send_json(&mut bob, Command::Who);
+    assert!(matches!(receive_json(&mut bob), Event::Users(users) if users.len() == 2));
+}
+
+#[test]
+fn web_client_is_served() {
+    let ws_port = free_port();
+    let env = [("WS_PORT", ws_port.to_string())];
+    let _server = TestServer::start(BIN, test_dir("web-client", BIN), &env);
+
+    let get = |path: &str| {
+        let mut stream = connect(ws_port);
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let page = get("/");
+    assert!(page.starts_with("HTTP/1.1 200 OK"), "{}", page);
+    assert!(page.contains("new WebSocket("));
+    assert!(get("/missing").starts_with("HTTP/1.1 404 Not Found"));
+}